@@ -3,6 +3,7 @@ use bevy_ecs::{
     prelude::*,
     schedule::{RunOnce, StageLabel, SystemDescriptor},
 };
+use bevy_tasks::{ComputeTaskPool, TaskPool};
 
 use crate::{events::Events, gameloop::Timer};
 
@@ -16,7 +17,9 @@ pub struct App {
 
 impl App {
     pub fn builder() -> AppBuilder {
-        AppBuilder::default().insert_non_send(simple_async_local_executor::Executor::default())
+        AppBuilder::default()
+            .insert_non_send(simple_async_local_executor::Executor::default())
+            .insert_resource(ComputeTaskPool(TaskPool::default()))
     }
 
     pub fn update(&mut self) {
@@ -166,7 +169,7 @@ fn default_stage() -> impl Stage {
 
 #[cfg(not(target_arch = "wasm32"))]
 fn default_stage() -> impl Stage {
-    SystemStage::single_threaded()
+    SystemStage::parallel()
 }
 
 impl Default for AppBuilder {
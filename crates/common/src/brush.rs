@@ -0,0 +1,273 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{material::MaterialId, rng::Rng, world::Tick};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Shape {
+    Circle,
+    Square,
+    // straight from the first point of the stroke to the last
+    Line,
+    // scattered cells within a circle
+    Spray,
+}
+
+impl Shape {
+    pub const ALL: [Shape; 4] = [Shape::Circle, Shape::Square, Shape::Line, Shape::Spray];
+
+    // the shape after this one, wrapping around
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|&shape| shape == self).unwrap();
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+/// A brush dragged through the world, painting `material` everywhere it touches.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Stroke {
+    // painting empty erases
+    pub material: MaterialId,
+    pub shape: Shape,
+    // in cells. 0 paints single cells
+    pub radius: u8,
+    // in cells, in the order they were painted
+    pub points: Vec<(u32, u32)>,
+    // when it was painted, on the painter's clock
+    pub tick: Tick,
+}
+
+/// The widest brush the server accepts.
+pub const MAX_BRUSH_RADIUS: u8 = 16;
+/// The longest stroke the server accepts, in cells. Longer drags are sent as several strokes.
+pub const MAX_STROKE_LENGTH: u32 = 256;
+
+// fraction of the cells under a spray that get painted
+const SPRAY_DENSITY: f32 = 0.15;
+
+impl Stroke {
+    // the points the brush is actually dragged between
+    fn path(&self) -> Vec<(u32, u32)> {
+        match self.shape {
+            Shape::Line => self
+                .points
+                .first()
+                .into_iter()
+                .chain(self.points.last())
+                .copied()
+                .collect(),
+            _ => self.points.clone(),
+        }
+    }
+
+    /// How far the brush travels, in cells.
+    pub fn length(&self) -> u32 {
+        self.path()
+            .windows(2)
+            .map(|segment| distance(segment[0], segment[1]))
+            .fold(0, u32::saturating_add)
+    }
+
+    /// The most cells the stroke can cover, without working out which. Past the first stamp,
+    /// each step along the path adds at most a new column and row of the brush.
+    pub fn max_cells(&self) -> usize {
+        let side = 2 * self.radius as usize + 1;
+        self.path()
+            .windows(2)
+            .map(|segment| {
+                let delta_x = (segment[1].0 as i64 - segment[0].0 as i64).unsigned_abs() as usize;
+                let delta_y = (segment[1].1 as i64 - segment[0].1 as i64).unsigned_abs() as usize;
+                // steps moving both ways add a column and a row sharing a corner
+                (delta_x + delta_y)
+                    .saturating_mul(side)
+                    .saturating_sub(delta_x.min(delta_y))
+            })
+            .fold(side * side, usize::saturating_add)
+    }
+
+    /// Splits the stroke into strokes of at most `MAX_STROKE_LENGTH` that together cover the
+    /// same cells.
+    pub fn split(self) -> Vec<Stroke> {
+        let path = self.path();
+        let mut points = path.first().copied().into_iter().collect::<Vec<_>>();
+        for &to in path.iter().skip(1) {
+            let from = *points.last().unwrap();
+            // cut long segments into pieces that fit
+            let pieces = distance(from, to).div_ceil(MAX_STROKE_LENGTH);
+            points.extend((1..=pieces).map(|piece| lerp(from, to, piece, pieces)));
+        }
+
+        let mut strokes = Vec::new();
+        let mut current = points.first().copied().into_iter().collect::<Vec<_>>();
+        let mut length = 0;
+        for &point in points.iter().skip(1) {
+            let last = *current.last().unwrap();
+            let step = distance(last, point);
+            if length + step > MAX_STROKE_LENGTH {
+                strokes.push(std::mem::replace(&mut current, vec![last]));
+                length = 0;
+            }
+            current.push(point);
+            length += step;
+        }
+        if !current.is_empty() {
+            strokes.push(current);
+        }
+        strokes
+            .into_iter()
+            .map(|points| Stroke {
+                points,
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    /// The cells the stroke covers within a `width` by `height` world, sorted and without
+    /// duplicates. `rng` picks the cells a spray hits.
+    pub fn cells(&self, width: u32, height: u32, rng: &mut Rng) -> Vec<(u32, u32)> {
+        let path = self.path();
+        let mut centers = path.first().copied().into_iter().collect::<Vec<_>>();
+        for segment in path.windows(2) {
+            let steps = distance(segment[0], segment[1]);
+            centers.extend((1..=steps).map(|step| lerp(segment[0], segment[1], step, steps)));
+        }
+
+        let radius = self.radius as i64;
+        let mut cells = Vec::new();
+        for (center_x, center_y) in centers {
+            for relative_y in -radius..=radius {
+                for relative_x in -radius..=radius {
+                    let round = self.shape != Shape::Square;
+                    if round && relative_x * relative_x + relative_y * relative_y > radius * radius
+                    {
+                        continue;
+                    }
+                    let x = center_x as i64 + relative_x;
+                    let y = center_y as i64 + relative_y;
+                    if (0..width as i64).contains(&x) && (0..height as i64).contains(&y) {
+                        cells.push((x as u32, y as u32));
+                    }
+                }
+            }
+        }
+        cells.sort_unstable();
+        cells.dedup();
+        if self.shape == Shape::Spray {
+            cells.retain(|_| rng.chance(SPRAY_DENSITY));
+        }
+        cells
+    }
+}
+
+// the point `step` out of `steps` of the way from `from` to `to`, rounded down
+fn lerp(from: (u32, u32), to: (u32, u32), step: u32, steps: u32) -> (u32, u32) {
+    let lerp = |from: u32, to: u32| {
+        (from as i64 + (to as i64 - from as i64) * step as i64 / steps as i64) as u32
+    };
+    (lerp(from.0, to.0), lerp(from.1, to.1))
+}
+
+// cells visited going from `from` to `to` in a straight line
+fn distance(from: (u32, u32), to: (u32, u32)) -> u32 {
+    let delta_x = (to.0 as i64 - from.0 as i64).abs();
+    let delta_y = (to.1 as i64 - from.1 as i64).abs();
+    delta_x.max(delta_y) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stroke(shape: Shape, radius: u8, points: &[(u32, u32)]) -> Stroke {
+        Stroke {
+            material: MaterialId(1),
+            shape,
+            radius,
+            points: points.to_vec(),
+            tick: Tick(0),
+        }
+    }
+
+    fn cells(stroke: &Stroke) -> Vec<(u32, u32)> {
+        stroke.cells(16, 16, &mut Rng::new(0))
+    }
+
+    #[test]
+    fn stamps() {
+        assert_eq!(cells(&stroke(Shape::Circle, 0, &[(3, 4)])), vec![(3, 4)]);
+        assert_eq!(cells(&stroke(Shape::Circle, 1, &[(3, 4)])).len(), 5);
+        assert_eq!(cells(&stroke(Shape::Square, 1, &[(3, 4)])).len(), 9);
+    }
+
+    #[test]
+    fn strokes_are_continuous() {
+        let covered = cells(&stroke(Shape::Circle, 0, &[(0, 0), (5, 0), (5, 3)]));
+        assert_eq!(covered.len(), 9);
+        assert!((0..=5).all(|x| covered.contains(&(x, 0))));
+        assert!((0..=3).all(|y| covered.contains(&(5, y))));
+    }
+
+    #[test]
+    fn lines_skip_the_middle_points() {
+        let line = stroke(Shape::Line, 0, &[(0, 0), (0, 9), (4, 4)]);
+        assert_eq!(line.length(), 4);
+        assert_eq!(cells(&line), vec![(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)]);
+    }
+
+    #[test]
+    fn clipped_to_the_world() {
+        let corner = stroke(Shape::Square, 2, &[(0, 15)]);
+        assert_eq!(cells(&corner).len(), 9);
+    }
+
+    #[test]
+    fn long_strokes_are_split() {
+        let long = stroke(
+            Shape::Circle,
+            1,
+            &[(0, 0), (600, 0), (600, 100), (610, 100)],
+        );
+        let strokes = long.clone().split();
+        assert_eq!(strokes.len(), 4);
+        assert!(strokes
+            .iter()
+            .all(|stroke| stroke.length() <= MAX_STROKE_LENGTH));
+        let mut covered = strokes
+            .iter()
+            .flat_map(|stroke| stroke.cells(1024, 1024, &mut Rng::new(0)))
+            .collect::<Vec<_>>();
+        covered.sort_unstable();
+        covered.dedup();
+        assert_eq!(covered, long.cells(1024, 1024, &mut Rng::new(0)));
+
+        let line = stroke(Shape::Line, 0, &[(0, 0), (0, 5), (300, 300)]).split();
+        assert_eq!(line.len(), 2);
+        assert_eq!(line[0].points.first(), Some(&(0, 0)));
+        assert_eq!(line[1].points.last(), Some(&(300, 300)));
+    }
+
+    #[test]
+    fn max_cells_bounds_what_strokes_cover() {
+        let points = [(20, 20), (60, 35), (40, 90)];
+        for &shape in &[Shape::Square, Shape::Circle, Shape::Line, Shape::Spray] {
+            let stroke = stroke(shape, 3, &points);
+            let covered = stroke.cells(1024, 1024, &mut Rng::new(0)).len();
+            assert!(covered <= stroke.max_cells());
+        }
+        // and exactly, for a square dragged in a straight line
+        let square = stroke(Shape::Square, 3, &points[..2]);
+        assert_eq!(
+            square.cells(1024, 1024, &mut Rng::new(0)).len(),
+            square.max_cells()
+        );
+    }
+
+    #[test]
+    fn sprays_are_sparse_and_repeatable() {
+        let spray = stroke(Shape::Spray, 6, &[(8, 8)]);
+        let full = cells(&stroke(Shape::Circle, 6, &[(8, 8)]));
+        let sprayed = cells(&spray);
+        assert!(!sprayed.is_empty() && sprayed.len() < full.len() / 2);
+        assert!(sprayed.iter().all(|cell| full.contains(cell)));
+        assert_eq!(sprayed, cells(&spray));
+    }
+}
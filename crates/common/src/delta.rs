@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+
+use crate::world::{Cell, ChunkPosition};
+
+/// Changes to a chunk: spans of cells that differ from the previous version, each run-length
+/// encoded. A span covering the whole chunk describes it from scratch.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ChunkDelta {
+    pub chunk: ChunkPosition,
+    pub spans: Vec<Span>,
+}
+
+// consecutive cells, starting at `start`. indices are row-major from the bottom left of the
+// chunk
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Span {
+    pub start: u16,
+    pub runs: Vec<Run>,
+}
+
+// `length` copies of `cell`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Run {
+    pub length: u16,
+    pub cell: Cell,
+}
+
+// unchanged cells between two changes that are sent anyway, since a new span costs more than
+// a few cells
+const MAX_GAP: usize = 2;
+
+impl ChunkDelta {
+    /// The whole chunk, for someone who knows nothing about it yet.
+    pub fn full(chunk: ChunkPosition, cells: &[Cell]) -> Self {
+        Self {
+            chunk,
+            spans: vec![Span {
+                start: 0,
+                runs: encode_runs(cells),
+            }],
+        }
+    }
+
+    /// What changed from `previous` to `current`, or `None` if nothing did. Both are the same
+    /// chunk, so they have the same length.
+    pub fn between(chunk: ChunkPosition, previous: &[Cell], current: &[Cell]) -> Option<Self> {
+        debug_assert_eq!(previous.len(), current.len());
+        let mut spans = Vec::new();
+        let mut changed = (0..current.len()).filter(|&index| previous[index] != current[index]);
+        let mut start = changed.next()?;
+        let mut end = start + 1;
+        for index in changed {
+            if index - end > MAX_GAP {
+                spans.push(Span {
+                    start: start as u16,
+                    runs: encode_runs(&current[start..end]),
+                });
+                start = index;
+            }
+            end = index + 1;
+        }
+        spans.push(Span {
+            start: start as u16,
+            runs: encode_runs(&current[start..end]),
+        });
+        Some(Self { chunk, spans })
+    }
+
+    /// How many bytes it takes in a packet.
+    pub fn encoded_size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    /// Applies the changes to `cells`, the previous version of the chunk. Returns `None`,
+    /// leaving `cells` untouched, if a span doesn't fit.
+    pub fn apply(&self, cells: &mut [Cell]) -> Option<()> {
+        for span in &self.spans {
+            let length = span
+                .runs
+                .iter()
+                .map(|run| run.length as usize)
+                .sum::<usize>();
+            if span.start as usize + length > cells.len() {
+                return None;
+            }
+        }
+        for span in &self.spans {
+            let mut index = span.start as usize;
+            for run in &span.runs {
+                let end = index + run.length as usize;
+                cells[index..end].fill(run.cell);
+                index = end;
+            }
+        }
+        Some(())
+    }
+}
+
+fn encode_runs(cells: &[Cell]) -> Vec<Run> {
+    let mut runs: Vec<Run> = Vec::new();
+    for &cell in cells {
+        match runs.last_mut() {
+            Some(run) if run.cell == cell && run.length < u16::MAX => run.length += 1,
+            _ => runs.push(Run { length: 1, cell }),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        material::MaterialId,
+        rng::Rng,
+        world::{AMBIENT_TEMPERATURE, CHUNK_SIZE},
+    };
+
+    const AREA: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+    const CHUNK: ChunkPosition = ChunkPosition { x: 1, y: 2 };
+
+    // few materials and long stretches of the same one, like real chunks
+    fn random_cell(rng: &mut Rng) -> Cell {
+        let temperature = AMBIENT_TEMPERATURE + rng.below(3) as i16;
+        Cell::with_temperature(MaterialId(rng.below(4) as u8), temperature)
+    }
+
+    fn random_chunk(rng: &mut Rng) -> Vec<Cell> {
+        let mut cells = Vec::with_capacity(AREA);
+        while cells.len() < AREA {
+            let cell = random_cell(rng);
+            let length = (1 + rng.below(64) as usize).min(AREA - cells.len());
+            cells.extend(std::iter::repeat_n(cell, length));
+        }
+        cells
+    }
+
+    // changes anything from a single cell to most of the chunk
+    fn mutate(rng: &mut Rng, cells: &[Cell]) -> Vec<Cell> {
+        let mut mutated = cells.to_vec();
+        let chance = [0.001, 0.05, 0.5, 1.0][rng.below(4) as usize];
+        for cell in mutated.iter_mut() {
+            if rng.chance(chance) {
+                *cell = random_cell(rng);
+            }
+        }
+        mutated
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let mut rng = Rng::new(12);
+        for _ in 0..500 {
+            let previous = random_chunk(&mut rng);
+            let current = mutate(&mut rng, &previous);
+            let mut decoded = previous.clone();
+            if let Some(delta) = ChunkDelta::between(CHUNK, &previous, &current) {
+                assert_eq!(delta.chunk, CHUNK);
+                delta.apply(&mut decoded).unwrap();
+            }
+            assert_eq!(decoded, current);
+        }
+    }
+
+    #[test]
+    fn full_chunks_round_trip() {
+        let mut rng = Rng::new(13);
+        for _ in 0..100 {
+            let cells = random_chunk(&mut rng);
+            let mut decoded = vec![Cell::empty(); AREA];
+            ChunkDelta::full(CHUNK, &cells).apply(&mut decoded).unwrap();
+            assert_eq!(decoded, cells);
+        }
+    }
+
+    #[test]
+    fn deltas_survive_serialization() {
+        let mut rng = Rng::new(14);
+        let previous = random_chunk(&mut rng);
+        let current = mutate(&mut rng, &previous);
+        let delta = ChunkDelta::between(CHUNK, &previous, &current).unwrap();
+        let bytes = bincode::serialize(&delta).unwrap();
+        assert_eq!(bincode::deserialize::<ChunkDelta>(&bytes).unwrap(), delta);
+    }
+
+    #[test]
+    fn unchanged_chunks_have_no_delta() {
+        let cells = random_chunk(&mut Rng::new(15));
+        assert_eq!(ChunkDelta::between(CHUNK, &cells, &cells), None);
+    }
+
+    #[test]
+    fn small_changes_are_small() {
+        let previous = vec![Cell::empty(); AREA];
+        let mut current = previous.clone();
+        current[100] = Cell::new(MaterialId(1));
+        current[102] = Cell::new(MaterialId(1));
+        current[3000] = Cell::new(MaterialId(2));
+        let delta = ChunkDelta::between(CHUNK, &previous, &current).unwrap();
+        assert_eq!(delta.spans.len(), 2);
+        assert_eq!(delta.spans[0].start, 100);
+        assert_eq!(delta.spans[0].runs.len(), 3);
+        assert_eq!(delta.spans[1].start, 3000);
+    }
+
+    #[test]
+    fn spans_that_dont_fit_are_rejected() {
+        let mut cells = vec![Cell::empty(); AREA];
+        let delta = ChunkDelta {
+            chunk: CHUNK,
+            spans: vec![
+                Span {
+                    start: 0,
+                    runs: vec![Run {
+                        length: 1,
+                        cell: Cell::new(MaterialId(1)),
+                    }],
+                },
+                Span {
+                    start: AREA as u16 - 1,
+                    runs: vec![Run {
+                        length: 2,
+                        cell: Cell::new(MaterialId(1)),
+                    }],
+                },
+            ],
+        };
+        assert_eq!(delta.apply(&mut cells), None);
+        assert!(cells.iter().all(Cell::is_empty));
+    }
+}
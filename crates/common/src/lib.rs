@@ -1,18 +1,49 @@
 pub mod app;
+pub mod brush;
+pub mod delta;
 pub mod events;
 mod gameloop;
+pub mod material;
 pub mod net;
+pub mod rng;
 pub mod world;
 
+use brush::Stroke;
+use delta::ChunkDelta;
 use serde::{Deserialize, Serialize};
-use world::Cell;
+use world::{FlyingCell, Tick};
 
 // server -> client
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum ServerPacket {
-    ConnectChallenge { challenge: String },
-    SetCells { cells: Vec<Cell> },
-    UpdateCells { cells: Vec<Cell> },
+    ConnectChallenge {
+        challenge: String,
+    },
+    // every chunk that isn't empty, described from scratch, for a client that just joined. sent
+    // reliably, so it may arrive after newer updates, which the client replays over it rather
+    // than dropping it as stale. split into `parts` packets, all stamped with the same tick
+    Snapshot {
+        tick: Tick,
+        parts: u32,
+        chunks: Vec<ChunkDelta>,
+    },
+    // chunks described from scratch, a keyframe every few seconds so clients that missed
+    // updates catch up, split over as many packets as it takes, all stamped with the same tick
+    SetCells {
+        tick: Tick,
+        chunks: Vec<ChunkDelta>,
+    },
+    // what changed since the previous update
+    UpdateCells {
+        tick: Tick,
+        chunks: Vec<ChunkDelta>,
+    },
+    // every cell flying outside of the grid, sent each tick while there are any and once more,
+    // empty, after the last has landed
+    Particles {
+        tick: Tick,
+        particles: Vec<FlyingCell>,
+    },
 }
 
 impl ServerPacket {
@@ -30,6 +61,7 @@ impl ServerPacket {
 pub enum ClientPacket {
     Connect(),
     SetName { name: String },
+    Paint(Stroke),
 }
 
 impl ClientPacket {
@@ -0,0 +1,652 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::world::{Cell, AMBIENT_TEMPERATURE};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("could not read material definitions: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse material definitions: {0}")]
+    Parse(#[from] ron::Error),
+    #[error("material `{0}` is defined more than once")]
+    Duplicate(String),
+    #[error("material `{0}` is reserved")]
+    Reserved(String),
+    #[error("material `{0}` is not defined")]
+    Undefined(String),
+    #[error("material `{0}` melts or boils below the temperature it freezes or condenses at")]
+    PhaseOverlap(String),
+    #[error("material `{0}` can catch fire but doesn't define how it burns")]
+    Incombustible(String),
+    #[error("reaction between `{0}` and `{1}` is defined more than once")]
+    DuplicateReaction(String, String),
+    #[error("reaction between `{0}` and `{1}` has a chance outside of 0 to 1")]
+    InvalidChance(String, String),
+    #[error("`{0}` can only be the second reactant of a reaction")]
+    MisplacedAny(String),
+    #[error(
+        "material `{0}` explodes with a radius outside of 1 to {} or a power outside of 0 to {}",
+        MAX_BLAST_RADIUS,
+        MAX_BLAST_POWER
+    )]
+    InvalidBlast(String),
+    #[error("too many materials ({0}), at most {} are supported", MaterialId::MAX)]
+    TooMany(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// index into `Materials`
+#[derive(
+    Deserialize, Serialize, Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd, Default,
+)]
+pub struct MaterialId(pub u8);
+
+impl MaterialId {
+    pub const EMPTY: MaterialId = MaterialId(0);
+    const MAX: usize = u8::MAX as usize + 1;
+
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum State {
+    Solid,
+    Powder,
+    Liquid,
+    Gas,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Color(pub u8, pub u8, pub u8, pub u8);
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Material {
+    pub name: String,
+    pub state: State,
+    // relative to water
+    pub density: f32,
+    pub color: Color,
+    // chance per tick of catching fire from a burning neighbor
+    #[serde(default)]
+    pub flammability: f32,
+    // temperature of newly placed cells, in degrees celsius
+    #[serde(default = "ambient_temperature")]
+    pub temperature: i16,
+    // fraction of the temperature difference to a neighbor exchanged per tick, from 0 to 1.
+    // heat only flows between two cells as fast as the worse conductor allows
+    #[serde(default)]
+    pub conductivity: f32,
+    // turns into another material when hotter than this, e.g. melting or boiling
+    #[serde(default)]
+    pub above: Option<PhaseChange>,
+    // turns into another material when colder than this, e.g. freezing or condensing
+    #[serde(default)]
+    pub below: Option<PhaseChange>,
+    // how cells of the material burn once they catch fire
+    #[serde(default)]
+    pub burning: Option<Burning>,
+    // new cells start out burning, like fire itself
+    #[serde(default)]
+    pub ignited: bool,
+    // detonates once it catches fire or gets too hot
+    #[serde(default)]
+    pub explosive: Option<Explosive>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PhaseChange {
+    pub temperature: i16,
+    // name of the material to turn into
+    pub into: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Burning {
+    // how long a cell burns for
+    pub ticks: u16,
+    // name of the material left behind once it burns out
+    pub into: String,
+    // material released into the empty cell above while burning
+    #[serde(default)]
+    pub emits: Option<Emission>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Emission {
+    pub material: String,
+    // chance per tick
+    pub chance: f32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Explosive {
+    // detonates when hotter than this
+    pub temperature: i16,
+    // cells within this many cells of the detonation are flung away
+    pub radius: u8,
+    // speed given to cells right next to the detonation, in cells per tick. falls off towards
+    // the edge of the blast
+    pub power: f32,
+}
+
+/// The largest blast radius a material may have.
+pub const MAX_BLAST_RADIUS: u8 = 32;
+/// The most power a blast may have, in cells per tick. Flung cells move a cell at a time, so
+/// this bounds the work each of them takes per tick.
+pub const MAX_BLAST_POWER: f32 = 32.0;
+
+fn ambient_temperature() -> i16 {
+    AMBIENT_TEMPERATURE
+}
+
+impl Material {
+    fn empty() -> Self {
+        Self {
+            name: EMPTY.to_string(),
+            state: State::Gas,
+            density: 0.0,
+            color: Color(0, 0, 0, 0),
+            flammability: 0.0,
+            temperature: AMBIENT_TEMPERATURE,
+            conductivity: 0.0,
+            above: None,
+            below: None,
+            burning: None,
+            ignited: false,
+            explosive: None,
+        }
+    }
+}
+
+const EMPTY: &str = "empty";
+// stands for any material other than empty and the first reactant, in reactions
+const ANY: &str = "any";
+
+// what happens when cells of two materials are neighbors
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReactionDefinition {
+    // material names, the second one may be `any`
+    pub reactants: (String, String),
+    // what the reactants turn into, in the same order
+    pub products: (String, String),
+    // chance per tick of reacting while the cells are neighbors
+    #[serde(default = "always")]
+    pub chance: f32,
+}
+
+fn always() -> f32 {
+    1.0
+}
+
+// a resolved `ReactionDefinition`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reaction {
+    // what each reactant turns into, in the order they were passed to `Materials::reaction`
+    pub products: (MaterialId, MaterialId),
+    pub chance: f32,
+}
+
+impl Reaction {
+    fn reversed(self) -> Self {
+        Self {
+            products: (self.products.1, self.products.0),
+            chance: self.chance,
+        }
+    }
+}
+
+// on-disk format of a material definition file
+#[derive(Deserialize, Serialize, Debug)]
+struct Definitions {
+    materials: Vec<Material>,
+    #[serde(default)]
+    reactions: Vec<ReactionDefinition>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Materials {
+    materials: Vec<Material>,
+    by_name: HashMap<String, MaterialId>,
+    // the materials each material can turn into resolved to ids, indexed like `materials`
+    transitions: Vec<Transitions>,
+    // reactions between two specific materials, stored in both orders
+    reactions: HashMap<(MaterialId, MaterialId), Reaction>,
+    // reactions of a material with `any` other material
+    any_reactions: HashMap<MaterialId, Reaction>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Transitions {
+    above: Option<(i16, MaterialId)>,
+    below: Option<(i16, MaterialId)>,
+    burnout: Option<MaterialId>,
+    emits: Option<(MaterialId, f32)>,
+}
+
+impl Materials {
+    /// The definitions in `assets/materials.ron`, embedded at compile time so the wasm client
+    /// and the server always agree on material ids.
+    pub fn builtin() -> Self {
+        Self::from_ron(include_str!("../../../assets/materials.ron"))
+            .expect("builtin material definitions are invalid")
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let source = std::fs::read_to_string(path)?;
+        Self::from_ron(&source)
+    }
+
+    pub fn from_ron(source: &str) -> Result<Self> {
+        let definitions: Definitions = ron::from_str(source)?;
+        Self::from_definitions(definitions)
+    }
+
+    fn from_definitions(definitions: Definitions) -> Result<Self> {
+        let mut materials = Self {
+            materials: Vec::new(),
+            by_name: HashMap::new(),
+            transitions: Vec::new(),
+            reactions: HashMap::new(),
+            any_reactions: HashMap::new(),
+        };
+        materials.insert(Material::empty())?;
+        for material in definitions.materials {
+            if material.name == EMPTY || material.name == ANY {
+                return Err(Error::Reserved(material.name));
+            }
+            materials.insert(material)?;
+        }
+        materials.transitions = materials
+            .materials
+            .iter()
+            .map(|material| materials.resolve_transitions(material))
+            .collect::<Result<_>>()?;
+        for reaction in &definitions.reactions {
+            materials.insert_reaction(reaction)?;
+        }
+        Ok(materials)
+    }
+
+    fn insert_reaction(&mut self, definition: &ReactionDefinition) -> Result<()> {
+        let (first, second) = &definition.reactants;
+        let duplicate = || Error::DuplicateReaction(first.clone(), second.clone());
+        if !(0.0..=1.0).contains(&definition.chance) {
+            return Err(Error::InvalidChance(first.clone(), second.clone()));
+        }
+        if first == ANY {
+            return Err(Error::MisplacedAny(first.clone()));
+        }
+        if first == EMPTY || second == EMPTY {
+            return Err(Error::Reserved(EMPTY.to_string()));
+        }
+        let resolve = |name: &String| self.id(name).ok_or_else(|| Error::Undefined(name.clone()));
+        let reaction = Reaction {
+            products: (
+                resolve(&definition.products.0)?,
+                resolve(&definition.products.1)?,
+            ),
+            chance: definition.chance,
+        };
+        let first = resolve(first)?;
+        if second == ANY {
+            if self.any_reactions.insert(first, reaction).is_some() {
+                return Err(duplicate());
+            }
+            return Ok(());
+        }
+        let second = resolve(second)?;
+        if self.reactions.contains_key(&(first, second)) {
+            return Err(duplicate());
+        }
+        self.reactions.insert((first, second), reaction);
+        self.reactions.insert((second, first), reaction.reversed());
+        Ok(())
+    }
+
+    fn resolve_transitions(&self, material: &Material) -> Result<Transitions> {
+        let resolve = |name: &String| self.id(name).ok_or_else(|| Error::Undefined(name.clone()));
+        let resolve_change = |change: &Option<PhaseChange>| {
+            change
+                .as_ref()
+                .map(|change| resolve(&change.into).map(|id| (change.temperature, id)))
+                .transpose()
+        };
+        let burning = material.burning.as_ref();
+        let transitions = Transitions {
+            above: resolve_change(&material.above)?,
+            below: resolve_change(&material.below)?,
+            burnout: burning.map(|burning| resolve(&burning.into)).transpose()?,
+            emits: burning
+                .and_then(|burning| burning.emits.as_ref())
+                .map(|emits| resolve(&emits.material).map(|id| (id, emits.chance)))
+                .transpose()?,
+        };
+        if let (Some((above, _)), Some((below, _))) = (transitions.above, transitions.below) {
+            if above < below {
+                return Err(Error::PhaseOverlap(material.name.clone()));
+            }
+        }
+        if (material.flammability > 0.0 || material.ignited) && burning.is_none() {
+            return Err(Error::Incombustible(material.name.clone()));
+        }
+        if let Some(explosive) = &material.explosive {
+            let radius = (1..=MAX_BLAST_RADIUS).contains(&explosive.radius);
+            // also false for NaN
+            let power = explosive.power > 0.0 && explosive.power <= MAX_BLAST_POWER;
+            if !radius || !power {
+                return Err(Error::InvalidBlast(material.name.clone()));
+            }
+        }
+        Ok(transitions)
+    }
+
+    fn insert(&mut self, material: Material) -> Result<()> {
+        if self.materials.len() >= MaterialId::MAX {
+            return Err(Error::TooMany(self.materials.len() + 1));
+        }
+        if self.by_name.contains_key(&material.name) {
+            return Err(Error::Duplicate(material.name));
+        }
+        let id = MaterialId(self.materials.len() as u8);
+        self.by_name.insert(material.name.clone(), id);
+        self.materials.push(material);
+        Ok(())
+    }
+
+    pub fn get(&self, id: MaterialId) -> Option<&Material> {
+        self.materials.get(id.index())
+    }
+
+    pub fn id(&self, name: &str) -> Option<MaterialId> {
+        self.by_name.get(name).copied()
+    }
+
+    /// A new cell of the given material at its initial temperature, already burning if the
+    /// material starts out ignited.
+    pub fn cell(&self, id: MaterialId) -> Cell {
+        let material = match self.get(id) {
+            Some(material) => material,
+            None => return Cell::new(id),
+        };
+        let cell = Cell::with_temperature(id, material.temperature);
+        if material.ignited {
+            self.ignite(cell).unwrap_or(cell)
+        } else {
+            cell
+        }
+    }
+
+    /// `cell` set on fire, if its material can burn.
+    pub fn ignite(&self, mut cell: Cell) -> Option<Cell> {
+        let burning = self.get(cell.material)?.burning.as_ref()?;
+        cell.lifetime = burning.ticks;
+        Some(cell)
+    }
+
+    /// The material a burning cell of material `id` leaves behind, if it can burn.
+    pub fn burnout(&self, id: MaterialId) -> Option<MaterialId> {
+        self.transitions.get(id.index())?.burnout
+    }
+
+    /// The material a burning cell of material `id` emits and the chance per tick it does.
+    pub fn emission(&self, id: MaterialId) -> Option<(MaterialId, f32)> {
+        self.transitions.get(id.index())?.emits
+    }
+
+    /// The material `cell` turns into at its current temperature, if it changes phase.
+    pub fn phase_change(&self, cell: Cell) -> Option<MaterialId> {
+        let changes = self.transitions.get(cell.material.index())?;
+        match (changes.above, changes.below) {
+            (Some((threshold, into)), _) if cell.temperature > threshold => Some(into),
+            (_, Some((threshold, into))) if cell.temperature < threshold => Some(into),
+            _ => None,
+        }
+    }
+
+    /// The reaction between neighboring cells of materials `a` and `b`, if they react.
+    /// Reactions between two specific materials take precedence over ones with `any`.
+    pub fn reaction(&self, a: MaterialId, b: MaterialId) -> Option<Reaction> {
+        if a == MaterialId::EMPTY || b == MaterialId::EMPTY {
+            return None;
+        }
+        if let Some(&reaction) = self.reactions.get(&(a, b)) {
+            return Some(reaction);
+        }
+        if a == b {
+            return None;
+        }
+        self.any_reactions.get(&a).copied().or_else(|| {
+            self.any_reactions
+                .get(&b)
+                .map(|reaction| reaction.reversed())
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (MaterialId, &Material)> {
+        self.materials
+            .iter()
+            .enumerate()
+            .map(|(index, material)| (MaterialId(index as u8), material))
+    }
+}
+
+impl std::ops::Index<MaterialId> for Materials {
+    type Output = Material;
+
+    fn index(&self, id: MaterialId) -> &Material {
+        &self.materials[id.index()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_materials_load() {
+        let materials = Materials::builtin();
+        assert_eq!(materials.id(EMPTY), Some(MaterialId::EMPTY));
+        let sand = materials.id("sand").expect("sand is builtin");
+        assert_eq!(materials[sand].state, State::Powder);
+    }
+
+    #[test]
+    fn duplicate_materials_are_rejected() {
+        let source = r#"(materials: [
+            (name: "sand", state: Powder, density: 1.6, color: (0, 0, 0, 255)),
+            (name: "sand", state: Powder, density: 1.6, color: (0, 0, 0, 255)),
+        ])"#;
+        assert!(matches!(
+            Materials::from_ron(source),
+            Err(Error::Duplicate(name)) if name == "sand"
+        ));
+    }
+
+    #[test]
+    fn empty_is_reserved() {
+        let source = r#"(materials: [
+            (name: "empty", state: Gas, density: 0.0, color: (0, 0, 0, 0)),
+        ])"#;
+        assert!(matches!(
+            Materials::from_ron(source),
+            Err(Error::Reserved(_))
+        ));
+    }
+
+    #[test]
+    fn builtin_phase_changes() {
+        let materials = Materials::builtin();
+        let id = |name| materials.id(name).unwrap();
+        let water = |temperature| Cell::with_temperature(id("water"), temperature);
+        assert_eq!(materials.phase_change(water(150)), Some(id("steam")));
+        assert_eq!(materials.phase_change(water(-5)), Some(id("ice")));
+        assert_eq!(materials.phase_change(water(50)), None);
+        let stone = Cell::with_temperature(id("stone"), 1500);
+        assert_eq!(materials.phase_change(stone), Some(id("lava")));
+        assert_eq!(materials.phase_change(materials.cell(id("lava"))), None);
+    }
+
+    #[test]
+    fn builtin_burning() {
+        let materials = Materials::builtin();
+        let id = |name| materials.id(name).unwrap();
+        assert!(materials.cell(id("fire")).is_burning());
+        assert!(!materials.cell(id("wood")).is_burning());
+        assert!(materials
+            .ignite(materials.cell(id("wood")))
+            .unwrap()
+            .is_burning());
+        assert_eq!(materials.ignite(materials.cell(id("stone"))), None);
+        assert_eq!(materials.burnout(id("wood")), Some(id("ash")));
+        assert_eq!(
+            materials.emission(id("wood")).map(|(smoke, _)| smoke),
+            Some(id("smoke"))
+        );
+    }
+
+    #[test]
+    fn flammable_materials_must_burn() {
+        let source = r#"(materials: [
+            (name: "paper", state: Solid, density: 0.5, color: (0, 0, 0, 255), flammability: 0.9),
+        ])"#;
+        assert!(matches!(
+            Materials::from_ron(source),
+            Err(Error::Incombustible(name)) if name == "paper"
+        ));
+    }
+
+    #[test]
+    fn builtin_explosives() {
+        let materials = Materials::builtin();
+        let explosive = |name| materials[materials.id(name).unwrap()].explosive.clone();
+        assert!(explosive("gunpowder").is_some());
+        assert!(explosive("nitro").is_some());
+        assert!(explosive("sand").is_none());
+    }
+
+    #[test]
+    fn oversized_blasts_are_rejected() {
+        let bomb = |radius, power| {
+            let source = format!(
+                r#"(materials: [
+                    (
+                        name: "bomb", state: Solid, density: 1.0, color: (0, 0, 0, 255),
+                        explosive: Some((temperature: 100, radius: {}, power: {})),
+                    ),
+                ])"#,
+                radius, power
+            );
+            Materials::from_ron(&source)
+        };
+        assert!(bomb(4, "4.0").is_ok());
+        for (radius, power) in [
+            (200, "4.0"),
+            (0, "4.0"),
+            (4, "0.0"),
+            (4, "-1.0"),
+            (4, "1000.0"),
+            (4, "NaN"),
+            (4, "inf"),
+        ] {
+            assert!(
+                matches!(bomb(radius, power), Err(Error::InvalidBlast(name)) if name == "bomb"),
+                "radius {} and power {} should be rejected",
+                radius,
+                power
+            );
+        }
+    }
+
+    #[test]
+    fn undefined_phase_change_is_rejected() {
+        let source = r#"(materials: [
+            (
+                name: "water", state: Liquid, density: 1.0, color: (0, 0, 0, 255),
+                above: Some((temperature: 100, into: "vapor")),
+            ),
+        ])"#;
+        assert!(matches!(
+            Materials::from_ron(source),
+            Err(Error::Undefined(name)) if name == "vapor"
+        ));
+    }
+
+    #[test]
+    fn overlapping_phase_changes_are_rejected() {
+        let source = r#"(materials: [
+            (
+                name: "slush", state: Liquid, density: 1.0, color: (0, 0, 0, 255),
+                above: Some((temperature: 0, into: "slush")),
+                below: Some((temperature: 10, into: "slush")),
+            ),
+        ])"#;
+        assert!(matches!(
+            Materials::from_ron(source),
+            Err(Error::PhaseOverlap(_))
+        ));
+    }
+
+    #[test]
+    fn builtin_reactions() {
+        let materials = Materials::builtin();
+        let id = |name| materials.id(name).unwrap();
+        let products = |a, b| materials.reaction(id(a), id(b)).map(|r| r.products);
+        assert_eq!(products("water", "lava"), Some((id("steam"), id("stone"))));
+        assert_eq!(products("lava", "water"), Some((id("stone"), id("steam"))));
+        assert_eq!(
+            products("acid", "sand"),
+            Some((id("acid"), MaterialId::EMPTY))
+        );
+        assert_eq!(
+            products("stone", "acid"),
+            Some((MaterialId::EMPTY, id("acid")))
+        );
+        assert_eq!(products("acid", "acid"), None);
+        assert_eq!(products("sand", "water"), None);
+        assert_eq!(materials.reaction(id("acid"), MaterialId::EMPTY), None);
+    }
+
+    const TWO_MATERIALS: &str = r#"
+        (name: "a", state: Powder, density: 1.0, color: (0, 0, 0, 255)),
+        (name: "b", state: Powder, density: 1.0, color: (0, 0, 0, 255)),
+    "#;
+
+    fn with_reactions(reactions: &str) -> Result<Materials> {
+        Materials::from_ron(&format!(
+            "(materials: [{}], reactions: [{}])",
+            TWO_MATERIALS, reactions
+        ))
+    }
+
+    #[test]
+    fn invalid_reactions_are_rejected() {
+        assert!(matches!(
+            with_reactions(r#"(reactants: ("a", "c"), products: ("a", "b"))"#),
+            Err(Error::Undefined(name)) if name == "c"
+        ));
+        assert!(matches!(
+            with_reactions(
+                r#"(reactants: ("a", "b"), products: ("b", "a")),
+                (reactants: ("b", "a"), products: ("a", "b")),"#
+            ),
+            Err(Error::DuplicateReaction(..))
+        ));
+        assert!(matches!(
+            with_reactions(r#"(reactants: ("a", "b"), products: ("b", "a"), chance: 1.5)"#),
+            Err(Error::InvalidChance(..))
+        ));
+        assert!(matches!(
+            with_reactions(r#"(reactants: ("any", "a"), products: ("b", "a"))"#),
+            Err(Error::MisplacedAny(_))
+        ));
+        assert!(matches!(
+            with_reactions(r#"(reactants: ("a", "empty"), products: ("b", "a"))"#),
+            Err(Error::Reserved(_))
+        ));
+    }
+}
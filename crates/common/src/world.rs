@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::material::MaterialId;
+
 #[derive(Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Tick(pub u32);
 
@@ -13,8 +15,68 @@ impl Tick {
     }
 }
 
-#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
-pub enum Cell {
-    Empty,
-    Stone,
+// room temperature, in degrees celsius
+pub const AMBIENT_TEMPERATURE: i16 = 20;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Cell {
+    pub material: MaterialId,
+    // degrees celsius
+    pub temperature: i16,
+    // ticks until a burning cell burns out, 0 if it isn't burning
+    pub lifetime: u16,
+}
+
+impl Cell {
+    pub fn new(material: MaterialId) -> Self {
+        Self::with_temperature(material, AMBIENT_TEMPERATURE)
+    }
+
+    pub fn with_temperature(material: MaterialId, temperature: i16) -> Self {
+        Self {
+            material,
+            temperature,
+            lifetime: 0,
+        }
+    }
+
+    pub fn empty() -> Self {
+        Self::new(MaterialId::EMPTY)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.material == MaterialId::EMPTY
+    }
+
+    pub fn is_burning(&self) -> bool {
+        self.lifetime > 0
+    }
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+// a cell flying outside of the grid, as clients see it. only the server simulates them
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FlyingCell {
+    // in cells, (0, 0) being the bottom left corner of the world
+    pub x: f32,
+    pub y: f32,
+    pub material: MaterialId,
+}
+
+// width and height of a chunk, in cells
+pub const CHUNK_SIZE: u32 = 64;
+
+// size of the world, in cells
+pub const WORLD_WIDTH: u32 = 1024;
+pub const WORLD_HEIGHT: u32 = 1024;
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ChunkPosition {
+    pub x: u32,
+    pub y: u32,
 }
@@ -0,0 +1,93 @@
+use game_common::{
+    brush::{Shape, Stroke, MAX_BRUSH_RADIUS},
+    material::MaterialId,
+    world::Tick,
+};
+
+// turns pointer input into strokes for the server
+#[derive(Debug)]
+pub struct Brush {
+    pub material: MaterialId,
+    pub shape: Shape,
+    pub radius: u8,
+    // in cells
+    cursor: Option<(u32, u32)>,
+    down: bool,
+    // where a line starts
+    start: Option<(u32, u32)>,
+    // last point already sent, so the next stroke continues from it without a gap
+    last: Option<(u32, u32)>,
+    // points painted but not sent yet
+    pending: Vec<(u32, u32)>,
+}
+
+impl Brush {
+    pub fn new(material: MaterialId) -> Self {
+        Self {
+            material,
+            shape: Shape::Circle,
+            radius: 4,
+            cursor: None,
+            down: false,
+            start: None,
+            last: None,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn grow(&mut self) {
+        self.radius = (self.radius + 1).min(MAX_BRUSH_RADIUS);
+    }
+
+    pub fn shrink(&mut self) {
+        self.radius = self.radius.saturating_sub(1);
+    }
+
+    pub fn move_to(&mut self, x: u32, y: u32) {
+        self.cursor = Some((x, y));
+        if self.down && self.shape != Shape::Line {
+            self.pending.push((x, y));
+        }
+    }
+
+    pub fn press(&mut self) {
+        self.down = true;
+        self.start = self.cursor;
+        self.last = None;
+        if self.shape != Shape::Line {
+            self.pending.extend(self.cursor);
+        }
+    }
+
+    pub fn release(&mut self) {
+        if self.down && self.shape == Shape::Line {
+            self.pending
+                .extend(self.start.into_iter().chain(self.cursor));
+        }
+        self.down = false;
+        self.start = None;
+    }
+
+    // strokes painted since the last call, split up so the server accepts them
+    pub fn take_strokes(&mut self, tick: Tick) -> Vec<Stroke> {
+        if self.pending.is_empty() {
+            return Vec::new();
+        }
+        let mut points = Vec::new();
+        if self.shape != Shape::Line {
+            points.extend(self.last.take());
+        }
+        points.append(&mut self.pending);
+        if self.down {
+            self.last = points.last().copied();
+        }
+        Stroke {
+            material: self.material,
+            shape: self.shape,
+            radius: self.radius,
+            points,
+            tick,
+        }
+        .split()
+    }
+}
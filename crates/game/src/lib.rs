@@ -1,14 +1,27 @@
+mod brush;
 mod net;
 mod render;
 mod world;
 
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
-use game_common::{ClientPacket, ServerPacket};
-use tracing::debug;
+use game_common::{
+    material::{MaterialId, Materials},
+    world::{Tick, WORLD_HEIGHT, WORLD_WIDTH},
+    ClientPacket, ServerPacket,
+};
+use gnet::protocol::{Delivery, DisconnectReason};
+use tracing::{debug, error, warn};
 use wasm_bindgen::prelude::*;
 use winit::{
-    event::{Event, WindowEvent},
+    dpi::{PhysicalPosition, PhysicalSize},
+    event::{
+        ElementState, Event, KeyboardInput, MouseButton, Touch, TouchPhase, VirtualKeyCode,
+        WindowEvent,
+    },
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
@@ -44,21 +57,35 @@ pub fn start(canvas: web_sys::HtmlCanvasElement) {
 
 pub fn start_internal(mut canvas: web_sys::HtmlCanvasElement) -> Result<(), Error> {
     debug!("creating renderer");
-    let renderer = render::Renderer::new(&mut canvas)?;
+    let mut renderer = render::Renderer::new(&mut canvas)?;
+
+    let materials = Materials::builtin();
+    let mut cells = world::ClientCells::default();
+    let mut particles = world::ClientParticles::default();
 
     debug!("setting up networking");
     let client = Arc::new(gnet::client::Client::<ClientPacket, ServerPacket>::new());
+    // the client is busy until it has connected, and strokes before then would paint over a
+    // world we haven't seen yet
+    let connected = Arc::new(AtomicBool::new(false));
 
     wasm_bindgen_futures::spawn_local({
         let client = client.clone();
+        let connected = connected.clone();
         async move {
-            client.connect(([127, 0, 0, 1], 9000).into()).await.unwrap();
-            client.send_reliable(ClientPacket::SetName {
-                name: "conner".to_string(),
-            });
-            for message in client.recv().await {
-                debug!("got message {:?}", message);
+            if let Err(error) = client.connect(([127, 0, 0, 1], 9000).into()).await {
+                error!(%error, "couldn't connect to the server");
+                return;
             }
+            // the server answers with a snapshot of the world. both are small and on builtin
+            // channels, so they can't be refused
+            client.send_reliable(ClientPacket::Connect()).unwrap();
+            client
+                .send_reliable(ClientPacket::SetName {
+                    name: "conner".to_string(),
+                })
+                .unwrap();
+            connected.store(true, Ordering::Relaxed);
         }
     });
 
@@ -93,6 +120,10 @@ pub fn start_internal(mut canvas: web_sys::HtmlCanvasElement) -> Result<(), Erro
             .unwrap()
     };
 
+    let mut brush = brush::Brush::new(materials.id("sand").unwrap_or(MaterialId::EMPTY));
+    let mut tick = Tick::zero();
+    let mut disconnected = false;
+
     debug!("starting event loop");
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
@@ -101,13 +132,135 @@ pub fn start_internal(mut canvas: web_sys::HtmlCanvasElement) -> Result<(), Erro
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
                 window_id,
-            } if window_id == window.id() => *control_flow = ControlFlow::Exit,
+            } if window_id == window.id() => {
+                client.disconnect();
+                *control_flow = ControlFlow::Exit;
+            }
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::H),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => renderer.toggle_heat_map(),
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(key),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => match key {
+                VirtualKeyCode::B => brush.shape = brush.shape.next(),
+                VirtualKeyCode::LBracket => brush.shrink(),
+                VirtualKeyCode::RBracket => brush.grow(),
+                VirtualKeyCode::N => debug!(stats = ?client.stats(), "connection"),
+                key => {
+                    // 0 erases, 1 to 9 pick materials in the order they're defined
+                    if let Some(material) =
+                        material_key(key).filter(|&id| materials.get(id).is_some())
+                    {
+                        brush.material = material;
+                    }
+                }
+            },
+            Event::WindowEvent {
+                event: WindowEvent::CursorMoved { position, .. },
+                ..
+            } => {
+                let (x, y) = to_cell(position, window.inner_size());
+                brush.move_to(x, y);
+            }
+            Event::WindowEvent {
+                event:
+                    WindowEvent::MouseInput {
+                        state,
+                        button: MouseButton::Left,
+                        ..
+                    },
+                ..
+            } => match state {
+                ElementState::Pressed => brush.press(),
+                ElementState::Released => brush.release(),
+            },
+            Event::WindowEvent {
+                event:
+                    WindowEvent::Touch(Touch {
+                        phase, location, ..
+                    }),
+                ..
+            } => {
+                let (x, y) = to_cell(location, window.inner_size());
+                brush.move_to(x, y);
+                match phase {
+                    TouchPhase::Started => brush.press(),
+                    TouchPhase::Moved => {}
+                    TouchPhase::Ended | TouchPhase::Cancelled => brush.release(),
+                }
+            }
             Event::MainEventsCleared => {
                 window.request_redraw();
             }
             Event::RedrawRequested(_) => {
+                // strokes from before we connected are dropped rather than kept for later
+                let strokes = brush.take_strokes(tick);
+                if connected.load(Ordering::Relaxed) {
+                    for stroke in strokes {
+                        // later strokes paint over earlier ones, so they go in order
+                        let packet = ClientPacket::Paint(stroke);
+                        if let Err(error) = client.send(Delivery::ReliableOrdered, packet) {
+                            warn!(%error, "dropping stroke");
+                        }
+                    }
+                }
+                tick.increment_self();
+                particles.frame();
                 client.process();
-                renderer.render();
+                for received in client.poll() {
+                    match received.packet {
+                        ServerPacket::Snapshot {
+                            tick,
+                            parts,
+                            chunks,
+                        } => cells.snapshot(tick, parts, chunks),
+                        ServerPacket::SetCells { tick, chunks } => cells.set(tick, chunks),
+                        ServerPacket::UpdateCells { tick, chunks } => cells.update(tick, chunks),
+                        ServerPacket::Particles {
+                            particles: flying, ..
+                        } => particles.set(flying),
+                        packet => debug!("got packet {:?}", packet),
+                    }
+                }
+                if !disconnected {
+                    if let Some(reason) = client.disconnected() {
+                        match reason {
+                            DisconnectReason::ProtocolMismatch { .. }
+                            | DisconnectReason::SchemaMismatch { .. } => {
+                                error!(?reason, "the server runs another version, reload the page")
+                            }
+                            _ => warn!(?reason, "disconnected from the server"),
+                        }
+                        disconnected = true;
+                    }
+                }
+                // a texel per cell is a lot to upload, so only when it's shown
+                let heat = (renderer.view() == render::View::Heat).then(|| cells.heat_map());
+                renderer.render(
+                    &cells.fire_sprites(&materials),
+                    &particles.sprites(&materials),
+                    heat.as_deref(),
+                );
             }
             _ => (),
         }
@@ -115,3 +268,21 @@ pub fn start_internal(mut canvas: web_sys::HtmlCanvasElement) -> Result<(), Erro
 
     Ok(())
 }
+
+// the world is stretched over the whole window, with y pointing up
+fn to_cell(position: PhysicalPosition<f64>, window: PhysicalSize<u32>) -> (u32, u32) {
+    let x = position.x / window.width.max(1) as f64 * WORLD_WIDTH as f64;
+    let y = (1.0 - position.y / window.height.max(1) as f64) * WORLD_HEIGHT as f64;
+    (
+        x.clamp(0.0, (WORLD_WIDTH - 1) as f64) as u32,
+        y.clamp(0.0, (WORLD_HEIGHT - 1) as f64) as u32,
+    )
+}
+
+fn material_key(key: VirtualKeyCode) -> Option<MaterialId> {
+    use VirtualKeyCode::*;
+    let keys = [Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9];
+    keys.iter()
+        .position(|&candidate| candidate == key)
+        .map(|index| MaterialId(index as u8))
+}
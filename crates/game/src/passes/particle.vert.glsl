@@ -0,0 +1,13 @@
+attribute vec4 a_vertex_position;
+// x, y in cells, of the particle's center
+attribute vec2 a_particle;
+attribute vec4 a_color;
+uniform mat4 u_projection;
+uniform vec2 u_world_size;
+varying vec4 v_color;
+
+void main() {
+  v_color = a_color;
+  vec2 cell = a_particle + a_vertex_position.xy - 0.5;
+  gl_Position = u_projection * vec4(cell / u_world_size, 0.0, 1.0);
+}
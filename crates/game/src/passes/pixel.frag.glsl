@@ -1,3 +1,19 @@
+precision mediump float;
+uniform bool u_heat_map;
+// a texel per cell, 0 at room temperature or colder and 1 at the hottest shown
+uniform sampler2D u_heat;
+varying vec2 v_position;
+
+// black -> red -> yellow -> white as `t` goes from 0 to 1
+vec3 heat(float t) {
+  return clamp(vec3(t * 3.0, t * 3.0 - 1.0, t * 3.0 - 2.0), 0.0, 1.0);
+}
+
 void main() {
-  gl_FragColor = vec4(gl_FragCoord.xy / 2000.0, 0.0, 1.0);
-}
\ No newline at end of file
+  if (u_heat_map) {
+    gl_FragColor = vec4(heat(texture2D(u_heat, v_position).r), 1.0);
+  } else {
+    vec2 value = gl_FragCoord.xy / 2000.0;
+    gl_FragColor = vec4(value, 0.0, 1.0);
+  }
+}
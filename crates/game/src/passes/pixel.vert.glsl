@@ -1,5 +1,8 @@
 attribute vec4 a_vertex_position;
 uniform mat4 u_projection;
+// where in the world, from 0 to 1 across it
+varying vec2 v_position;
 void main() {
+  v_position = a_vertex_position.xy;
   gl_Position = u_projection * a_vertex_position;
 }
\ No newline at end of file
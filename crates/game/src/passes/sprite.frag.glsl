@@ -1,3 +1,9 @@
+precision mediump float;
+uniform sampler2D u_atlas;
+varying vec2 v_uv;
+
 void main() {
-  discard;
-}
\ No newline at end of file
+  vec4 color = texture2D(u_atlas, v_uv);
+  // the atlas has no alpha channel, fade the dark background out instead
+  gl_FragColor = vec4(color.rgb, max(color.r, max(color.g, color.b)));
+}
@@ -1,5 +1,19 @@
 attribute vec4 a_vertex_position;
+// x, y in cells and the frame of the atlas
+attribute vec3 a_sprite;
 uniform mat4 u_projection;
+uniform vec2 u_world_size;
+varying vec2 v_uv;
+
+// sprites are larger than a cell so flames overlap
+const float SPRITE_SIZE = 4.0;
+
 void main() {
-  gl_Position = u_projection * a_vertex_position;
-}
\ No newline at end of file
+  // 4x4 frames, starting at the top left. the image's first row is at v = 0
+  float column = mod(a_sprite.z, 4.0);
+  float row = floor(a_sprite.z / 4.0);
+  v_uv = vec2(column + a_vertex_position.x, row + 1.0 - a_vertex_position.y) / 4.0;
+
+  vec2 cell = a_sprite.xy + 0.5 + (a_vertex_position.xy - 0.5) * SPRITE_SIZE;
+  gl_Position = u_projection * vec4(cell / u_world_size, 0.0, 1.0);
+}
@@ -1,13 +1,14 @@
 use std::rc::Rc;
 
 use bytemuck::cast_ref;
+use game_common::world::{WORLD_HEIGHT, WORLD_WIDTH};
 use js_sys::Float32Array;
 use tracing::debug;
 use ultraviolet::{projection::lh_yup::orthographic_gl, Mat4, Vec3};
-use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
 use web_sys::{
-    HtmlCanvasElement, WebGl2RenderingContext, WebGlBuffer, WebGlProgram, WebGlShader,
-    WebGlUniformLocation,
+    HtmlCanvasElement, HtmlImageElement, WebGl2RenderingContext, WebGlBuffer, WebGlProgram,
+    WebGlShader, WebGlTexture, WebGlUniformLocation,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -20,10 +21,20 @@ pub enum Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// what the pixel pass shows
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum View {
+    Materials,
+    // debug view of cell temperatures
+    Heat,
+}
+
 pub struct Renderer {
     context: Rc<WebGl2RenderingContext>,
     pixel_pass: PixelPass,
     sprite_pass: SpritePass,
+    particle_pass: ParticlePass,
+    view: View,
 }
 
 impl Renderer {
@@ -37,19 +48,35 @@ impl Renderer {
         );
         let pixel_pass = PixelPass::new(Rc::clone(&context));
         let sprite_pass = SpritePass::new(Rc::clone(&context));
+        let particle_pass = ParticlePass::new(Rc::clone(&context));
         Ok(Self {
             context,
             pixel_pass,
             sprite_pass,
+            particle_pass,
+            view: View::Materials,
         })
     }
 
-    pub fn render(&self) {
+    pub fn toggle_heat_map(&mut self) {
+        self.view = match self.view {
+            View::Materials => View::Heat,
+            View::Heat => View::Materials,
+        };
+        debug!("switched to {:?} view", self.view);
+    }
+
+    pub fn view(&self) -> View {
+        self.view
+    }
+
+    // `heat` is `ClientCells::heat_map`, only needed for the heat view
+    pub fn render(&self, fire: &[FireSprite], particles: &[ParticleSprite], heat: Option<&[u8]>) {
         self.context.clear_color(0.0, 0.0, 0.0, 1.0);
         self.context.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT);
-        self.pixel_pass.render();
-        self.sprite_pass.render();
-     
+        self.pixel_pass.render(self.view, heat);
+        self.particle_pass.render(particles);
+        self.sprite_pass.render(fire);
     }
 }
 
@@ -57,9 +84,13 @@ struct PixelPass {
     context: Rc<WebGl2RenderingContext>,
     position_buffer: WebGlBuffer,
     program: WebGlProgram,
+    // a texel per cell, its temperature
+    heat: WebGlTexture,
     // vertex_position attribute location
     a_vertex_position: i32,
     u_projection: WebGlUniformLocation,
+    u_heat_map: WebGlUniformLocation,
+    u_heat: WebGlUniformLocation,
 }
 
 impl PixelPass {
@@ -75,6 +106,56 @@ impl PixelPass {
         buffer
     }
 
+    // empty until the heat view first uploads temperatures
+    fn create_heat_texture(context: &WebGl2RenderingContext) -> WebGlTexture {
+        let texture = context.create_texture().unwrap();
+        context.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&texture));
+        // a cell per texel, with no mipmaps
+        for filter in [
+            WebGl2RenderingContext::TEXTURE_MIN_FILTER,
+            WebGl2RenderingContext::TEXTURE_MAG_FILTER,
+        ]
+        .iter()
+        {
+            context.tex_parameteri(
+                WebGl2RenderingContext::TEXTURE_2D,
+                *filter,
+                WebGl2RenderingContext::NEAREST as i32,
+            );
+        }
+        for wrap in [
+            WebGl2RenderingContext::TEXTURE_WRAP_S,
+            WebGl2RenderingContext::TEXTURE_WRAP_T,
+        ]
+        .iter()
+        {
+            context.tex_parameteri(
+                WebGl2RenderingContext::TEXTURE_2D,
+                *wrap,
+                WebGl2RenderingContext::CLAMP_TO_EDGE as i32,
+            );
+        }
+        texture
+    }
+
+    fn upload_heat(&self, heat: &[u8]) {
+        self.context
+            .bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&self.heat));
+        self.context
+            .tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+                WebGl2RenderingContext::TEXTURE_2D,
+                0,
+                WebGl2RenderingContext::R8 as i32,
+                WORLD_WIDTH as i32,
+                WORLD_HEIGHT as i32,
+                0,
+                WebGl2RenderingContext::RED,
+                WebGl2RenderingContext::UNSIGNED_BYTE,
+                Some(heat),
+            )
+            .unwrap();
+    }
+
     pub fn new(context: Rc<WebGl2RenderingContext>) -> Self {
         debug!("creating pixel pass");
         let vert = load_shader(
@@ -89,10 +170,15 @@ impl PixelPass {
         );
         let program = init_program(&context, vert, frag);
         let position_buffer = Self::create_position_buffer(&&context);
+        let heat = Self::create_heat_texture(&context);
         let a_vertex_position = context.get_attrib_location(&program, "a_vertex_position");
         let u_projection = context
             .get_uniform_location(&program, "u_projection")
             .unwrap();
+        let u_heat_map = context
+            .get_uniform_location(&program, "u_heat_map")
+            .unwrap();
+        let u_heat = context.get_uniform_location(&program, "u_heat").unwrap();
         // let u_model_view = context
         //     .get_uniform_location(&program, "u_model_view")
         //     .unwrap();
@@ -100,12 +186,15 @@ impl PixelPass {
             context,
             position_buffer,
             program,
+            heat,
             a_vertex_position,
             u_projection,
+            u_heat_map,
+            u_heat,
         }
     }
 
-    pub fn render(&self) {
+    pub fn render(&self, view: View, heat: Option<&[u8]>) {
         let perspective = {
             let matrix = orthographic_gl(0.0, 1.0, 0.0, 1.0, -1.0, 1.0);
             matrix
@@ -146,6 +235,14 @@ impl PixelPass {
             false,
             cast_ref::<_, [f32; 16]>(&perspective),
         );
+        self.context
+            .uniform1i(Some(&self.u_heat_map), (view == View::Heat) as i32);
+        if let (View::Heat, Some(heat)) = (view, heat) {
+            self.context
+                .active_texture(WebGl2RenderingContext::TEXTURE0);
+            self.upload_heat(heat);
+            self.context.uniform1i(Some(&self.u_heat), 0);
+        }
         // self.context.uniform_matrix4fv_with_f32_array(
         //     Some(&self.u_model_view),
         //     false,
@@ -161,13 +258,36 @@ impl PixelPass {
     }
 }
 
+// a burning cell, drawn with a frame of the fire atlas
+#[derive(Debug, Copy, Clone)]
+pub struct FireSprite {
+    // in cells
+    pub x: f32,
+    pub y: f32,
+    // 0 to `FRAMES - 1`, from just lit to burnt out
+    pub frame: f32,
+}
+
+impl FireSprite {
+    // the atlas is 4x4 frames, starting at the top left
+    pub const FRAMES: u32 = 16;
+    const FLOATS: usize = 3;
+}
+
+const FIRE_ATLAS: &str = "assets/fire-texture-atlas.jpg";
+
 struct SpritePass {
     context: Rc<WebGl2RenderingContext>,
     position_buffer: WebGlBuffer,
+    // per-instance `FireSprite`s
+    sprite_buffer: WebGlBuffer,
     program: WebGlProgram,
+    atlas: WebGlTexture,
     // vertex_position attribute location
     a_vertex_position: i32,
+    a_sprite: i32,
     u_projection: WebGlUniformLocation,
+    u_world_size: WebGlUniformLocation,
 }
 
 impl SpritePass {
@@ -183,6 +303,67 @@ impl SpritePass {
         buffer
     }
 
+    // the texture is blank until the image has loaded
+    fn load_atlas(context: &Rc<WebGl2RenderingContext>) -> WebGlTexture {
+        let texture = context.create_texture().unwrap();
+        context.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&texture));
+        context
+            .tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+                WebGl2RenderingContext::TEXTURE_2D,
+                0,
+                WebGl2RenderingContext::RGBA as i32,
+                1,
+                1,
+                0,
+                WebGl2RenderingContext::RGBA,
+                WebGl2RenderingContext::UNSIGNED_BYTE,
+                Some(&[0, 0, 0, 0]),
+            )
+            .unwrap();
+
+        let image = HtmlImageElement::new().unwrap();
+        let onload = Closure::once_into_js({
+            let context = Rc::clone(context);
+            let texture = texture.clone();
+            let image = image.clone();
+            move || {
+                debug!("loaded fire atlas");
+                context.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&texture));
+                context
+                    .tex_image_2d_with_u32_and_u32_and_html_image_element(
+                        WebGl2RenderingContext::TEXTURE_2D,
+                        0,
+                        WebGl2RenderingContext::RGBA as i32,
+                        WebGl2RenderingContext::RGBA,
+                        WebGl2RenderingContext::UNSIGNED_BYTE,
+                        &image,
+                    )
+                    .unwrap();
+                context.tex_parameteri(
+                    WebGl2RenderingContext::TEXTURE_2D,
+                    WebGl2RenderingContext::TEXTURE_MIN_FILTER,
+                    WebGl2RenderingContext::LINEAR as i32,
+                );
+                // neighboring frames shouldn't bleed in at the edges
+                for wrap in [
+                    WebGl2RenderingContext::TEXTURE_WRAP_S,
+                    WebGl2RenderingContext::TEXTURE_WRAP_T,
+                ]
+                .iter()
+                {
+                    context.tex_parameteri(
+                        WebGl2RenderingContext::TEXTURE_2D,
+                        *wrap,
+                        WebGl2RenderingContext::CLAMP_TO_EDGE as i32,
+                    );
+                }
+            }
+        });
+        image.set_onload(Some(onload.unchecked_ref()));
+        image.set_src(FIRE_ATLAS);
+        texture
+    }
+
     pub fn new(context: Rc<WebGl2RenderingContext>) -> Self {
         debug!("creating sprite pass");
         let vert = load_shader(
@@ -196,34 +377,36 @@ impl SpritePass {
             include_str!("passes/sprite.frag.glsl"),
         );
         let program = init_program(&context, vert, frag);
-        let position_buffer = Self::create_position_buffer(&&context);
+        let position_buffer = Self::create_position_buffer(&context);
+        let sprite_buffer = context.create_buffer().unwrap();
+        let atlas = Self::load_atlas(&context);
         let a_vertex_position = context.get_attrib_location(&program, "a_vertex_position");
+        let a_sprite = context.get_attrib_location(&program, "a_sprite");
         let u_projection = context
             .get_uniform_location(&program, "u_projection")
             .unwrap();
-        // let u_model_view = context
-        //     .get_uniform_location(&program, "u_model_view")
-        //     .unwrap();
+        let u_world_size = context
+            .get_uniform_location(&program, "u_world_size")
+            .unwrap();
         Self {
             context,
             position_buffer,
+            sprite_buffer,
             program,
+            atlas,
             a_vertex_position,
+            a_sprite,
             u_projection,
+            u_world_size,
         }
     }
 
-    pub fn render(&self) {
-        let perspective = {
-            let matrix = orthographic_gl(0.0, 1.0, 0.0, 1.0, -1.0, 1.0);
-            matrix
-        };
+    pub fn render(&self, sprites: &[FireSprite]) {
+        if sprites.is_empty() {
+            return;
+        }
 
-        let _model_view = {
-            let mut matrix = Mat4::identity();
-            matrix.translate(&Vec3::new(0.0, 0.0, -6.0));
-            matrix
-        };
+        let perspective = orthographic_gl(0.0, 1.0, 0.0, 1.0, -1.0, 1.0);
 
         self.context.use_program(Some(&self.program));
 
@@ -249,22 +432,221 @@ impl SpritePass {
                 .enable_vertex_attrib_array(self.a_vertex_position as u32);
         }
 
+        {
+            let data = sprites
+                .iter()
+                .flat_map(|sprite| vec![sprite.x, sprite.y, sprite.frame])
+                .collect::<Vec<_>>();
+            self.context.bind_buffer(
+                WebGl2RenderingContext::ARRAY_BUFFER,
+                Some(&self.sprite_buffer),
+            );
+            self.context.buffer_data_with_array_buffer_view(
+                WebGl2RenderingContext::ARRAY_BUFFER,
+                &Float32Array::from(&data[..]),
+                WebGl2RenderingContext::DYNAMIC_DRAW,
+            );
+            self.context.vertex_attrib_pointer_with_i32(
+                self.a_sprite as u32,
+                FireSprite::FLOATS as i32,
+                WebGl2RenderingContext::FLOAT,
+                false,
+                0,
+                0,
+            );
+            self.context
+                .enable_vertex_attrib_array(self.a_sprite as u32);
+            self.context.vertex_attrib_divisor(self.a_sprite as u32, 1);
+        }
+
         self.context.uniform_matrix4fv_with_f32_array(
             Some(&self.u_projection),
             false,
             cast_ref::<_, [f32; 16]>(&perspective),
         );
-        // self.context.uniform_matrix4fv_with_f32_array(
-        //     Some(&self.u_model_view),
-        //     false,
-        //     cast_ref::<_, [f32; 16]>(&model_view),
-        // );
+        self.context.uniform2f(
+            Some(&self.u_world_size),
+            WORLD_WIDTH as f32,
+            WORLD_HEIGHT as f32,
+        );
+        self.context
+            .active_texture(WebGl2RenderingContext::TEXTURE0);
+        self.context
+            .bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&self.atlas));
 
+        // additive, the atlas has a black background
+        self.context.enable(WebGl2RenderingContext::BLEND);
+        self.context.blend_func(
+            WebGl2RenderingContext::SRC_ALPHA,
+            WebGl2RenderingContext::ONE,
+        );
         {
             let offset = 0;
             let vertex_count = 4;
-            self.context
-                .draw_arrays(WebGl2RenderingContext::TRIANGLE_STRIP, offset, vertex_count);
+            self.context.draw_arrays_instanced(
+                WebGl2RenderingContext::TRIANGLE_STRIP,
+                offset,
+                vertex_count,
+                sprites.len() as i32,
+            );
+        }
+        self.context.disable(WebGl2RenderingContext::BLEND);
+        self.context.vertex_attrib_divisor(self.a_sprite as u32, 0);
+        self.context
+            .disable_vertex_attrib_array(self.a_sprite as u32);
+    }
+}
+
+// a cell flying outside of the grid, drawn as a square of its material's color
+#[derive(Debug, Copy, Clone)]
+pub struct ParticleSprite {
+    // in cells, of its center
+    pub x: f32,
+    pub y: f32,
+    // rgba, from 0 to 1
+    pub color: [f32; 4],
+}
+
+impl ParticleSprite {
+    const FLOATS: usize = 6;
+}
+
+struct ParticlePass {
+    context: Rc<WebGl2RenderingContext>,
+    position_buffer: WebGlBuffer,
+    // per-instance `ParticleSprite`s
+    particle_buffer: WebGlBuffer,
+    program: WebGlProgram,
+    a_vertex_position: i32,
+    a_particle: i32,
+    a_color: i32,
+    u_projection: WebGlUniformLocation,
+    u_world_size: WebGlUniformLocation,
+}
+
+impl ParticlePass {
+    pub fn new(context: Rc<WebGl2RenderingContext>) -> Self {
+        debug!("creating particle pass");
+        let vert = load_shader(
+            &context,
+            WebGl2RenderingContext::VERTEX_SHADER,
+            include_str!("passes/particle.vert.glsl"),
+        );
+        let frag = load_shader(
+            &context,
+            WebGl2RenderingContext::FRAGMENT_SHADER,
+            include_str!("passes/particle.frag.glsl"),
+        );
+        let program = init_program(&context, vert, frag);
+        // the same unit square as the sprites
+        let position_buffer = SpritePass::create_position_buffer(&context);
+        let particle_buffer = context.create_buffer().unwrap();
+        let a_vertex_position = context.get_attrib_location(&program, "a_vertex_position");
+        let a_particle = context.get_attrib_location(&program, "a_particle");
+        let a_color = context.get_attrib_location(&program, "a_color");
+        let u_projection = context
+            .get_uniform_location(&program, "u_projection")
+            .unwrap();
+        let u_world_size = context
+            .get_uniform_location(&program, "u_world_size")
+            .unwrap();
+        Self {
+            context,
+            position_buffer,
+            particle_buffer,
+            program,
+            a_vertex_position,
+            a_particle,
+            a_color,
+            u_projection,
+            u_world_size,
+        }
+    }
+
+    pub fn render(&self, particles: &[ParticleSprite]) {
+        if particles.is_empty() {
+            return;
+        }
+
+        let perspective = orthographic_gl(0.0, 1.0, 0.0, 1.0, -1.0, 1.0);
+
+        self.context.use_program(Some(&self.program));
+
+        self.context.bind_buffer(
+            WebGl2RenderingContext::ARRAY_BUFFER,
+            Some(&self.position_buffer),
+        );
+        self.context.vertex_attrib_pointer_with_i32(
+            self.a_vertex_position as u32,
+            2,
+            WebGl2RenderingContext::FLOAT,
+            false,
+            0,
+            0,
+        );
+        self.context
+            .enable_vertex_attrib_array(self.a_vertex_position as u32);
+
+        {
+            let data = particles
+                .iter()
+                .flat_map(|particle| {
+                    let [r, g, b, a] = particle.color;
+                    vec![particle.x, particle.y, r, g, b, a]
+                })
+                .collect::<Vec<_>>();
+            self.context.bind_buffer(
+                WebGl2RenderingContext::ARRAY_BUFFER,
+                Some(&self.particle_buffer),
+            );
+            self.context.buffer_data_with_array_buffer_view(
+                WebGl2RenderingContext::ARRAY_BUFFER,
+                &Float32Array::from(&data[..]),
+                WebGl2RenderingContext::DYNAMIC_DRAW,
+            );
+            let stride = (ParticleSprite::FLOATS * std::mem::size_of::<f32>()) as i32;
+            // x, y, then the color
+            for (attribute, size, offset) in [(self.a_particle, 2, 0), (self.a_color, 4, 2)].iter()
+            {
+                self.context.vertex_attrib_pointer_with_i32(
+                    *attribute as u32,
+                    *size,
+                    WebGl2RenderingContext::FLOAT,
+                    false,
+                    stride,
+                    offset * std::mem::size_of::<f32>() as i32,
+                );
+                self.context.enable_vertex_attrib_array(*attribute as u32);
+                self.context.vertex_attrib_divisor(*attribute as u32, 1);
+            }
+        }
+
+        self.context.uniform_matrix4fv_with_f32_array(
+            Some(&self.u_projection),
+            false,
+            cast_ref::<_, [f32; 16]>(&perspective),
+        );
+        self.context.uniform2f(
+            Some(&self.u_world_size),
+            WORLD_WIDTH as f32,
+            WORLD_HEIGHT as f32,
+        );
+
+        self.context.enable(WebGl2RenderingContext::BLEND);
+        self.context.blend_func(
+            WebGl2RenderingContext::SRC_ALPHA,
+            WebGl2RenderingContext::ONE_MINUS_SRC_ALPHA,
+        );
+        self.context.draw_arrays_instanced(
+            WebGl2RenderingContext::TRIANGLE_STRIP,
+            0,
+            4,
+            particles.len() as i32,
+        );
+        self.context.disable(WebGl2RenderingContext::BLEND);
+        for attribute in [self.a_particle, self.a_color].iter() {
+            self.context.vertex_attrib_divisor(*attribute as u32, 0);
+            self.context.disable_vertex_attrib_array(*attribute as u32);
         }
     }
 }
@@ -1 +1,242 @@
+use std::collections::{HashMap, HashSet};
 
+use game_common::{
+    delta::ChunkDelta,
+    material::Materials,
+    world::{
+        Cell, ChunkPosition, FlyingCell, Tick, AMBIENT_TEMPERATURE, CHUNK_SIZE, WORLD_HEIGHT,
+        WORLD_WIDTH,
+    },
+};
+use tracing::warn;
+
+use crate::render::{FireSprite, ParticleSprite};
+
+// cells this hot or hotter are white in the heat map, about as hot as lava gets
+const HEAT_MAP_MAX_TEMPERATURE: i16 = 1200;
+// frames particles stay up without word from the server, in case the packet saying they all
+// landed was lost
+const PARTICLE_TIMEOUT_FRAMES: u32 = 30;
+
+// the cells the server has sent so far
+#[derive(Debug)]
+pub struct ClientCells {
+    chunks: HashMap<ChunkPosition, Vec<Cell>>,
+    // of the newest packet applied
+    tick: Option<Tick>,
+    // until the whole join snapshot has arrived
+    joining: Option<Joining>,
+}
+
+#[derive(Debug, Default)]
+struct Joining {
+    // every update so far, to replay over the snapshot's chunks as they arrive
+    updates: Vec<(Tick, Vec<ChunkDelta>)>,
+    // snapshot packets still to come, once the first has arrived
+    remaining: Option<u32>,
+}
+
+impl Default for ClientCells {
+    fn default() -> Self {
+        Self {
+            chunks: HashMap::new(),
+            tick: None,
+            joining: Some(Joining::default()),
+        }
+    }
+}
+
+impl ClientCells {
+    // part of the join snapshot, like `ServerPacket::Snapshot`. it comes reliably, behind
+    // updates that are newer, so it's always applied, and then the updates from its tick on are
+    // applied again over its chunks
+    pub fn snapshot(&mut self, tick: Tick, parts: u32, chunks: Vec<ChunkDelta>) {
+        let joining = match &mut self.joining {
+            Some(joining) => joining,
+            None => return self.set(tick, chunks),
+        };
+        let snapshot_chunks = chunks
+            .iter()
+            .map(|delta| delta.chunk)
+            .collect::<HashSet<_>>();
+        let replay = joining
+            .updates
+            .iter()
+            .filter(|(update_tick, _)| *update_tick >= tick)
+            .flat_map(|(_, deltas)| deltas)
+            .filter(|delta| snapshot_chunks.contains(&delta.chunk))
+            .cloned()
+            .collect::<Vec<_>>();
+        let remaining = joining.remaining.unwrap_or(parts).saturating_sub(1);
+        joining.remaining = Some(remaining);
+        if remaining == 0 {
+            self.joining = None;
+        }
+
+        let newest = self.tick.max(Some(tick));
+        self.apply(tick, chunks);
+        self.apply(tick, replay);
+        self.tick = newest;
+    }
+
+    // part of a keyframe, like `ServerPacket::SetCells`. the chunks are described from scratch
+    // and cover empty ones too, so nothing needs clearing
+    pub fn set(&mut self, tick: Tick, chunks: Vec<ChunkDelta>) {
+        if self.is_stale(tick) {
+            return;
+        }
+        self.apply(tick, chunks);
+    }
+
+    // changes, like `ServerPacket::UpdateCells`
+    pub fn update(&mut self, tick: Tick, chunks: Vec<ChunkDelta>) {
+        if self.is_stale(tick) {
+            return;
+        }
+        if let Some(joining) = &mut self.joining {
+            joining.updates.push((tick, chunks.clone()));
+        }
+        self.apply(tick, chunks);
+    }
+
+    // packets older than what we have would undo newer changes. packets from the same tick are
+    // fine, since deltas set cells rather than change them
+    fn is_stale(&self, tick: Tick) -> bool {
+        self.tick.is_some_and(|known| tick < known)
+    }
+
+    fn apply(&mut self, tick: Tick, chunks: Vec<ChunkDelta>) {
+        self.tick = Some(tick);
+        for delta in chunks {
+            let cells = self
+                .chunks
+                .entry(delta.chunk)
+                .or_insert_with(|| vec![Cell::empty(); (CHUNK_SIZE * CHUNK_SIZE) as usize]);
+            if delta.apply(cells).is_none() {
+                warn!("malformed delta for chunk {:?}", delta.chunk);
+            }
+        }
+    }
+
+    // (x, y, cell) of every known cell
+    fn iter(&self) -> impl Iterator<Item = (u32, u32, Cell)> + '_ {
+        self.chunks.iter().flat_map(|(chunk, cells)| {
+            cells.iter().enumerate().map(move |(index, &cell)| {
+                let x = chunk.x * CHUNK_SIZE + index as u32 % CHUNK_SIZE;
+                let y = chunk.y * CHUNK_SIZE + index as u32 / CHUNK_SIZE;
+                (x, y, cell)
+            })
+        })
+    }
+
+    // the heat map's texels, a byte per cell in rows from the bottom. ambient and colder cells,
+    // and those not received yet, are 0, and `HEAT_MAP_MAX_TEMPERATURE` and hotter ones 255
+    pub fn heat_map(&self) -> Vec<u8> {
+        let range = (HEAT_MAP_MAX_TEMPERATURE - AMBIENT_TEMPERATURE) as f32;
+        let mut texels = vec![0; (WORLD_WIDTH * WORLD_HEIGHT) as usize];
+        for (x, y, cell) in self.iter() {
+            if x >= WORLD_WIDTH || y >= WORLD_HEIGHT {
+                continue;
+            }
+            let warmth = (cell.temperature as i32 - AMBIENT_TEMPERATURE as i32).max(0) as f32;
+            texels[(y * WORLD_WIDTH + x) as usize] = ((warmth / range).min(1.0) * 255.0) as u8;
+        }
+        texels
+    }
+
+    // one sprite per burning cell, further along the animation the closer it is to burning out
+    pub fn fire_sprites(&self, materials: &Materials) -> Vec<FireSprite> {
+        self.iter()
+            .filter(|(_, _, cell)| cell.is_burning())
+            .map(|(x, y, cell)| {
+                let ticks = materials
+                    .get(cell.material)
+                    .and_then(|material| material.burning.as_ref())
+                    .map_or(1, |burning| burning.ticks.max(1));
+                let burnt = 1.0 - (cell.lifetime.min(ticks) as f32 / ticks as f32);
+                FireSprite {
+                    x: x as f32,
+                    y: y as f32,
+                    frame: (burnt * (FireSprite::FRAMES - 1) as f32).round(),
+                }
+            })
+            .collect()
+    }
+}
+
+// the cells flying outside of the grid, as of the newest `ServerPacket::Particles`
+#[derive(Debug, Default)]
+pub struct ClientParticles {
+    particles: Vec<FlyingCell>,
+    // frames since they were sent
+    age: u32,
+}
+
+impl ClientParticles {
+    // the particles channel is sequenced, so these are always newer
+    pub fn set(&mut self, particles: Vec<FlyingCell>) {
+        self.particles = particles;
+        self.age = 0;
+    }
+
+    // once per frame
+    pub fn frame(&mut self) {
+        self.age += 1;
+        if self.age > PARTICLE_TIMEOUT_FRAMES {
+            self.particles.clear();
+        }
+    }
+
+    pub fn sprites(&self, materials: &Materials) -> Vec<ParticleSprite> {
+        self.particles
+            .iter()
+            .map(|particle| {
+                let color = materials
+                    .get(particle.material)
+                    .map_or([0, 0, 0, 255], |material| {
+                        let color = &material.color;
+                        [color.0, color.1, color.2, color.3]
+                    });
+                ParticleSprite {
+                    x: particle.x,
+                    y: particle.y,
+                    color: color.map(|channel| channel as f32 / 255.0),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AREA: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+    #[test]
+    fn snapshots_apply_behind_newer_updates() {
+        let materials = Materials::builtin();
+        let sand = materials.cell(materials.id("sand").unwrap());
+        let left = ChunkPosition { x: 0, y: 0 };
+        let right = ChunkPosition { x: 1, y: 0 };
+        let mut joined = vec![Cell::empty(); AREA];
+        joined[..4].fill(sand);
+        // a tick later, one grain of sand falls somewhere else
+        let mut fallen = joined.clone();
+        fallen[0] = Cell::empty();
+        fallen[100] = sand;
+
+        let mut cells = ClientCells::default();
+        let update = ChunkDelta::between(left, &joined, &fallen).unwrap();
+        cells.update(Tick(11), vec![update]);
+        cells.snapshot(Tick(10), 2, vec![ChunkDelta::full(left, &joined)]);
+        assert_eq!(cells.chunks[&left], fallen);
+        // an update older than the newest one is still stale
+        cells.update(Tick(10), vec![ChunkDelta::full(left, &joined)]);
+        assert_eq!(cells.chunks[&left], fallen);
+
+        cells.snapshot(Tick(10), 2, vec![ChunkDelta::full(right, &joined)]);
+        assert_eq!(cells.chunks[&right], joined);
+        assert!(cells.joining.is_none());
+    }
+}
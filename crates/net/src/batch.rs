@@ -0,0 +1,177 @@
+use std::convert::{TryFrom, TryInto};
+
+use crate::{
+    fragment::MAX_DATAGRAM_SIZE,
+    frame::{Header, Kind, HEADER_SIZE},
+};
+
+// each message in a batch starts with its length, as a little endian u16
+pub(crate) const FRAME_HEADER: usize = 2;
+// the longest message that length can describe
+pub(crate) const MAX_FRAMED_SIZE: usize = u16::MAX as usize;
+// what's left of a datagram once the batch has its own header
+const MAX_FRAMES_SIZE: usize = MAX_DATAGRAM_SIZE - HEADER_SIZE;
+
+// the messages of one flush, packed into as few datagrams as they fit in
+#[derive(Debug, Default)]
+pub(crate) struct Batcher {
+    datagrams: Vec<Datagram>,
+}
+
+#[derive(Debug)]
+enum Datagram {
+    // too large to share, so it goes as is, and fragmented if need be
+    Alone(Vec<u8>),
+    Batch { messages: Vec<Vec<u8>>, size: usize },
+}
+
+impl Batcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // adds `message` to the batch being filled, or starts another if it doesn't fit. messages
+    // keep their order
+    pub fn push(&mut self, message: Vec<u8>) {
+        let framed = FRAME_HEADER + message.len();
+        if framed > MAX_FRAMES_SIZE {
+            self.datagrams.push(Datagram::Alone(message));
+            return;
+        }
+        if let Some(Datagram::Batch { messages, size }) = self.datagrams.last_mut() {
+            if *size + framed <= MAX_FRAMES_SIZE {
+                messages.push(message);
+                *size += framed;
+                return;
+            }
+        }
+        self.datagrams.push(Datagram::Batch {
+            messages: vec![message],
+            size: framed,
+        });
+    }
+
+    // the datagrams to send. batches of more than one message go as a batch frame
+    pub fn finish(self) -> Vec<Vec<u8>> {
+        self.datagrams
+            .into_iter()
+            .map(|datagram| match datagram {
+                Datagram::Alone(message) => message,
+                Datagram::Batch { mut messages, .. } if messages.len() == 1 => messages.remove(0),
+                Datagram::Batch { messages, .. } => {
+                    Header::bare(Kind::Batch).frame(&frame(&messages))
+                }
+            })
+            .collect()
+    }
+}
+
+// `messages` one after the other, each behind its length. panics if one is longer than
+// `MAX_FRAMED_SIZE`, rather than cutting its length short
+pub(crate) fn frame(messages: &[Vec<u8>]) -> Vec<u8> {
+    let size = messages
+        .iter()
+        .map(|message| FRAME_HEADER + message.len())
+        .sum();
+    let mut frames = Vec::with_capacity(size);
+    for message in messages {
+        let length = u16::try_from(message.len()).expect("message too long to frame");
+        frames.extend_from_slice(&length.to_le_bytes());
+        frames.extend_from_slice(message);
+    }
+    frames
+}
+
+// the messages in the frames of a batch, or `None` if the last one is cut short
+pub(crate) fn unframe(mut frames: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut messages = Vec::new();
+    while !frames.is_empty() {
+        if frames.len() < FRAME_HEADER {
+            return None;
+        }
+        let (header, rest) = frames.split_at(FRAME_HEADER);
+        let length = u16::from_le_bytes(header.try_into().unwrap()) as usize;
+        if rest.len() < length {
+            return None;
+        }
+        let (message, rest) = rest.split_at(length);
+        messages.push(message.to_vec());
+        frames = rest;
+    }
+    Some(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame;
+
+    // the messages in a batch frame
+    fn unbatch(datagram: &[u8]) -> Vec<Vec<u8>> {
+        let (header, frames) = frame::decode(datagram).unwrap();
+        assert_eq!(header.kind, Kind::Batch);
+        unframe(frames).unwrap()
+    }
+
+    #[test]
+    fn small_messages_share_datagrams() {
+        let mut batcher = Batcher::new();
+        let messages = (0..100u8).map(|index| vec![index; 50]).collect::<Vec<_>>();
+        for message in &messages {
+            batcher.push(message.clone());
+        }
+        let datagrams = batcher.finish();
+        // 52 bytes a frame, 22 to a datagram
+        assert_eq!(datagrams.len(), 5);
+        let unframed = datagrams
+            .iter()
+            .flat_map(|datagram| unbatch(datagram))
+            .collect::<Vec<_>>();
+        assert_eq!(unframed, messages);
+    }
+
+    #[test]
+    fn large_and_lone_messages_go_as_they_are() {
+        let mut batcher = Batcher::new();
+        batcher.push(vec![1; 10]);
+        batcher.push(vec![2; 5000]);
+        batcher.push(vec![3; 10]);
+        batcher.push(vec![4; 10]);
+        let datagrams = batcher.finish();
+        assert_eq!(datagrams.len(), 3);
+        assert_eq!(datagrams[0], vec![1; 10]);
+        assert_eq!(datagrams[1], vec![2; 5000]);
+        assert_eq!(unbatch(&datagrams[2]), vec![vec![3; 10], vec![4; 10]]);
+    }
+
+    #[test]
+    fn full_batches_fit_in_a_datagram() {
+        let mut batcher = Batcher::new();
+        batcher.push(vec![0; 1]);
+        batcher.push(vec![0; MAX_FRAMES_SIZE - 2 * FRAME_HEADER - 1]);
+        let datagram = batcher.finish().remove(0);
+        assert_eq!(datagram.len(), MAX_DATAGRAM_SIZE);
+        assert_eq!(unbatch(&datagram).len(), 2);
+    }
+
+    #[test]
+    fn cut_frames_are_rejected() {
+        assert_eq!(unframe(&[]), Some(Vec::new()));
+        assert_eq!(unframe(&[1]), None);
+        assert_eq!(unframe(&[3, 0, 1, 2]), None);
+        assert_eq!(unframe(&[0, 0, 1, 0, 7]), Some(vec![vec![], vec![7]]));
+        assert_eq!(frame(&[vec![], vec![7]]), vec![0, 0, 1, 0, 7]);
+    }
+
+    #[test]
+    fn the_longest_message_round_trips() {
+        let longest = vec![vec![7; MAX_FRAMED_SIZE]];
+        assert_eq!(unframe(&frame(&longest)), Some(longest));
+    }
+
+    #[test]
+    #[should_panic(expected = "too long to frame")]
+    fn longer_messages_are_never_cut_short() {
+        frame(&[vec![7; MAX_FRAMED_SIZE + 1]]);
+    }
+}
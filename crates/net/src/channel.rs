@@ -0,0 +1,644 @@
+use std::{collections::VecDeque, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tracing::trace;
+
+use crate::{
+    batch::{FRAME_HEADER, MAX_FRAMED_SIZE},
+    fragment::MAX_MESSAGE_SIZE,
+    frame::HEADER_SIZE,
+    protocol::{AckId, BufferResult, Delivery, ReliableBuffer},
+    stats::Traffic,
+};
+
+// bytes per second each peer may be sent, unless changed
+const DEFAULT_BANDWIDTH: u32 = 4 << 20;
+// how long unused bandwidth is saved up for, so a quiet connection can send a burst at once
+const BURST: Duration = Duration::from_millis(100);
+// how many messages each redundant datagram carries, counting the new one
+const REDUNDANCY: usize = 3;
+// how many packets an unreliable channel keeps waiting for bandwidth. past it the oldest are
+// dropped, being the stalest
+const MAX_WAITING: usize = 256;
+
+/// One of the declared channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct ChannelId(pub(crate) u8);
+
+impl ChannelId {
+    pub const RELIABLE: Self = Self(0);
+    pub const RELIABLE_ORDERED: Self = Self(1);
+    pub const UNRELIABLE: Self = Self(2);
+    pub const UNRELIABLE_SEQUENCED: Self = Self(3);
+    pub const UNRELIABLE_REDUNDANT: Self = Self(4);
+
+    /// The channel every `Channels` starts with for `delivery`.
+    pub fn builtin(delivery: Delivery) -> Self {
+        match delivery {
+            Delivery::Reliable => Self::RELIABLE,
+            Delivery::ReliableOrdered => Self::RELIABLE_ORDERED,
+            Delivery::Unreliable => Self::UNRELIABLE,
+            Delivery::UnreliableSequenced => Self::UNRELIABLE_SEQUENCED,
+            Delivery::UnreliableRedundant => Self::UNRELIABLE_REDUNDANT,
+        }
+    }
+}
+
+/// Why a packet couldn't be queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum QueueError {
+    #[error("channel {0:?} wasn't declared")]
+    UnknownChannel(ChannelId),
+    /// Even fragmented, it wouldn't fit in a message.
+    #[error("packet of {size} bytes is over the {max} bytes its channel can send")]
+    TooLarge { size: usize, max: usize },
+    /// The client is connecting, or in use on another thread. Try again later.
+    #[error("the client is busy connecting")]
+    Busy,
+}
+
+/// How a channel's packets are sent, and how much of the bandwidth they get.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelConfig {
+    pub delivery: Delivery,
+    /// Channels with higher priorities get what's left of the bandwidth first.
+    pub priority: u8,
+    /// The part of the bandwidth kept for this channel, from 0 to 1, whatever the priorities.
+    pub share: f64,
+}
+
+impl ChannelConfig {
+    pub fn new(delivery: Delivery) -> Self {
+        Self {
+            delivery,
+            priority: 0,
+            share: 0.0,
+        }
+    }
+}
+
+/// The channels a side sends on, and how many bytes per second it may send each peer.
+///
+/// Only the sending side has to declare a channel: packets say how the receiving side should
+/// treat them.
+#[derive(Debug, Clone)]
+pub struct Channels {
+    channels: Vec<(String, ChannelConfig)>,
+    pub bandwidth: u32,
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        let builtin = [
+            ("reliable", Delivery::Reliable),
+            ("reliable-ordered", Delivery::ReliableOrdered),
+            ("unreliable", Delivery::Unreliable),
+            ("unreliable-sequenced", Delivery::UnreliableSequenced),
+            ("unreliable-redundant", Delivery::UnreliableRedundant),
+        ];
+        Self {
+            channels: builtin
+                .iter()
+                .map(|(name, delivery)| (name.to_string(), ChannelConfig::new(*delivery)))
+                .collect(),
+            bandwidth: DEFAULT_BANDWIDTH,
+        }
+    }
+}
+
+impl Channels {
+    /// Declares a channel after the builtin ones. Panics if `name` is taken, or if there are
+    /// 256 channels already.
+    pub fn add(&mut self, name: &str, config: ChannelConfig) -> ChannelId {
+        assert!(self.id(name).is_none(), "channel {} declared twice", name);
+        assert!(self.channels.len() <= u8::MAX as usize, "too many channels");
+        self.channels.push((name.to_string(), config));
+        ChannelId((self.channels.len() - 1) as u8)
+    }
+
+    pub fn id(&self, name: &str) -> Option<ChannelId> {
+        self.channels
+            .iter()
+            .position(|(channel, _)| channel == name)
+            .map(|index| ChannelId(index as u8))
+    }
+
+    pub fn config(&self, channel: ChannelId) -> Option<&ChannelConfig> {
+        self.channels
+            .get(channel.0 as usize)
+            .map(|(_, config)| config)
+    }
+}
+
+// what the scheduler sends, for `frame::encode` to put in a frame
+#[derive(Debug)]
+pub(crate) enum Message<'a> {
+    Reliable {
+        channel: ChannelId,
+        id: AckId,
+        ordered: bool,
+        packet: &'a [u8],
+    },
+    Unreliable {
+        channel: ChannelId,
+        packet: &'a [u8],
+    },
+    // a frame of its own, encoded already
+    Control(&'a [u8]),
+    Sequenced {
+        channel: ChannelId,
+        sequence: u32,
+        packet: &'a [u8],
+    },
+    // the latest few, oldest first, the first of them numbered `sequence`
+    Redundant {
+        channel: ChannelId,
+        sequence: u32,
+        packets: &'a [Vec<u8>],
+    },
+}
+
+// the packets waiting to go to one peer, and how much may be sent to it
+#[derive(Debug)]
+pub(crate) struct Scheduler {
+    // acks and the like, sent ahead of everything else whatever the allowance
+    control: Vec<Vec<u8>>,
+    queues: Vec<Queue>,
+    // indexes into `queues`, highest priority first
+    by_priority: Vec<usize>,
+    bandwidth: f64,
+    // bytes that may be sent now. it grows with time, and goes below zero when a packet bigger
+    // than what's left goes anyway
+    allowance: f64,
+    refilled_at: instant::Instant,
+}
+
+impl Scheduler {
+    pub fn new(channels: &Channels) -> Self {
+        let queues = channels
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(index, (_, config))| Queue::new(ChannelId(index as u8), config.clone()))
+            .collect::<Vec<_>>();
+        let mut by_priority = (0..queues.len()).collect::<Vec<_>>();
+        by_priority.sort_by_key(|index| std::cmp::Reverse(queues[*index].config.priority));
+        let bandwidth = channels.bandwidth as f64;
+        Self {
+            control: Vec::new(),
+            queues,
+            by_priority,
+            bandwidth,
+            allowance: bandwidth * BURST.as_secs_f64(),
+            refilled_at: instant::Instant::now(),
+        }
+    }
+
+    // queues `packet` on `channel`, unless the channel wasn't declared or the packet could
+    // never be sent, which would leave reliable channels resending it forever. unreliable
+    // channels drop their oldest packet when too many are waiting
+    pub fn push(&mut self, channel: ChannelId, packet: Vec<u8>) -> Result<(), QueueError> {
+        let queue = self
+            .queues
+            .get_mut(channel.0 as usize)
+            .ok_or(QueueError::UnknownChannel(channel))?;
+        let max = max_packet_size(queue.config.delivery);
+        if packet.len() > max {
+            return Err(QueueError::TooLarge {
+                size: packet.len(),
+                max,
+            });
+        }
+        queue.waiting.push_back(packet);
+        let reliable = matches!(
+            queue.config.delivery,
+            Delivery::Reliable | Delivery::ReliableOrdered
+        );
+        if !reliable && queue.waiting.len() > MAX_WAITING {
+            trace!(?channel, "dropping the oldest waiting packet");
+            queue.waiting.pop_front();
+        }
+        Ok(())
+    }
+
+    // queues an encoded protocol packet, to go out with the next flush
+    pub fn push_control(&mut self, packet: Vec<u8>) {
+        self.control.push(packet);
+    }
+
+    pub fn ack(&mut self, channel: ChannelId, id: &AckId, traffic: &mut Traffic) {
+        if let Some(queue) = self.queues.get_mut(channel.0 as usize) {
+            queue.buffer.ack(id, &mut traffic.rtt, &mut traffic.loss);
+        }
+    }
+
+    // sends control packets, then what the allowance lets through: each channel's share first,
+    // then whatever is left by priority. `send` returns how many bytes the message takes, or
+    // nothing if it couldn't go. counting the datagrams that carry them is up to the caller
+    pub fn flush(&mut self, traffic: &mut Traffic, send: impl FnMut(&Message) -> Option<usize>) {
+        self.flush_at(instant::Instant::now(), traffic, send);
+    }
+
+    fn flush_at(
+        &mut self,
+        now: instant::Instant,
+        traffic: &mut Traffic,
+        mut send: impl FnMut(&Message) -> Option<usize>,
+    ) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.refilled_at = now;
+        self.allowance =
+            (self.allowance + self.bandwidth * elapsed).min(self.bandwidth * BURST.as_secs_f64());
+        let available = self.allowance.max(0.0);
+
+        for packet in self.control.drain(..) {
+            if let Some(size) = send(&Message::Control(&packet)) {
+                self.allowance -= size as f64;
+            }
+        }
+        for index in &self.by_priority {
+            let queue = &mut self.queues[*index];
+            if queue.config.share > 0.0 {
+                let limit = available * queue.config.share;
+                queue.send(limit, &mut self.allowance, traffic, &mut send);
+            }
+        }
+        for index in &self.by_priority {
+            let queue = &mut self.queues[*index];
+            queue.send(f64::INFINITY, &mut self.allowance, traffic, &mut send);
+            queue.settle();
+        }
+    }
+}
+
+// the largest packet a channel for `delivery` can send, once framed and fragmented
+fn max_packet_size(delivery: Delivery) -> usize {
+    let max = MAX_MESSAGE_SIZE - HEADER_SIZE;
+    match delivery {
+        // each message repeats the packets before it, each behind a length that has to fit in a
+        // u16
+        Delivery::UnreliableRedundant => (max / REDUNDANCY - FRAME_HEADER).min(MAX_FRAMED_SIZE),
+        _ => max,
+    }
+}
+
+// one channel's packets to one peer
+#[derive(Debug)]
+struct Queue {
+    channel: ChannelId,
+    config: ChannelConfig,
+    waiting: VecDeque<Vec<u8>>,
+    // reliable channels move waiting packets here, to be resent until acked
+    buffer: ReliableBuffer<Vec<u8>>,
+    next_sequence: u32,
+    // the last few sent on redundant channels
+    recent: Vec<Vec<u8>>,
+}
+
+impl Queue {
+    fn new(channel: ChannelId, config: ChannelConfig) -> Self {
+        Self {
+            channel,
+            config,
+            waiting: VecDeque::new(),
+            buffer: ReliableBuffer::new(),
+            next_sequence: 0,
+            recent: Vec::new(),
+        }
+    }
+
+    // sends until `limit` bytes have gone out, or the allowance runs out
+    fn send(
+        &mut self,
+        limit: f64,
+        allowance: &mut f64,
+        traffic: &mut Traffic,
+        send: &mut impl FnMut(&Message) -> Option<usize>,
+    ) {
+        let channel = self.channel;
+        let start = *allowance;
+        let has_room = |allowance: f64| start - allowance < limit && allowance > 0.0;
+        match self.config.delivery {
+            Delivery::Reliable | Delivery::ReliableOrdered => {
+                let ordered = self.config.delivery == Delivery::ReliableOrdered;
+                for packet in self.waiting.drain(..) {
+                    self.buffer.add(packet);
+                }
+                self.buffer
+                    .process(&traffic.rtt, &mut traffic.loss, |packet, id| {
+                        if !has_room(*allowance) {
+                            return BufferResult::NotSent;
+                        }
+                        let message = Message::Reliable {
+                            channel,
+                            id,
+                            ordered,
+                            packet,
+                        };
+                        match send(&message) {
+                            Some(size) => {
+                                *allowance -= size as f64;
+                                BufferResult::Attempted
+                            }
+                            None => BufferResult::NotSent,
+                        }
+                    });
+            }
+            Delivery::Unreliable => {
+                while let Some(packet) = self.waiting.front() {
+                    if !has_room(*allowance) {
+                        break;
+                    }
+                    match send(&Message::Unreliable { channel, packet }) {
+                        Some(size) => {
+                            *allowance -= size as f64;
+                        }
+                        None => break,
+                    }
+                    self.waiting.pop_front();
+                }
+            }
+            Delivery::UnreliableSequenced => {
+                while let Some(packet) = self.waiting.front() {
+                    if !has_room(*allowance) {
+                        break;
+                    }
+                    let message = Message::Sequenced {
+                        channel,
+                        sequence: self.next_sequence,
+                        packet,
+                    };
+                    match send(&message) {
+                        Some(size) => {
+                            *allowance -= size as f64;
+                        }
+                        None => break,
+                    }
+                    self.next_sequence += 1;
+                    self.waiting.pop_front();
+                }
+            }
+            Delivery::UnreliableRedundant => {
+                while !self.waiting.is_empty() {
+                    if !has_room(*allowance) {
+                        break;
+                    }
+                    self.recent.push(self.waiting.pop_front().unwrap());
+                    let first = self.recent.len().saturating_sub(REDUNDANCY);
+                    let packets = &self.recent[first..];
+                    let message = Message::Redundant {
+                        channel,
+                        sequence: self.next_sequence + 1 - packets.len() as u32,
+                        packets,
+                    };
+                    match send(&message) {
+                        Some(size) => {
+                            *allowance -= size as f64;
+                        }
+                        None => {
+                            let packet = self.recent.pop().unwrap();
+                            self.waiting.push_front(packet);
+                            break;
+                        }
+                    }
+                    self.next_sequence += 1;
+                    self.recent.drain(..first);
+                }
+            }
+        }
+    }
+
+    // after a flush, sequenced packets that had to wait are dropped for the newest of them,
+    // since it would make the other side drop them anyway
+    fn settle(&mut self) {
+        if self.config.delivery == Delivery::UnreliableSequenced && self.waiting.len() > 1 {
+            trace!(channel = ?self.channel, dropped = self.waiting.len() - 1, "dropping stale packets");
+            let newest = self.waiting.pop_back().unwrap();
+            self.waiting.clear();
+            self.waiting.push_back(newest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // flushes at `now`, returning the first byte of each packet sent
+    fn flush(scheduler: &mut Scheduler, now: instant::Instant) -> Vec<u8> {
+        let mut sent = Vec::new();
+        scheduler.flush_at(now, &mut Traffic::default(), |message| {
+            let (packet, size) = match message {
+                Message::Reliable { packet, .. }
+                | Message::Unreliable { packet, .. }
+                | Message::Control(packet)
+                | Message::Sequenced { packet, .. } => (packet[0], packet.len()),
+                Message::Redundant { packets, .. } => (
+                    packets.last().unwrap()[0],
+                    packets.iter().map(Vec::len).sum(),
+                ),
+            };
+            sent.push(packet);
+            Some(size)
+        });
+        sent
+    }
+
+    fn channels(bandwidth: u32) -> Channels {
+        Channels {
+            bandwidth,
+            ..Channels::default()
+        }
+    }
+
+    #[test]
+    fn higher_priorities_go_first() {
+        let mut channels = channels(10_000);
+        let low = channels.add("low", ChannelConfig::new(Delivery::Unreliable));
+        let high = channels.add(
+            "high",
+            ChannelConfig {
+                priority: 1,
+                ..ChannelConfig::new(Delivery::Reliable)
+            },
+        );
+        let start = instant::Instant::now();
+        let mut scheduler = Scheduler::new(&channels);
+        // a burst is 1000 bytes
+        for _ in 0..3 {
+            scheduler.push(low, vec![0; 400]).unwrap();
+            scheduler.push(high, vec![1; 400]).unwrap();
+        }
+        assert_eq!(flush(&mut scheduler, start), vec![1, 1, 1]);
+        assert_eq!(
+            flush(&mut scheduler, start + Duration::from_millis(100)),
+            vec![0, 0]
+        );
+    }
+
+    #[test]
+    fn shares_are_kept_for_their_channels() {
+        let mut channels = channels(10_000);
+        let bulk = channels.add(
+            "bulk",
+            ChannelConfig {
+                share: 0.5,
+                ..ChannelConfig::new(Delivery::Unreliable)
+            },
+        );
+        let urgent = channels.add(
+            "urgent",
+            ChannelConfig {
+                priority: 1,
+                ..ChannelConfig::new(Delivery::Unreliable)
+            },
+        );
+        let mut scheduler = Scheduler::new(&channels);
+        for _ in 0..10 {
+            scheduler.push(urgent, vec![1; 100]).unwrap();
+            scheduler.push(bulk, vec![0; 100]).unwrap();
+        }
+        let sent = flush(&mut scheduler, instant::Instant::now());
+        assert_eq!(sent, [[0; 5], [1; 5]].concat());
+    }
+
+    #[test]
+    fn bandwidth_is_spread_over_time() {
+        let start = instant::Instant::now();
+        let mut scheduler = Scheduler::new(&channels(10_000));
+        for _ in 0..100 {
+            scheduler
+                .push(ChannelId::UNRELIABLE, vec![0; 1000])
+                .unwrap();
+        }
+        let mut sent = 0;
+        for tick in 0..=10 {
+            sent += flush(&mut scheduler, start + Duration::from_millis(100 * tick)).len();
+        }
+        // a second's worth, and the burst it started with
+        assert_eq!(sent, 11);
+        // packets bigger than the allowance still go, and the ones after wait longer
+        scheduler.push(ChannelId::RELIABLE, vec![1; 5000]).unwrap();
+        let later = start + Duration::from_millis(2000);
+        assert_eq!(flush(&mut scheduler, later), vec![1]);
+        assert!(flush(&mut scheduler, later + Duration::from_millis(400)).is_empty());
+        assert_eq!(
+            flush(&mut scheduler, later + Duration::from_millis(600)),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn sequenced_packets_left_waiting_are_dropped_for_newer_ones() {
+        let start = instant::Instant::now();
+        let mut scheduler = Scheduler::new(&channels(10_000));
+        for index in 0..5 {
+            scheduler
+                .push(ChannelId::UNRELIABLE_SEQUENCED, vec![index; 400])
+                .unwrap();
+        }
+        assert_eq!(flush(&mut scheduler, start), vec![0, 1, 2]);
+        assert_eq!(
+            flush(&mut scheduler, start + Duration::from_millis(100)),
+            vec![4]
+        );
+    }
+
+    #[test]
+    fn control_packets_go_first_whatever_the_allowance() {
+        let start = instant::Instant::now();
+        let mut scheduler = Scheduler::new(&channels(10_000));
+        scheduler
+            .push(ChannelId::UNRELIABLE, vec![1; 2000])
+            .unwrap();
+        scheduler.push(ChannelId::UNRELIABLE, vec![2; 10]).unwrap();
+        scheduler.push_control(vec![3; 10]);
+        assert_eq!(flush(&mut scheduler, start), vec![3, 1]);
+        // spent, but they still go
+        scheduler.push_control(vec![4; 10]);
+        assert_eq!(flush(&mut scheduler, start), vec![4]);
+    }
+
+    #[test]
+    fn redundant_packets_carry_the_ones_before() {
+        let mut scheduler = Scheduler::new(&Channels::default());
+        let mut sent = Vec::new();
+        for index in 0..5u8 {
+            scheduler
+                .push(ChannelId::UNRELIABLE_REDUNDANT, vec![index])
+                .unwrap();
+            scheduler.flush(&mut Traffic::default(), |message| {
+                if let Message::Redundant {
+                    sequence, packets, ..
+                } = message
+                {
+                    sent.push((*sequence, packets.concat()));
+                }
+                Some(1)
+            });
+        }
+        assert_eq!(
+            sent,
+            vec![
+                (0, vec![0]),
+                (0, vec![0, 1]),
+                (0, vec![0, 1, 2]),
+                (1, vec![1, 2, 3]),
+                (2, vec![2, 3, 4]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unreliable_channels_drop_the_oldest_waiting_packets() {
+        let start = instant::Instant::now();
+        let mut scheduler = Scheduler::new(&channels(10_000_000));
+        for index in 0..MAX_WAITING + 10 {
+            scheduler
+                .push(ChannelId::UNRELIABLE, vec![index as u8])
+                .unwrap();
+            scheduler
+                .push(ChannelId::RELIABLE, vec![index as u8])
+                .unwrap();
+        }
+        let sent = flush(&mut scheduler, start);
+        let unreliable = &sent[MAX_WAITING + 10..];
+        assert_eq!(unreliable.len(), MAX_WAITING);
+        assert_eq!(unreliable[0], 10);
+    }
+
+    #[test]
+    fn packets_too_large_to_send_are_refused() {
+        let start = instant::Instant::now();
+        let mut scheduler = Scheduler::new(&channels(10_000));
+        let max = MAX_MESSAGE_SIZE - HEADER_SIZE;
+        assert_eq!(
+            scheduler.push(ChannelId::RELIABLE, vec![1; max + 1]),
+            Err(QueueError::TooLarge { size: max + 1, max })
+        );
+        assert_eq!(
+            scheduler.push(ChannelId(200), vec![1]),
+            Err(QueueError::UnknownChannel(ChannelId(200)))
+        );
+        // nothing was left behind to be resent every timeout
+        for second in 0..10 {
+            assert!(flush(&mut scheduler, start + Duration::from_secs(second)).is_empty());
+        }
+        // redundant packets are framed behind their length
+        assert_eq!(
+            scheduler.push(
+                ChannelId::UNRELIABLE_REDUNDANT,
+                vec![1; MAX_FRAMED_SIZE + 1]
+            ),
+            Err(QueueError::TooLarge {
+                size: MAX_FRAMED_SIZE + 1,
+                max: MAX_FRAMED_SIZE
+            })
+        );
+        scheduler.push(ChannelId::RELIABLE, vec![2; max]).unwrap();
+        assert_eq!(
+            flush(&mut scheduler, start + Duration::from_secs(10)),
+            vec![2]
+        );
+    }
+}
@@ -6,15 +6,35 @@ use std::{
 use serde::{de::DeserializeOwned, Serialize};
 use tracing::{debug, warn};
 
-use crate::protocol::{
-    AckId, BufferResult, ClientProtocolPacket, ReliableBuffer, ServerProtocolPacket,
-    ServerProtocolPacketInner,
+use crate::{
+    batch::{self, Batcher},
+    channel::{ChannelId, Channels, QueueError, Scheduler},
+    fragment::{Fragmenter, Reassembler, MAX_DATAGRAM_SIZE},
+    frame::{self, Kind},
+    protocol::{
+        AckId, ClientProtocolPacket, Delivery, DisconnectReason, Incoming, ProtocolMarker,
+        ServerProtocolPacket, ServerProtocolPacketInner, IDLE_TIMEOUT,
+    },
+    stats::{ConnectionStats, Traffic},
 };
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    #[cfg(target_arch = "wasm32")]
+    #[error("js api error: {0:?}")]
+    Js(wasm_bindgen::JsValue),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("nothing is listening on the loopback network")]
+    NoLoopbackServer,
+    #[error("the client panicked on another thread")]
+    Poisoned,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -79,15 +99,24 @@ mod wasm {
 
         pub fn send(&mut self, data: &[u8]) -> bool {
             if let Some(websocket) = self.websocket.as_ref() {
-                websocket.send_with_u8_array(data).unwrap();
-                true
+                websocket.send_with_u8_array(data).is_ok()
             } else {
                 false
             }
         }
 
-        pub async fn connect(&mut self, addr: SocketAddr) {
-            let websocket = WebSocket::new(&format!("ws://{}/connect", addr)).unwrap();
+        // anything already sent still goes out first
+        pub fn close(&mut self) {
+            if let Some(websocket) = self.websocket.take() {
+                websocket.close().ok();
+            }
+            self.on_message = None;
+            self.on_close = None;
+            self.on_error = None;
+        }
+
+        pub async fn connect(&mut self, addr: SocketAddr) -> Result<()> {
+            let websocket = WebSocket::new(&format!("ws://{}/connect", addr)).map_err(Error::Js)?;
             websocket.set_binary_type(BinaryType::Arraybuffer);
             let (ready_tx, ready_rx) = oneshot::channel::<()>();
             let on_open = EventListener::once(&websocket, "open", {
@@ -119,7 +148,8 @@ mod wasm {
             self.on_open = Some(on_open);
             self.on_error = Some(on_error);
             self.on_close = Some(on_close);
-            ready_rx.await;
+            ready_rx.await.ok();
+            Ok(())
         }
     }
 
@@ -228,8 +258,12 @@ mod wasm {
         }
 
         pub fn send(&self, data: &[u8]) -> bool {
-            self.channel.send_with_u8_array(data).unwrap();
-            true
+            self.channel.send_with_u8_array(data).is_ok()
+        }
+
+        pub fn close(&mut self) {
+            self.channel.close();
+            self.peer.close();
         }
 
         pub async fn connect(&mut self, addr: SocketAddr) -> Result<()> {
@@ -294,49 +328,217 @@ use wasm::*;
 #[cfg(not(target_arch = "wasm32"))]
 mod native {
 
-    use std::net::SocketAddr;
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle};
+    use tokio_tungstenite::tungstenite::Message;
+    use tracing::{debug, warn};
 
     use super::Result;
+    use crate::protocol::UdpSession;
 
+    // plain UDP, since there is no WebRTC stack to use outside the browser. the server tells us
+    // where to send datagrams
     #[derive(Debug)]
-    pub(super) struct UnreliableTransport {}
+    pub(super) struct UnreliableTransport {
+        outgoing_tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+        reader: Option<JoinHandle<()>>,
+        http_client: reqwest::Client,
+        incoming_tx: crossbeam_channel::Sender<Vec<u8>>,
+        incoming_rx: crossbeam_channel::Receiver<Vec<u8>>,
+    }
 
     impl UnreliableTransport {
         pub fn new() -> Self {
-            unimplemented!()
+            let (incoming_tx, incoming_rx) = crossbeam_channel::unbounded();
+            Self {
+                outgoing_tx: None,
+                reader: None,
+                http_client: reqwest::Client::new(),
+                incoming_tx,
+                incoming_rx,
+            }
+        }
+
+        pub fn send(&self, data: &[u8]) -> bool {
+            match self.outgoing_tx.as_ref() {
+                Some(outgoing_tx) => outgoing_tx.send(data.to_vec()).is_ok(),
+                None => false,
+            }
+        }
+
+        // sends through `outgoing_tx` instead of a socket, for the loopback network
+        pub fn attach(&mut self, outgoing_tx: mpsc::UnboundedSender<Vec<u8>>) {
+            self.outgoing_tx = Some(outgoing_tx);
+        }
+
+        pub fn incoming_tx(&self) -> crossbeam_channel::Sender<Vec<u8>> {
+            self.incoming_tx.clone()
         }
-        pub fn send(&self, _data: &[u8]) -> bool {
-            unimplemented!()
+
+        // anything already sent still goes out first
+        pub fn close(&mut self) {
+            self.outgoing_tx = None;
+            if let Some(reader) = self.reader.take() {
+                reader.abort();
+            }
         }
+
         pub fn incoming(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
-            unimplemented!();
-            vec![].into_iter()
+            self.incoming_rx.try_iter()
         }
 
-        pub async fn connect(&mut self, _addr: SocketAddr) -> Result<()> {
-            unimplemented!()
+        pub async fn connect(&mut self, addr: SocketAddr) -> Result<()> {
+            let session = self
+                .http_client
+                .get(format!("http://{}/udp", addr))
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<UdpSession>()
+                .await?;
+            let local_addr: SocketAddr = if session.addr.is_ipv4() {
+                (Ipv4Addr::UNSPECIFIED, 0).into()
+            } else {
+                (Ipv6Addr::UNSPECIFIED, 0).into()
+            };
+            let socket = UdpSocket::bind(local_addr).await?;
+            socket.connect(session.addr).await?;
+            debug!(?session.addr, "udp connected");
+            let socket = Arc::new(socket);
+
+            let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+            tokio::spawn({
+                let socket = Arc::clone(&socket);
+                async move {
+                    while let Some(data) = outgoing_rx.recv().await {
+                        if let Err(e) = socket.send(&data).await {
+                            warn!("udp error: {}", e);
+                        }
+                    }
+                    debug!("udp send loop done");
+                }
+            });
+
+            let reader = tokio::spawn({
+                let socket = Arc::clone(&socket);
+                let incoming_tx = self.incoming_tx.clone();
+                async move {
+                    let mut buffer = vec![0; 65536];
+                    loop {
+                        match socket.recv(&mut buffer).await {
+                            Ok(size) => {
+                                if incoming_tx.send(buffer[..size].to_vec()).is_err() {
+                                    break;
+                                }
+                            }
+                            Err(e) => {
+                                warn!("udp error: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                    debug!("udp receive loop done");
+                }
+            });
+            self.outgoing_tx = Some(outgoing_tx);
+            self.reader = Some(reader);
+            Ok(())
         }
     }
 
     #[derive(Debug)]
-    pub(super) struct ReliableTransport {}
+    pub(super) struct ReliableTransport {
+        outgoing_tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+        reader: Option<JoinHandle<()>>,
+        incoming_tx: crossbeam_channel::Sender<Vec<u8>>,
+        incoming_rx: crossbeam_channel::Receiver<Vec<u8>>,
+    }
 
     impl ReliableTransport {
         pub(super) fn new() -> Self {
-            unimplemented!()
+            let (incoming_tx, incoming_rx) = crossbeam_channel::unbounded();
+            Self {
+                outgoing_tx: None,
+                reader: None,
+                incoming_tx,
+                incoming_rx,
+            }
         }
+
         pub fn incoming(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
-            unimplemented!();
-            vec![].into_iter()
+            self.incoming_rx.try_iter()
+        }
+
+        pub fn process(&mut self) {}
+
+        pub fn send(&mut self, data: &[u8]) -> bool {
+            match self.outgoing_tx.as_ref() {
+                Some(outgoing_tx) => outgoing_tx.send(data.to_vec()).is_ok(),
+                None => false,
+            }
         }
-        pub fn process(&mut self) {
-            unimplemented!()
+
+        // sends through `outgoing_tx` instead of a websocket, for the loopback network
+        pub fn attach(&mut self, outgoing_tx: mpsc::UnboundedSender<Vec<u8>>) {
+            self.outgoing_tx = Some(outgoing_tx);
+        }
+
+        pub fn incoming_tx(&self) -> crossbeam_channel::Sender<Vec<u8>> {
+            self.incoming_tx.clone()
         }
-        pub fn send(&mut self, _data: &[u8]) -> bool {
-            unimplemented!()
+
+        // anything already sent still goes out first, then the websocket closes
+        pub fn close(&mut self) {
+            self.outgoing_tx = None;
+            if let Some(reader) = self.reader.take() {
+                reader.abort();
+            }
         }
-        pub async fn connect(&mut self, _addr: SocketAddr) {
-            unimplemented!()
+
+        pub async fn connect(&mut self, addr: SocketAddr) -> Result<()> {
+            let (websocket, _) =
+                tokio_tungstenite::connect_async(format!("ws://{}/connect", addr)).await?;
+            debug!("websocket connected");
+            let (mut websocket_tx, mut websocket_rx) = websocket.split();
+
+            let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+            tokio::spawn(async move {
+                while let Some(data) = outgoing_rx.recv().await {
+                    if let Err(e) = websocket_tx.send(Message::binary(data)).await {
+                        warn!("websocket error: {}", e);
+                        break;
+                    }
+                }
+                websocket_tx.close().await.ok();
+                debug!("websocket send loop done");
+            });
+
+            let incoming_tx = self.incoming_tx.clone();
+            let reader = tokio::spawn(async move {
+                while let Some(message) = websocket_rx.next().await {
+                    match message {
+                        Ok(Message::Binary(data)) => {
+                            if incoming_tx.send(data).is_err() {
+                                break;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!("websocket error: {}", e);
+                            break;
+                        }
+                    }
+                }
+                debug!("websocket closed");
+            });
+            self.outgoing_tx = Some(outgoing_tx);
+            self.reader = Some(reader);
+            Ok(())
         }
     }
 }
@@ -344,6 +546,27 @@ mod native {
 #[cfg(not(target_arch = "wasm32"))]
 use native::*;
 
+#[cfg(not(target_arch = "wasm32"))]
+use crate::loopback::Loopback;
+
+/// How a packet reached the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    // the websocket, or acked datagrams
+    Reliable,
+    Unreliable,
+}
+
+/// A packet from the server, with when and how it arrived.
+#[derive(Debug, Clone)]
+pub struct Received<T> {
+    pub packet: T,
+    pub channel: Channel,
+    // calls to `Client::process` before it arrived
+    pub tick: u64,
+    pub arrived_at: instant::Instant,
+}
+
 type Inner<OutgoingPacket, IncomingPacket> =
     Arc<RwLock<ClientInner<OutgoingPacket, IncomingPacket>>>;
 
@@ -354,30 +577,81 @@ pub struct Client<OutgoingPacket, IncomingPacket> {
 
 impl<OutgoingPacket, IncomingPacket> Client<OutgoingPacket, IncomingPacket>
 where
-    OutgoingPacket: std::fmt::Debug + Clone + Serialize + Send + Sync + 'static,
+    OutgoingPacket: std::fmt::Debug + Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
     IncomingPacket: std::fmt::Debug + DeserializeOwned + Send + Sync + 'static,
 {
     pub fn new() -> Self {
+        Self::with_channels(Channels::default())
+    }
+
+    /// A client that sends on `channels` instead of just the builtin ones.
+    pub fn with_channels(channels: Channels) -> Self {
         Self {
-            inner: Arc::new(RwLock::new(ClientInner::new())),
+            inner: Arc::new(RwLock::new(ClientInner::new(
+                &channels,
+                ProtocolMarker::new::<IncomingPacket, OutgoingPacket>(),
+            ))),
         }
     }
 
     pub async fn connect(&self, addr: SocketAddr) -> Result<()> {
-        if let Ok(mut inner) = self.inner.write() {
-            inner.connect(addr).await
-        } else {
-            warn!("TODO");
-            panic!();
-        }
+        // not locked while the transports connect, so the client can be used meanwhile
+        let mut reliable = ReliableTransport::new();
+        reliable.connect(addr).await?;
+        let mut unreliable = UnreliableTransport::new();
+        unreliable.connect(addr).await?;
+        self.inner
+            .write()
+            .map_err(|_| Error::Poisoned)?
+            .attach_transports(reliable, unreliable);
+        Ok(())
     }
 
-    pub fn send_reliable(&self, packet: OutgoingPacket) {
-        if let Ok(mut inner) = self.inner.try_write() {
-            inner.send_reliable_user(packet);
-        } else {
-            warn!("TODO");
-            panic!();
+    /// Connects to a server on `network` rather than over sockets. Fails if no server is bound
+    /// to it.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn connect_loopback(&self, network: &Loopback) -> Result<()> {
+        // not locked while the server hears of the client, so it can be processed meanwhile
+        let (reliable_tx, unreliable_tx) = self
+            .inner
+            .read()
+            .map_err(|_| Error::Poisoned)?
+            .loopback_incoming();
+        let (reliable_up, unreliable_up) = network
+            .connect(reliable_tx, unreliable_tx)
+            .await
+            .ok_or(Error::NoLoopbackServer)?;
+        self.inner
+            .write()
+            .map_err(|_| Error::Poisoned)?
+            .attach_loopback(reliable_up, unreliable_up);
+        Ok(())
+    }
+
+    pub fn send_reliable(&self, packet: OutgoingPacket) -> std::result::Result<(), QueueError> {
+        self.send(Delivery::Reliable, packet)
+    }
+
+    /// Sends on the builtin channel for `delivery`.
+    pub fn send(
+        &self,
+        delivery: Delivery,
+        packet: OutgoingPacket,
+    ) -> std::result::Result<(), QueueError> {
+        self.send_on(ChannelId::builtin(delivery), packet)
+    }
+
+    /// Queues `packet` on `channel`, to go out with the next `process` as the bandwidth allows.
+    /// Fails if the channel wasn't declared, if the packet is too large to ever arrive, or if
+    /// the client is busy connecting.
+    pub fn send_on(
+        &self,
+        channel: ChannelId,
+        packet: OutgoingPacket,
+    ) -> std::result::Result<(), QueueError> {
+        match self.inner.try_write() {
+            Ok(mut inner) => inner.send_user(channel, packet),
+            Err(_) => Err(QueueError::Busy),
         }
     }
 
@@ -387,45 +661,67 @@ where
         }
     }
 
-    pub async fn recv(&self) -> impl Iterator<Item = IncomingPacket> + '_ {
-        let inner = self.inner.read().unwrap();
-        inner.recv().await.collect::<Vec<_>>().into_iter()
+    /// Tells the server we're leaving, then closes both transports.
+    pub fn disconnect(&self) {
+        if let Ok(mut inner) = self.inner.try_write() {
+            inner.disconnect();
+        }
     }
-}
 
-#[derive(Debug, Clone)]
-enum ProtocolOrUser<T> {
-    Protocol(ClientProtocolPacket),
-    User(T),
-}
+    /// How the connection is doing. Empty while connecting.
+    pub fn stats(&self) -> ConnectionStats {
+        match self.inner.try_read() {
+            Ok(inner) => inner.traffic.stats(),
+            Err(_) => ConnectionStats::default(),
+        }
+    }
 
-impl<T> ProtocolOrUser<T>
-where
-    T: Serialize,
-{
-    fn encode(&self) -> Vec<u8> {
-        match self {
-            ProtocolOrUser::Protocol(packet) => packet.encode(),
-            ProtocolOrUser::User(packet) => bincode::serialize(packet).unwrap(),
+    /// Why the connection ended, once it has.
+    pub fn disconnected(&self) -> Option<DisconnectReason> {
+        match self.inner.try_read() {
+            Ok(inner) => inner.disconnected,
+            Err(_) => None,
         }
     }
 
-    fn as_ack_request(&self, ack_id: AckId) -> ProtocolOrUser<T> {
-        ProtocolOrUser::Protocol(ClientProtocolPacket::AckRequest {
-            packet: self.encode(),
-            id: ack_id,
-        })
+    pub async fn recv(&self) -> impl Iterator<Item = IncomingPacket> + '_ {
+        self.poll().into_iter().map(|received| received.packet)
+    }
+
+    /// Packets received by `process` since the last call, without waiting. Returns nothing while
+    /// connecting.
+    pub fn poll(&self) -> Vec<Received<IncomingPacket>> {
+        match self.inner.try_read() {
+            Ok(inner) => inner.incoming_rx.try_iter().collect(),
+            Err(_) => Vec::new(),
+        }
     }
 }
 
 #[derive(Debug)]
 struct ClientInner<OutgoingPacket, IncomingPacket> {
-    reliable_buffer: ReliableBuffer<ProtocolOrUser<OutgoingPacket>>,
+    outgoing_type: std::marker::PhantomData<OutgoingPacket>,
+    // sent to the server in the handshake, for it to check against its own
+    marker: ProtocolMarker,
+    // encoded packets waiting to go, and reliable ones until they are acked
+    scheduler: Scheduler,
+    // what the server sent on the way, to drop copies and stale packets
+    incoming: Incoming<Vec<u8>>,
     reliable_transport: ReliableTransport,
     unreliable_transport: UnreliableTransport,
-    incoming_tx: crossbeam_channel::Sender<IncomingPacket>,
-    incoming_rx: crossbeam_channel::Receiver<IncomingPacket>,
+    incoming_tx: crossbeam_channel::Sender<Received<IncomingPacket>>,
+    incoming_rx: crossbeam_channel::Receiver<Received<IncomingPacket>>,
     can_use_unreliable: bool,
+    // the server's challenge, answered until it welcomes us, and when the answer last went
+    challenge: Option<String>,
+    connect_sent_at: Option<instant::Instant>,
+    tick: u64,
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+    // when anything last arrived from the server, once connecting starts
+    last_heard: Option<instant::Instant>,
+    disconnected: Option<DisconnectReason>,
+    traffic: Traffic,
 }
 
 impl<OutgoingPacket, IncomingPacket> ClientInner<OutgoingPacket, IncomingPacket>
@@ -433,37 +729,70 @@ where
     OutgoingPacket: std::fmt::Debug + Clone + Serialize + Send + Sync + 'static,
     IncomingPacket: std::fmt::Debug + DeserializeOwned + Send + Sync + 'static,
 {
-    pub fn new() -> Self {
+    pub fn new(channels: &Channels, marker: ProtocolMarker) -> Self {
         let (incoming_tx, incoming_rx) = crossbeam_channel::unbounded();
 
         Self {
+            marker,
             can_use_unreliable: false,
+            challenge: None,
+            connect_sent_at: None,
             reliable_transport: ReliableTransport::new(),
             unreliable_transport: UnreliableTransport::new(),
-            reliable_buffer: ReliableBuffer::new(),
+            outgoing_type: std::marker::PhantomData,
+            scheduler: Scheduler::new(channels),
+            incoming: Incoming::new(),
             incoming_rx,
             incoming_tx,
+            tick: 0,
+            fragmenter: Fragmenter::new(),
+            reassembler: Reassembler::new(),
+            last_heard: None,
+            disconnected: None,
+            traffic: Traffic::default(),
         }
     }
 
-    pub async fn connect(&mut self, addr: SocketAddr) -> Result<()> {
-        self.reliable_transport.connect(addr).await;
-        self.unreliable_transport.connect(addr).await.unwrap();
-        Ok(())
+    // sends and receives through `reliable` and `unreliable` from now on, already connected
+    fn attach_transports(&mut self, reliable: ReliableTransport, unreliable: UnreliableTransport) {
+        self.reliable_transport = reliable;
+        self.unreliable_transport = unreliable;
+        self.last_heard = Some(instant::Instant::now());
     }
 
-    fn process(&mut self) {
-        let transport = &mut self.unreliable_transport;
-        self.reliable_buffer.process(move |packet, ack_id| {
-            debug!("processing reliable buffer: {:?}", packet);
-            if transport.send(&packet.as_ack_request(ack_id).encode()) {
-                BufferResult::Attempted
-            } else {
-                BufferResult::NotSent
-            }
-        });
+    // where the loopback network delivers what the server sends
+    #[cfg(not(target_arch = "wasm32"))]
+    fn loopback_incoming(
+        &self,
+    ) -> (
+        crossbeam_channel::Sender<Vec<u8>>,
+        crossbeam_channel::Sender<Vec<u8>>,
+    ) {
+        (
+            self.reliable_transport.incoming_tx(),
+            self.unreliable_transport.incoming_tx(),
+        )
+    }
+
+    // sends through the loopback network's links to the server from now on
+    #[cfg(not(target_arch = "wasm32"))]
+    fn attach_loopback(
+        &mut self,
+        reliable_tx: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+        unreliable_tx: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
+    ) {
+        self.reliable_transport.attach(reliable_tx);
+        self.unreliable_transport.attach(unreliable_tx);
+        self.last_heard = Some(instant::Instant::now());
+    }
 
+    fn process(&mut self) {
+        if self.disconnected.is_some() {
+            return;
+        }
         self.reliable_transport.process();
+        self.reassembler.expire();
+        self.tick += 1;
 
         let unreliable_packets = self
             .unreliable_transport
@@ -471,7 +800,8 @@ where
             .into_iter()
             .collect::<Vec<_>>();
         for packet in unreliable_packets {
-            self.process_packet(packet);
+            self.traffic.received.record(packet.len());
+            self.process_packet(&packet, Channel::Unreliable);
         }
 
         let reliable_packets = self
@@ -480,57 +810,283 @@ where
             .into_iter()
             .collect::<Vec<_>>();
         for packet in reliable_packets {
-            self.process_packet(packet);
+            self.traffic.received.record(packet.len());
+            self.process_packet(&packet, Channel::Reliable);
+        }
+
+        // after what arrived, so acks for it go out with the rest
+        self.handshake();
+        self.flush();
+
+        let timed_out = self
+            .last_heard
+            .is_some_and(|heard| heard.elapsed() >= IDLE_TIMEOUT);
+        if timed_out && self.disconnected.is_none() {
+            debug!("server timed out");
+            self.close(DisconnectReason::TimedOut);
         }
     }
 
-    fn process_packet(&mut self, packet: Vec<u8>) {
-        use bincode::Options;
-        let bincoder = bincode::DefaultOptions::new()
-            .with_fixint_encoding()
-            .reject_trailing_bytes();
-        if let Ok(packet) = bincoder.deserialize::<IncomingPacket>(&packet) {
-            // debug!("got this: {:?}", packet);
-        } else if let Ok(packet) = bincoder.deserialize::<ServerProtocolPacket>(&packet) {
-            debug!("got server protocol packet: {:?}", packet);
-            let packet = packet.into();
-            match packet {
-                ServerProtocolPacketInner::ConnectChallenge { challenge } => self
-                    .send_unreliable_protocol_with_ack(ClientProtocolPacket::Connect { challenge }),
-                ServerProtocolPacketInner::AckRequest { packet, id } => {
-                    self.process_packet(packet);
-                    self.send_reliable_protocol(ClientProtocolPacket::Ack { id });
+    // sends what the scheduler lets through, packed into as few datagrams as fit
+    fn flush(&mut self) {
+        let mut batcher = Batcher::new();
+        self.scheduler.flush(&mut self.traffic, |message| {
+            let data = frame::encode(message);
+            let size = data.len();
+            batcher.push(data);
+            Some(size)
+        });
+        for datagram in batcher.finish() {
+            if send_unreliable(&self.unreliable_transport, &mut self.fragmenter, &datagram) {
+                self.traffic.sent.record(datagram.len());
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if self.disconnected.is_none() {
+            self.send_reliable_protocol(ClientProtocolPacket::Disconnect {
+                reason: DisconnectReason::Requested,
+            });
+            self.close(DisconnectReason::Requested);
+        }
+    }
+
+    fn close(&mut self, reason: DisconnectReason) {
+        debug!(?reason, "disconnected");
+        self.disconnected = Some(reason);
+        self.can_use_unreliable = false;
+        self.reliable_transport.close();
+        self.unreliable_transport.close();
+    }
+
+    // a datagram or websocket message from the server
+    fn process_packet(&mut self, packet: &[u8], channel: Channel) {
+        if self.disconnected.is_some() {
+            return;
+        }
+        self.last_heard = Some(instant::Instant::now());
+        let (header, body) = match frame::decode(packet) {
+            Some(decoded) => decoded,
+            None => {
+                warn!("dropping malformed packet");
+                return;
+            }
+        };
+        match header.kind {
+            Kind::Batch => match batch::unframe(body) {
+                Some(frames) => {
+                    for frame in frames {
+                        self.process_frame(&frame, channel);
+                    }
+                }
+                None => warn!("dropping malformed batch"),
+            },
+            Kind::Fragment => match frame::decode_fragment(body) {
+                Some(fragment) => {
+                    if let Some(frame) = self.reassembler.insert(fragment) {
+                        self.process_frame(&frame, channel);
+                    }
+                }
+                None => warn!("dropping malformed fragment"),
+            },
+            _ => self.process_frame(packet, channel),
+        }
+    }
+
+    // one frame of a datagram. batches and fragments can't be nested, so they are dropped here
+    fn process_frame(&mut self, frame: &[u8], channel: Channel) {
+        if self.disconnected.is_some() {
+            return;
+        }
+        let (header, body) = match frame::decode(frame) {
+            Some(decoded) => decoded,
+            None => {
+                warn!("dropping malformed frame");
+                return;
+            }
+        };
+        match header.kind {
+            Kind::Protocol => match ServerProtocolPacket::decode(body) {
+                Some(packet) => self.process_protocol_packet(packet.into()),
+                None => warn!("dropping malformed protocol packet"),
+            },
+            Kind::Unreliable => self.deliver(body, channel),
+            Kind::Reliable | Kind::ReliableOrdered => {
+                let id = AckId::new(header.sequence);
+                let ordered = header.kind == Kind::ReliableOrdered;
+                match self
+                    .incoming
+                    .reliable(header.channel, id, ordered, body.to_vec())
+                {
+                    Some(ready) => {
+                        self.scheduler.push_control(frame::ack(header.channel, id));
+                        for packet in ready {
+                            self.deliver(&packet, Channel::Reliable);
+                        }
+                    }
+                    None => debug!(?id, "dropping packet too far ahead"),
+                }
+            }
+            Kind::Sequenced => {
+                if self.incoming.sequenced(header.channel, header.sequence) {
+                    self.deliver(body, channel);
+                } else {
+                    debug!(sequence = header.sequence, "dropping stale packet");
                 }
-                ServerProtocolPacketInner::Ack { id } => {
-                    self.reliable_buffer.ack(&id);
+            }
+            Kind::Redundant => match batch::unframe(body) {
+                Some(packets) => {
+                    let fresh = self
+                        .incoming
+                        .redundant(header.channel, header.sequence, packets);
+                    for packet in fresh {
+                        self.deliver(&packet, channel);
+                    }
                 }
-                ServerProtocolPacketInner::Welcome {} => {
-                    debug!("welcomed. unreliable transport enabled.");
-                    self.can_use_unreliable = true;
+                None => warn!("dropping malformed redundant packet"),
+            },
+            Kind::Ack => {
+                let id = AckId::new(header.sequence);
+                self.scheduler.ack(header.channel, &id, &mut self.traffic);
+            }
+            Kind::Batch | Kind::Fragment => warn!(kind = ?header.kind, "dropping nested frame"),
+        }
+    }
+
+    fn process_protocol_packet(&mut self, packet: ServerProtocolPacketInner) {
+        debug!("got server protocol packet: {:?}", packet);
+        match packet {
+            ServerProtocolPacketInner::ConnectChallenge { marker, challenge } => {
+                match ProtocolMarker::mismatch(&marker, &self.marker) {
+                    Some(reason) => {
+                        warn!(?reason, "the server was built differently, so giving up");
+                        self.send_reliable_protocol(ClientProtocolPacket::Disconnect { reason });
+                        self.close(reason);
+                    }
+                    None => self.challenge = Some(challenge),
                 }
             }
+            ServerProtocolPacketInner::Welcome {} => {
+                debug!("welcomed. unreliable transport enabled.");
+                self.can_use_unreliable = true;
+                self.challenge = None;
+            }
+            ServerProtocolPacketInner::Ping { id } => {
+                self.scheduler
+                    .push_control(ClientProtocolPacket::Pong { id }.encode());
+            }
+            ServerProtocolPacketInner::Disconnect { reason } => self.close(reason),
         }
     }
 
-    fn send_user(&self, _packet: OutgoingPacket) {}
+    // hands an application packet over
+    fn deliver(&self, packet: &[u8], channel: Channel) {
+        use bincode::Options;
+        let bincoder = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes();
+        match bincoder.deserialize::<IncomingPacket>(packet) {
+            Ok(packet) => {
+                let received = Received {
+                    packet,
+                    channel,
+                    tick: self.tick,
+                    arrived_at: instant::Instant::now(),
+                };
+                // we hold the receiver too, so this can't fail
+                self.incoming_tx.send(received).unwrap();
+            }
+            Err(error) => warn!(%error, "dropping packet that doesn't decode"),
+        }
+    }
 
-    fn send_unreliable_protocol(&mut self, packet: ClientProtocolPacket) {
-        self.unreliable_transport.send(&packet.encode());
+    // the challenge goes back in a datagram, which may be lost, so it's sent again every
+    // retransmit timeout until the server welcomes us
+    fn handshake(&mut self) {
+        let challenge = match &self.challenge {
+            Some(challenge) => challenge.clone(),
+            None => return,
+        };
+        let due = self
+            .connect_sent_at
+            .is_none_or(|sent| sent.elapsed() >= self.traffic.rtt.retransmit_timeout());
+        if due {
+            let connect = ClientProtocolPacket::Connect {
+                marker: self.marker,
+                challenge,
+            };
+            self.scheduler.push_control(connect.encode());
+            self.connect_sent_at = Some(instant::Instant::now());
+        }
     }
 
-    fn send_unreliable_protocol_with_ack(&mut self, packet: ClientProtocolPacket) {
-        self.reliable_buffer.add(ProtocolOrUser::Protocol(packet));
+    fn send_user(
+        &mut self,
+        channel: ChannelId,
+        packet: OutgoingPacket,
+    ) -> std::result::Result<(), QueueError> {
+        let data = bincode::serialize(&packet).unwrap();
+        self.scheduler.push(channel, data)
     }
 
     fn send_reliable_protocol(&mut self, packet: ClientProtocolPacket) {
-        self.reliable_transport.send(&packet.encode());
+        let data = packet.encode();
+        if self.reliable_transport.send(&data) {
+            self.traffic.sent.record(data.len());
+        }
+    }
+}
+
+// sends `data` as one datagram, or as fragments if it doesn't fit in one
+fn send_unreliable(
+    transport: &UnreliableTransport,
+    fragmenter: &mut Fragmenter,
+    data: &[u8],
+) -> bool {
+    if data.len() <= MAX_DATAGRAM_SIZE {
+        return transport.send(data);
+    }
+    match fragmenter.split(data) {
+        Some(fragments) => fragments
+            .into_iter()
+            .all(|fragment| transport.send(&frame::encode_fragment(&fragment))),
+        None => {
+            warn!(size = data.len(), "dropping message too large to send");
+            false
+        }
     }
+}
 
-    fn send_reliable_user(&mut self, packet: OutgoingPacket) {
-        self.reliable_buffer.add(ProtocolOrUser::User(packet));
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::loopback::Rng;
+
+    #[test]
+    fn garbage_from_the_server_never_panics() {
+        let mut rng = Rng::new(25);
+        let marker = ProtocolMarker::new::<Vec<u16>, String>();
+        let mut client = ClientInner::<String, Vec<u16>>::new(&Channels::default(), marker);
+        for index in 0..10_000 {
+            let packet = frame::garbage(&mut rng);
+            let channel = match index % 2 {
+                0 => Channel::Unreliable,
+                _ => Channel::Reliable,
+            };
+            client.process_packet(&packet, channel);
+        }
+        client.flush();
+        assert!(client.disconnected.is_none());
     }
 
-    async fn recv(&self) -> impl Iterator<Item = IncomingPacket> + '_ {
-        self.incoming_rx.try_iter()
+    #[test]
+    fn sending_while_busy_fails_rather_than_panics() {
+        let client = Client::<String, String>::new();
+        let _connecting = client.inner.write().unwrap();
+        assert_eq!(
+            client.send_reliable("hello".to_string()),
+            Err(QueueError::Busy)
+        );
     }
 }
@@ -0,0 +1,286 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+
+// the largest datagram sent as is. anything bigger is split into fragments, which stay under it
+// along with their header
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 1200;
+const FRAGMENT_SIZE: usize = 1024;
+// 1 MiB. messages claiming more fragments are dropped rather than buffered
+const MAX_FRAGMENTS: u16 = 1024;
+// the largest message that can be sent at all
+pub(crate) const MAX_MESSAGE_SIZE: usize = FRAGMENT_SIZE * MAX_FRAGMENTS as usize;
+// how long to wait for the rest of a message before giving up on it
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+// messages a sender can have waiting for fragments at once. past it, the oldest is given up on
+const MAX_PARTIAL_MESSAGES: usize = 8;
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Hash, PartialEq, Eq)]
+pub(crate) struct MessageId(u32);
+
+// a piece of a message too large for a single datagram
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub(crate) struct Fragment {
+    message: MessageId,
+    index: u16,
+    count: u16,
+    bytes: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Fragmenter {
+    next_message: u32,
+}
+
+impl Fragmenter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn next_message(&mut self) -> MessageId {
+        let id = self.next_message;
+        self.next_message = self.next_message.wrapping_add(1);
+        MessageId(id)
+    }
+
+    // `bytes` in pieces of at most `FRAGMENT_SIZE`, or `None` if they are too large to send at
+    // all
+    pub fn split(&mut self, bytes: &[u8]) -> Option<Vec<Fragment>> {
+        let count = bytes.len().div_ceil(FRAGMENT_SIZE);
+        if count > MAX_FRAGMENTS as usize {
+            return None;
+        }
+        let message = self.next_message();
+        let fragments = bytes
+            .chunks(FRAGMENT_SIZE)
+            .enumerate()
+            .map(|(index, bytes)| Fragment {
+                message,
+                index: index as u16,
+                count: count as u16,
+                bytes: bytes.to_vec(),
+            })
+            .collect();
+        Some(fragments)
+    }
+}
+
+#[derive(Debug)]
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: u16,
+    started_at: instant::Instant,
+}
+
+// puts fragments from a single sender back together
+#[derive(Debug, Default)]
+pub(crate) struct Reassembler {
+    messages: HashMap<MessageId, Partial>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the whole message once `fragment` completes it. fragments that don't fit the rest of
+    // their message are dropped
+    pub fn insert(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+        self.insert_at(fragment, instant::Instant::now())
+    }
+
+    fn insert_at(&mut self, fragment: Fragment, now: instant::Instant) -> Option<Vec<u8>> {
+        let Fragment {
+            message,
+            index,
+            count,
+            bytes,
+        } = fragment;
+        if count > MAX_FRAGMENTS || index >= count || bytes.len() > FRAGMENT_SIZE {
+            debug!(?message, index, count, "dropping invalid fragment");
+            return None;
+        }
+        if !self.messages.contains_key(&message) && self.messages.len() >= MAX_PARTIAL_MESSAGES {
+            self.evict_oldest();
+        }
+        let partial = self.messages.entry(message).or_insert_with(|| Partial {
+            fragments: vec![None; count as usize],
+            received: 0,
+            started_at: now,
+        });
+        if partial.fragments.len() != count as usize {
+            debug!(?message, index, count, "dropping mismatched fragment");
+            return None;
+        }
+        let slot = &mut partial.fragments[index as usize];
+        if slot.is_none() {
+            *slot = Some(bytes);
+            partial.received += 1;
+        }
+        if partial.received < count {
+            return None;
+        }
+        trace!(?message, count, "reassembled message");
+        let partial = self.messages.remove(&message)?;
+        Some(partial.fragments.into_iter().flatten().flatten().collect())
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .messages
+            .iter()
+            .min_by_key(|(_, partial)| partial.started_at)
+            .map(|(message, _)| *message);
+        if let Some(message) = oldest {
+            debug!(
+                ?message,
+                "too many incomplete messages, dropping the oldest"
+            );
+            self.messages.remove(&message);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    // drops messages still missing fragments after `REASSEMBLY_TIMEOUT`
+    pub fn expire(&mut self) {
+        self.expire_at(instant::Instant::now());
+    }
+
+    fn expire_at(&mut self, now: instant::Instant) {
+        self.messages.retain(|message, partial| {
+            let alive = now - partial.started_at < REASSEMBLY_TIMEOUT;
+            if !alive {
+                debug!(
+                    ?message,
+                    received = partial.received,
+                    count = partial.fragments.len(),
+                    "dropping incomplete message"
+                );
+            }
+            alive
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(length: usize) -> Vec<u8> {
+        (0..length).map(|index| (index * 7) as u8).collect()
+    }
+
+    #[test]
+    fn fragments_round_trip_in_any_order() {
+        let mut fragmenter = Fragmenter::new();
+        let mut reassembler = Reassembler::new();
+        for length in [FRAGMENT_SIZE + 1, 3 * FRAGMENT_SIZE, 100_000] {
+            let bytes = message(length);
+            let mut fragments = fragmenter.split(&bytes).unwrap();
+            assert!(fragments
+                .iter()
+                .all(|fragment| bincode::serialize(fragment).unwrap().len() <= MAX_DATAGRAM_SIZE));
+            fragments.reverse();
+            // duplicates are ignored
+            fragments.insert(1, fragments[0].clone());
+            let last = fragments.pop().unwrap();
+            for fragment in fragments {
+                assert_eq!(reassembler.insert(fragment), None);
+            }
+            assert_eq!(reassembler.insert(last), Some(bytes));
+        }
+        assert!(reassembler.is_empty());
+    }
+
+    #[test]
+    fn interleaved_messages() {
+        let mut fragmenter = Fragmenter::new();
+        let mut reassembler = Reassembler::new();
+        let first = message(3000);
+        let second = message(5000);
+        let mut first_fragments = fragmenter.split(&first).unwrap().into_iter();
+        let mut second_fragments = fragmenter.split(&second).unwrap().into_iter();
+        let mut reassembled = Vec::new();
+        loop {
+            let next = vec![first_fragments.next(), second_fragments.next()];
+            if next.iter().all(Option::is_none) {
+                break;
+            }
+            reassembled.extend(
+                next.into_iter()
+                    .flatten()
+                    .filter_map(|fragment| reassembler.insert(fragment)),
+            );
+        }
+        assert_eq!(reassembled, vec![first, second]);
+    }
+
+    #[test]
+    fn incomplete_messages_expire() {
+        let mut fragmenter = Fragmenter::new();
+        let mut reassembler = Reassembler::new();
+        let start = instant::Instant::now();
+        let mut fragments = fragmenter.split(&message(3000)).unwrap();
+        let last = fragments.pop().unwrap();
+        for fragment in fragments {
+            reassembler.insert_at(fragment, start);
+        }
+        reassembler.expire_at(start + REASSEMBLY_TIMEOUT / 2);
+        assert_eq!(reassembler.messages.len(), 1);
+        reassembler.expire_at(start + REASSEMBLY_TIMEOUT);
+        assert!(reassembler.is_empty());
+        // the rest of it starts over, and never completes
+        assert_eq!(reassembler.insert(last), None);
+    }
+
+    #[test]
+    fn oldest_incomplete_messages_are_dropped() {
+        let mut fragmenter = Fragmenter::new();
+        let mut reassembler = Reassembler::new();
+        let start = instant::Instant::now();
+        let mut rests = Vec::new();
+        for index in 0..=MAX_PARTIAL_MESSAGES {
+            let mut fragments = fragmenter.split(&message(3000)).unwrap();
+            let now = start + Duration::from_millis(index as u64);
+            assert_eq!(reassembler.insert_at(fragments.remove(0), now), None);
+            rests.push(fragments);
+        }
+        assert_eq!(reassembler.messages.len(), MAX_PARTIAL_MESSAGES);
+        let mut rests = rests.into_iter();
+        let first = rests.next().unwrap();
+        let completed = rests
+            .flatten()
+            .filter_map(|fragment| reassembler.insert_at(fragment, start))
+            .count();
+        assert_eq!(completed, MAX_PARTIAL_MESSAGES);
+        // the first one was dropped, so the rest of it never completes
+        for fragment in first {
+            assert_eq!(reassembler.insert_at(fragment, start), None);
+        }
+    }
+
+    #[test]
+    fn invalid_fragments_are_dropped() {
+        let mut reassembler = Reassembler::new();
+        let fragment = |index, count| Fragment {
+            message: MessageId(0),
+            index,
+            count,
+            bytes: vec![1],
+        };
+        assert_eq!(reassembler.insert(fragment(2, 2)), None);
+        assert_eq!(reassembler.insert(fragment(0, MAX_FRAGMENTS + 1)), None);
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.insert(fragment(0, 2)), None);
+        // disagrees with the first fragment about the message length
+        assert_eq!(reassembler.insert(fragment(1, 3)), None);
+        assert_eq!(reassembler.insert(fragment(1, 2)), Some(vec![1, 1]));
+
+        let too_large = vec![0; FRAGMENT_SIZE * (MAX_FRAGMENTS as usize + 1)];
+        assert_eq!(Fragmenter::new().split(&too_large), None);
+    }
+}
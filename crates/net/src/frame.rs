@@ -0,0 +1,208 @@
+use std::convert::TryInto;
+
+use crate::{
+    batch,
+    channel::{ChannelId, Message},
+    fragment::Fragment,
+    protocol::AckId,
+};
+
+// every datagram and websocket message starts with a header: what kind of frame it is, then
+// the channel and sequence number it goes by, so either side knows what follows without guessing
+// from the bytes
+pub(crate) const HEADER_SIZE: usize = 6;
+
+// what a frame carries. the values go over the wire, so new kinds go at the end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    // a protocol packet. first, so the handshake stays readable whatever comes after it
+    Protocol,
+    // an application packet, sent once
+    Unreliable,
+    // an application packet resent until acked, the sequence number being its ack id
+    Reliable,
+    ReliableOrdered,
+    // an application packet dropped if a later one on its channel got there first
+    Sequenced,
+    // the latest few application packets on a channel, framed like a batch, the sequence number
+    // being the first one's
+    Redundant,
+    // acks the reliable packet numbered by the sequence number
+    Ack,
+    // several frames sent as one datagram
+    Batch,
+    // a piece of a frame too large for one datagram
+    Fragment,
+}
+
+const KINDS: [Kind; 9] = [
+    Kind::Protocol,
+    Kind::Unreliable,
+    Kind::Reliable,
+    Kind::ReliableOrdered,
+    Kind::Sequenced,
+    Kind::Redundant,
+    Kind::Ack,
+    Kind::Batch,
+    Kind::Fragment,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub kind: Kind,
+    // zero for frames that aren't on a channel
+    pub channel: ChannelId,
+    pub sequence: u32,
+}
+
+impl Header {
+    pub fn new(kind: Kind, channel: ChannelId, sequence: u32) -> Self {
+        Self {
+            kind,
+            channel,
+            sequence,
+        }
+    }
+
+    // for frames that aren't on a channel
+    pub fn bare(kind: Kind) -> Self {
+        Self::new(kind, ChannelId(0), 0)
+    }
+
+    // `body` behind this header
+    pub fn frame(&self, body: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_SIZE + body.len());
+        frame.push(self.kind as u8);
+        frame.push(self.channel.0);
+        frame.extend_from_slice(&self.sequence.to_le_bytes());
+        frame.extend_from_slice(body);
+        frame
+    }
+}
+
+// the header of `frame` and the body after it, or `None` if it's cut short or of a kind this
+// version doesn't know
+pub(crate) fn decode(frame: &[u8]) -> Option<(Header, &[u8])> {
+    if frame.len() < HEADER_SIZE {
+        return None;
+    }
+    let (header, body) = frame.split_at(HEADER_SIZE);
+    let header = Header {
+        kind: *KINDS.get(header[0] as usize)?,
+        channel: ChannelId(header[1]),
+        sequence: u32::from_le_bytes(header[2..].try_into().unwrap()),
+    };
+    Some((header, body))
+}
+
+// the frame a scheduled message goes as
+pub(crate) fn encode(message: &Message) -> Vec<u8> {
+    match *message {
+        Message::Control(frame) => frame.to_vec(),
+        Message::Unreliable { channel, packet } => {
+            Header::new(Kind::Unreliable, channel, 0).frame(packet)
+        }
+        Message::Reliable {
+            channel,
+            id,
+            ordered,
+            packet,
+        } => {
+            let kind = if ordered {
+                Kind::ReliableOrdered
+            } else {
+                Kind::Reliable
+            };
+            Header::new(kind, channel, id.get()).frame(packet)
+        }
+        Message::Sequenced {
+            channel,
+            sequence,
+            packet,
+        } => Header::new(Kind::Sequenced, channel, sequence).frame(packet),
+        Message::Redundant {
+            channel,
+            sequence,
+            packets,
+        } => Header::new(Kind::Redundant, channel, sequence).frame(&batch::frame(packets)),
+    }
+}
+
+pub(crate) fn ack(channel: ChannelId, id: AckId) -> Vec<u8> {
+    Header::new(Kind::Ack, channel, id.get()).frame(&[])
+}
+
+pub(crate) fn encode_fragment(fragment: &Fragment) -> Vec<u8> {
+    Header::bare(Kind::Fragment).frame(&bincode::serialize(fragment).unwrap())
+}
+
+pub(crate) fn decode_fragment(body: &[u8]) -> Option<Fragment> {
+    bincode::deserialize(body).ok()
+}
+
+// random bytes, often behind a header so they get past it, to throw at decoders
+#[cfg(test)]
+pub(crate) fn garbage(rng: &mut crate::loopback::Rng) -> Vec<u8> {
+    let channel = ChannelId(rng.next() as u8 % 8);
+    // mostly close together, for some to be handed over, and some about to wrap around
+    let sequence = match rng.next() % 3 {
+        0 => rng.next() as u32 % 16,
+        1 => u32::MAX - rng.next() as u32 % 4,
+        _ => rng.next() as u32,
+    };
+    match rng.next() % 4 {
+        0 => rng.bytes(64),
+        1 => {
+            let frames = (0..rng.next() % 4)
+                .map(|_| garbage(rng))
+                .collect::<Vec<_>>();
+            let kind = match rng.next() % 2 {
+                0 => Kind::Batch,
+                _ => Kind::Redundant,
+            };
+            Header::new(kind, channel, sequence).frame(&batch::frame(&frames))
+        }
+        _ => {
+            let kind = KINDS[rng.next() as usize % KINDS.len()];
+            Header::new(kind, channel, sequence).frame(&rng.bytes(64))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::Rng;
+
+    #[test]
+    fn headers_round_trip() {
+        let header = Header::new(Kind::ReliableOrdered, ChannelId(7), 0x0102_0304);
+        let frame = header.frame(&[9, 9]);
+        assert_eq!(frame, vec![3, 7, 4, 3, 2, 1, 9, 9]);
+        assert_eq!(decode(&frame), Some((header, &[9, 9][..])));
+        for (byte, kind) in KINDS.iter().enumerate() {
+            assert_eq!(*kind as usize, byte);
+        }
+    }
+
+    #[test]
+    fn short_and_unknown_frames_are_rejected() {
+        assert_eq!(decode(&[]), None);
+        assert_eq!(decode(&[1, 0, 0, 0, 0]), None);
+        assert_eq!(decode(&[KINDS.len() as u8, 0, 0, 0, 0, 0]), None);
+        assert!(decode(&[1, 0, 0, 0, 0, 0]).is_some());
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        let mut rng = Rng::new(25);
+        for _ in 0..10_000 {
+            let bytes = rng.bytes(64);
+            if let Some((header, body)) = decode(&bytes) {
+                assert_eq!(header.frame(body), bytes);
+                decode_fragment(body);
+                batch::unframe(body);
+            }
+        }
+    }
+}
@@ -1,6 +1,14 @@
+mod batch;
+pub mod channel;
 // this cfg is temporary
 // #[cfg(target_arch = "wasm32")]
 pub mod client;
+mod fragment;
+mod frame;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod loopback;
 pub mod protocol;
+pub mod schema;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod server;
+pub mod stats;
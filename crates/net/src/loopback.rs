@@ -0,0 +1,453 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, trace, warn};
+use uuid::Uuid;
+
+use crate::{
+    protocol::ClientId,
+    server::{Datagrams, ReliableEvent, UnreliableCommand},
+};
+
+// how much longer than usual a reordered datagram takes, at least, so it arrives after the ones
+// sent just after it even on links without latency
+const MIN_REORDER_DELAY: Duration = Duration::from_millis(5);
+
+/// How a simulated link treats what crosses it. The default delivers everything immediately,
+/// in order.
+#[derive(Debug, Clone, Default)]
+pub struct LinkConfig {
+    pub latency: Duration,
+    /// Up to this much more latency, picked for each packet.
+    pub jitter: Duration,
+    /// The chance, from 0 to 1, that a datagram is dropped.
+    pub loss: f64,
+    /// The chance that a datagram arrives twice.
+    pub duplication: f64,
+    /// The chance that a datagram is held back long enough for later ones to overtake it.
+    pub reordering: f64,
+    /// Decides the fate of every packet, so a link given the same packets drops, duplicates and
+    /// delays the same ones each run.
+    pub seed: u64,
+}
+
+/// An in-memory network for running a server and its clients in one process, without sockets.
+/// Datagrams cross links simulated as `LinkConfig` says. The reliable transport is delayed the
+/// same way, but like a websocket it never loses or reorders anything.
+#[derive(Debug, Clone)]
+pub struct Loopback {
+    network: Arc<Mutex<Network>>,
+}
+
+#[derive(Debug)]
+struct Network {
+    config: LinkConfig,
+    // links made so far, so each gets its own seed
+    links: u64,
+    next_client_id: u32,
+    server: Option<Endpoint>,
+}
+
+// where clients reach the server
+#[derive(Debug)]
+struct Endpoint {
+    events_tx: mpsc::Sender<ReliableEvent>,
+    reliable_tx: mpsc::UnboundedSender<(ClientId, Vec<u8>)>,
+    // datagrams as they arrive, fragments and all
+    datagram_tx: mpsc::UnboundedSender<(SocketAddr, Vec<u8>)>,
+    // links back to each client
+    reliable_links: HashMap<ClientId, mpsc::UnboundedSender<Vec<u8>>>,
+    unreliable_links: HashMap<SocketAddr, mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+impl Network {
+    // a link delivering what is sent into it with `deliver`, until that fails or every sender
+    // is gone
+    fn link(
+        &mut self,
+        ordered: bool,
+        deliver: impl FnMut(Vec<u8>) -> bool + Send + 'static,
+    ) -> (mpsc::UnboundedSender<Vec<u8>>, JoinHandle<()>) {
+        let seed = self.config.seed.wrapping_add(self.links);
+        self.links += 1;
+        let link = Link::new(self.config.clone(), ordered, seed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        (tx, tokio::spawn(link.run(rx, deliver)))
+    }
+}
+
+impl Loopback {
+    pub fn new(config: LinkConfig) -> Self {
+        Self {
+            network: Arc::new(Mutex::new(Network {
+                config,
+                links: 0,
+                next_client_id: 1,
+                server: None,
+            })),
+        }
+    }
+
+    // makes the server reachable. clients' packets queue up in these channels until it listens
+    pub(crate) fn bind(
+        &self,
+        events_tx: mpsc::Sender<ReliableEvent>,
+        reliable_tx: mpsc::UnboundedSender<(ClientId, Vec<u8>)>,
+        datagram_tx: mpsc::UnboundedSender<(SocketAddr, Vec<u8>)>,
+    ) {
+        self.network.lock().unwrap().server = Some(Endpoint {
+            events_tx,
+            reliable_tx,
+            datagram_tx,
+            reliable_links: HashMap::new(),
+            unreliable_links: HashMap::new(),
+        });
+    }
+
+    // connects a client, delivering what the server sends it into `reliable_tx` and
+    // `unreliable_tx`. returns where the client sends to the server over each transport
+    pub(crate) async fn connect(
+        &self,
+        reliable_tx: crossbeam_channel::Sender<Vec<u8>>,
+        unreliable_tx: crossbeam_channel::Sender<Vec<u8>>,
+    ) -> Option<(
+        mpsc::UnboundedSender<Vec<u8>>,
+        mpsc::UnboundedSender<Vec<u8>>,
+    )> {
+        let (id, addr, events_tx, reliable_up, unreliable_up, closed) = {
+            let mut network = self.network.lock().unwrap();
+            let (server_reliable_tx, datagram_tx, events_tx) = match network.server.as_ref() {
+                Some(server) => (
+                    server.reliable_tx.clone(),
+                    server.datagram_tx.clone(),
+                    server.events_tx.clone(),
+                ),
+                None => {
+                    warn!("nothing is listening on the loopback network");
+                    return None;
+                }
+            };
+            let id = ClientId::new(network.next_client_id);
+            network.next_client_id += 1;
+            // made up, but unique per client, since the server tells clients apart by address
+            let addr = SocketAddr::from((Ipv4Addr::from(0x0a00_0000 | id.get()), 0));
+
+            let (reliable_up, closed) = network.link(true, move |packet| {
+                server_reliable_tx.send((id, packet)).is_ok()
+            });
+            let (unreliable_up, _) = network.link(false, move |packet| {
+                datagram_tx.send((addr, packet)).is_ok()
+            });
+            let (reliable_down, _) =
+                network.link(true, move |packet| reliable_tx.send(packet).is_ok());
+            let (unreliable_down, _) =
+                network.link(false, move |packet| unreliable_tx.send(packet).is_ok());
+
+            let server = network.server.as_mut().unwrap();
+            server.reliable_links.insert(id, reliable_down);
+            server.unreliable_links.insert(addr, unreliable_down);
+            (id, addr, events_tx, reliable_up, unreliable_up, closed)
+        };
+        debug!(?id, ?addr, "loopback client connected");
+
+        let challenge = Uuid::new_v4().to_string();
+        events_tx
+            .send(ReliableEvent::NewClient { id, challenge })
+            .await
+            .ok()?;
+
+        // like a closed websocket, the client dropping its end disconnects it
+        let network = self.clone();
+        tokio::spawn(async move {
+            closed.await.ok();
+            debug!(?id, "loopback client disconnected");
+            if let Some(server) = network.network.lock().unwrap().server.as_mut() {
+                server.reliable_links.remove(&id);
+                server.unreliable_links.remove(&addr);
+            }
+            events_tx
+                .send(ReliableEvent::ClientDisconnected { id })
+                .await
+                .ok();
+        });
+        Some((reliable_up, unreliable_up))
+    }
+
+    // the server's side of the network: routes what it sends to clients' links, fragmenting it
+    // as the real transports do, and passes their datagrams on. stops with the server
+    pub(crate) async fn serve(
+        self,
+        mut datagram_rx: mpsc::UnboundedReceiver<(SocketAddr, Vec<u8>)>,
+        incoming_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+        mut reliable_rx: mpsc::Receiver<(ClientId, Vec<u8>)>,
+        mut unreliable_rx: mpsc::Receiver<UnreliableCommand>,
+    ) {
+        let mut datagrams = Datagrams::default();
+        loop {
+            tokio::select! {
+                Some((addr, data)) = datagram_rx.recv() => {
+                    if incoming_tx.send((addr, data)).await.is_err() {
+                        break;
+                    }
+                }
+                outgoing = reliable_rx.recv() => match outgoing {
+                    Some((id, data)) => self.send_reliable(id, data),
+                    None => break,
+                },
+                outgoing = unreliable_rx.recv() => match outgoing {
+                    Some(UnreliableCommand::Send(addr, data)) => {
+                        self.send_unreliable(addr, datagrams.split(data))
+                    }
+                    // every client has its own link, so there's nothing to route by
+                    Some(UnreliableCommand::Connected(_) | UnreliableCommand::Disconnected(_)) => {}
+                    None => break,
+                },
+            }
+        }
+        debug!("loopback server stopped");
+    }
+
+    fn send_reliable(&self, id: ClientId, data: Vec<u8>) {
+        let network = self.network.lock().unwrap();
+        let link = network
+            .server
+            .as_ref()
+            .and_then(|server| server.reliable_links.get(&id));
+        if let Some(link) = link {
+            link.send(data).ok();
+        }
+    }
+
+    fn send_unreliable(&self, addr: SocketAddr, datagrams: Vec<Vec<u8>>) {
+        let network = self.network.lock().unwrap();
+        let link = network
+            .server
+            .as_ref()
+            .and_then(|server| server.unreliable_links.get(&addr));
+        if let Some(link) = link {
+            for datagram in datagrams {
+                link.send(datagram).ok();
+            }
+        }
+    }
+}
+
+// one direction of a connection. ordered links only delay packets, like TCP would
+#[derive(Debug)]
+struct Link {
+    config: LinkConfig,
+    ordered: bool,
+    rng: Rng,
+    // packets on their way, by when they arrive and then by when they were sent
+    queue: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    sent: u64,
+    last_due: Option<Instant>,
+}
+
+impl Link {
+    fn new(config: LinkConfig, ordered: bool, seed: u64) -> Self {
+        Self {
+            config,
+            ordered,
+            rng: Rng::new(seed),
+            queue: BinaryHeap::new(),
+            sent: 0,
+            last_due: None,
+        }
+    }
+
+    fn send(&mut self, packet: Vec<u8>, now: Instant) {
+        if self.ordered {
+            let due = (now + self.delay()).max(self.last_due.unwrap_or(now));
+            self.last_due = Some(due);
+            self.push(due, packet);
+            return;
+        }
+        if self.rng.chance(self.config.loss) {
+            trace!("dropping datagram");
+            return;
+        }
+        let copies = if self.rng.chance(self.config.duplication) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut delay = self.delay();
+            if self.rng.chance(self.config.reordering) {
+                delay += (self.config.latency + self.config.jitter).max(MIN_REORDER_DELAY);
+            }
+            self.push(now + delay, packet.clone());
+        }
+    }
+
+    fn delay(&mut self) -> Duration {
+        self.config.latency + self.config.jitter.mul_f64(self.rng.unit())
+    }
+
+    fn push(&mut self, due: Instant, packet: Vec<u8>) {
+        self.queue.push(Reverse((due, self.sent, packet)));
+        self.sent += 1;
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((due, _, _))| *due)
+    }
+
+    // packets that have arrived by `now`, in the order they arrived
+    fn take_due(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+        while self.next_due().is_some_and(|next| next <= now) {
+            let Reverse((_, _, packet)) = self.queue.pop().unwrap();
+            due.push(packet);
+        }
+        due
+    }
+
+    async fn run(
+        mut self,
+        mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+        mut deliver: impl FnMut(Vec<u8>) -> bool,
+    ) {
+        let mut open = true;
+        while open || self.next_due().is_some() {
+            let next_due = self.next_due();
+            tokio::select! {
+                packet = rx.recv(), if open => match packet {
+                    Some(packet) => self.send(packet, Instant::now()),
+                    None => open = false,
+                },
+                _ = sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                    for packet in self.take_due(Instant::now()) {
+                        if !deliver(packet) {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// splitmix64. plenty for deciding what happens to packets, and the same every run for a seed
+#[derive(Debug)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.unit() < probability
+    }
+
+    // up to `max` random bytes, for throwing at decoders
+    #[cfg(test)]
+    pub(crate) fn bytes(&mut self, max: usize) -> Vec<u8> {
+        let length = self.next() as usize % (max + 1);
+        (0..length).map(|_| self.next() as u8).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(count: u16) -> impl Iterator<Item = Vec<u8>> {
+        (0..count).map(|index| index.to_le_bytes().to_vec())
+    }
+
+    fn numbers(packets: Vec<Vec<u8>>) -> Vec<u16> {
+        packets
+            .into_iter()
+            .map(|packet| u16::from_le_bytes([packet[0], packet[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn default_links_deliver_everything_at_once() {
+        let now = Instant::now();
+        let mut link = Link::new(LinkConfig::default(), false, 0);
+        for packet in numbered(100) {
+            link.send(packet, now);
+        }
+        assert_eq!(numbers(link.take_due(now)), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn ordered_links_only_delay() {
+        let config = LinkConfig {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(50),
+            loss: 0.5,
+            duplication: 0.5,
+            reordering: 0.5,
+            seed: 1,
+        };
+        let now = Instant::now();
+        let mut link = Link::new(config, true, 1);
+        for packet in numbered(100) {
+            link.send(packet, now);
+        }
+        assert!(link.take_due(now + Duration::from_millis(9)).is_empty());
+        assert_eq!(
+            numbers(link.take_due(now + Duration::from_secs(1))),
+            (0..100).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn datagrams_are_lost_duplicated_and_reordered() {
+        let config = LinkConfig {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(10),
+            loss: 0.2,
+            duplication: 0.1,
+            reordering: 0.1,
+            seed: 2,
+        };
+        let now = Instant::now();
+        let arrived = |seed| {
+            let mut link = Link::new(config.clone(), false, seed);
+            for packet in numbered(1000) {
+                link.send(packet, now);
+            }
+            numbers(link.take_due(now + Duration::from_secs(1)))
+        };
+
+        let first = arrived(2);
+        let mut unique = first.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert!((700..900).contains(&unique.len()));
+        assert!(first.len() > unique.len());
+        assert!(first.windows(2).any(|pair| pair[0] > pair[1]));
+        // the same seed decides the same way
+        assert_eq!(arrived(2), first);
+        assert_ne!(arrived(3), first);
+    }
+}
@@ -1,8 +1,19 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tracing::debug;
 
+use crate::{
+    channel::ChannelId,
+    frame::{Header, Kind},
+    schema,
+    stats::{LossEstimator, RttEstimator},
+};
+
 // #[derive(Debug, Clone, Deserialize, Serialize)]
 // pub(crate) struct AckMessage<T> {
 //     message: T,
@@ -22,15 +33,84 @@ use tracing::debug;
 //     }
 // }
 
-// used to avoid having the same bytes as a user packet
-#[derive(Debug, Clone, Deserialize, Serialize)]
+// bumped whenever the protocol packets change, so builds from either side of the change can tell
+pub(crate) const PROTOCOL_VERSION: u16 = 2;
+// the server pings connected clients this often, so quiet connections still show signs of life
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// connections nothing arrived on for this long are given up on
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// reliable packets further than this ahead of the first one still missing are dropped unacked,
+// and sent again once the gap has been filled
+const RECEIVE_WINDOW: u32 = 1024;
+
+/// How a packet is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Resent until acked, and handed over once, in whatever order it arrives.
+    Reliable,
+    /// Like `Reliable`, but handed over after every earlier packet on its channel.
+    ReliableOrdered,
+    /// Sent once, and may be lost.
+    Unreliable,
+    /// Like `Unreliable`, but dropped if a later sequenced packet got there first.
+    UnreliableSequenced,
+    /// Like `UnreliableSequenced`, but each datagram repeats the few packets before it, so a
+    /// lost one is made up for by the next.
+    UnreliableRedundant,
+}
+
+/// Why a connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DisconnectReason {
+    /// The other side said it was leaving.
+    Requested,
+    /// Nothing arrived for too long.
+    TimedOut,
+    /// The websocket closed without a word.
+    Closed,
+    /// The two sides speak different versions of the protocol.
+    ProtocolMismatch { server: u16, client: u16 },
+    /// The two sides were built with different packets, so they can't decode each other's.
+    SchemaMismatch { server: u64, client: u64 },
+}
+
+/// What a side speaks, compared during the handshake: the protocol version, and a hash of the
+/// application's packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub struct ProtocolMarker {
     version: u16,
+    schema: u64,
 }
 
 impl ProtocolMarker {
-    pub(crate) fn new() -> Self {
-        Self { version: 0 }
+    // for a side of an application whose server sends `ServerPacket` and whose clients send
+    // `ClientPacket`
+    pub(crate) fn new<ServerPacket, ClientPacket>() -> Self
+    where
+        ServerPacket: DeserializeOwned,
+        ClientPacket: DeserializeOwned,
+    {
+        Self {
+            version: PROTOCOL_VERSION,
+            schema: schema::hash::<(ServerPacket, ClientPacket)>(),
+        }
+    }
+
+    // why a client marked `client` can't talk to a server marked `server`, if it can't
+    pub(crate) fn mismatch(server: &Self, client: &Self) -> Option<DisconnectReason> {
+        if server.version != client.version {
+            Some(DisconnectReason::ProtocolMismatch {
+                server: server.version,
+                client: client.version,
+            })
+        } else if server.schema != client.schema {
+            Some(DisconnectReason::SchemaMismatch {
+                server: server.schema,
+                client: client.schema,
+            })
+        } else {
+            None
+        }
     }
 }
 
@@ -39,16 +119,17 @@ impl ProtocolMarker {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ServerProtocolPacket {
     inner: ServerProtocolPacketInner,
-    marker: ProtocolMarker,
 }
 
 impl ServerProtocolPacket {
-    pub fn decode(bytes: &[u8]) -> Option<Self> {
-        bincode::deserialize(bytes).ok()
+    // from the body of a protocol frame
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        bincode::deserialize(body).ok()
     }
 
+    // as a protocol frame, header included
     pub fn encode(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+        Header::bare(Kind::Protocol).frame(&bincode::serialize(self).unwrap())
     }
 }
 
@@ -60,10 +141,18 @@ impl From<ServerProtocolPacket> for ServerProtocolPacketInner {
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub(crate) enum ServerProtocolPacketInner {
-    AckRequest { packet: Vec<u8>, id: AckId },
-    Ack { id: AckId },
-    ConnectChallenge { challenge: String },
+    // first, with the marker first, so that later versions can keep it readable by older ones
+    ConnectChallenge {
+        marker: ProtocolMarker,
+        challenge: String,
+    },
     Welcome {},
+    Ping {
+        id: u32,
+    },
+    Disconnect {
+        reason: DisconnectReason,
+    },
 }
 
 impl ServerProtocolPacketInner {
@@ -74,44 +163,63 @@ impl ServerProtocolPacketInner {
 
 impl From<ServerProtocolPacketInner> for ServerProtocolPacket {
     fn from(inner: ServerProtocolPacketInner) -> Self {
-        Self {
-            inner,
-            marker: ProtocolMarker::new(),
-        }
+        Self { inner }
     }
 }
 
 // client -> server
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub(crate) enum ClientProtocolPacket {
-    AckRequest { packet: Vec<u8>, id: AckId },
-    Ack { id: AckId },
-    Connect { challenge: String },
+    // sent over and over until the server welcomes the client, since datagrams may be lost
+    Connect {
+        marker: ProtocolMarker,
+        challenge: String,
+    },
+    Pong {
+        id: u32,
+    },
+    Disconnect {
+        reason: DisconnectReason,
+    },
 }
 
 impl ClientProtocolPacket {
-    pub fn decode(bytes: &[u8]) -> Option<Self> {
-        bincode::deserialize(bytes).ok()
+    // from the body of a protocol frame
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        bincode::deserialize(body).ok()
     }
 
+    // as a protocol frame, header included
     pub fn encode(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+        Header::bare(Kind::Protocol).frame(&bincode::serialize(self).unwrap())
     }
 }
 
+// where native clients, which can't use WebRTC, send their datagrams instead
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct UdpSession {
+    pub addr: SocketAddr,
+}
+
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, Hash, PartialEq, Eq)]
 pub(crate) struct AckId(u32);
 
 impl AckId {
-    fn new(id: u32) -> Self {
+    pub(crate) fn new(id: u32) -> Self {
         Self(id)
     }
+
+    pub(crate) fn get(&self) -> u32 {
+        self.0
+    }
 }
 
 #[derive(Debug)]
 struct Sent<T> {
     value: T,
     sent_at: instant::Instant,
+    // how many times it has been sent so far
+    attempts: u32,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -123,15 +231,13 @@ pub(crate) enum BufferResult {
 
 #[derive(Debug)]
 pub(crate) struct ReliableBuffer<T> {
-    pending: Vec<(AckId, T)>,
+    // with how many times each was sent before
+    pending: Vec<(AckId, u32, T)>,
     sent: HashMap<AckId, Sent<T>>,
     next_ack_id: u32,
 }
 
-impl<T> ReliableBuffer<T>
-where
-    T: Clone,
-{
+impl<T> ReliableBuffer<T> {
     pub fn new() -> Self {
         Self {
             pending: Vec::new(),
@@ -146,47 +252,171 @@ where
         AckId::new(id)
     }
 
-    pub fn ack(&mut self, id: &AckId) {
-        self.sent.remove(id);
+    pub fn ack(&mut self, id: &AckId, rtt: &mut RttEstimator, loss: &mut LossEstimator) {
+        if let Some(sent) = self.sent.remove(id) {
+            // an ack for a resent packet could be for any of the copies, so only packets sent
+            // once are timed
+            if sent.attempts == 1 {
+                rtt.sample(sent.sent_at.elapsed());
+            }
+            loss.record(false);
+        }
+        // late for a copy that's waiting to go again
+        self.pending.retain(|(pending, _, _)| pending != id);
         debug!("{:?} was acked", id);
     }
 
-    pub fn process(&mut self, mut f: impl FnMut(&T, AckId) -> BufferResult) {
-        let mut not_sent = Vec::new();
+    // offers `f` the packets due to be resent, then the new ones, keeping those it doesn't send
+    pub fn process(
+        &mut self,
+        rtt: &RttEstimator,
+        loss: &mut LossEstimator,
+        mut f: impl FnMut(&T, AckId) -> BufferResult,
+    ) {
         let now = instant::Instant::now();
-        let max_delta = std::time::Duration::from_millis(300);
-
-        for (ack_id, sent) in &self.sent {
-            if now - sent.sent_at >= max_delta {
-                debug!("sending {:?} again", ack_id);
-                self.pending.push((*ack_id, sent.value.clone()));
-            }
+        let timeout = rtt.retransmit_timeout();
+
+        let mut due = self
+            .sent
+            .iter()
+            .filter(|(_, sent)| now - sent.sent_at >= timeout)
+            .map(|(ack_id, _)| *ack_id)
+            .collect::<Vec<_>>();
+        due.sort_unstable_by_key(|AckId(id)| *id);
+        let mut pending = Vec::with_capacity(due.len() + self.pending.len());
+        for ack_id in due {
+            debug!("sending {:?} again", ack_id);
+            loss.record(true);
+            let sent = self.sent.remove(&ack_id).unwrap();
+            pending.push((ack_id, sent.attempts, sent.value));
         }
+        pending.append(&mut self.pending);
 
-        let pending = self.pending.drain(..).collect::<Vec<_>>();
-        for (ack_id, value) in pending {
+        for (ack_id, attempts, value) in pending {
             debug!("sending {:?}", ack_id);
-            let sent = f(&value, ack_id);
-            match sent {
+            match f(&value, ack_id) {
                 BufferResult::NotSent => {
-                    not_sent.push((ack_id, value));
+                    self.pending.push((ack_id, attempts, value));
                 }
                 BufferResult::Attempted => {
                     // we'll need to verify with the server that this was sent
-                    let sent_at = instant::Instant::now();
-                    self.sent.insert(ack_id, Sent { value, sent_at });
+                    self.sent.insert(
+                        ack_id,
+                        Sent {
+                            value,
+                            sent_at: instant::Instant::now(),
+                            attempts: attempts + 1,
+                        },
+                    );
                 }
                 BufferResult::Sent => {
                     // no need to verify (e.g. TCP was used)
                 }
             }
         }
-        self.pending = not_sent;
     }
 
     pub fn add(&mut self, packet: T) {
         let ack_id = self.next_ack_id();
-        self.pending.push((ack_id, packet));
+        self.pending.push((ack_id, 0, packet));
+    }
+}
+
+// what has arrived from the other side on each channel, to hand reliable packets over once and
+// in order where asked, and to drop unreliable ones older than one already handed over
+#[derive(Debug)]
+pub(crate) struct Incoming<T> {
+    channels: HashMap<ChannelId, IncomingChannel<T>>,
+}
+
+#[derive(Debug)]
+struct IncomingChannel<T> {
+    // every reliable packet before this one has arrived
+    next_id: u32,
+    // reliable packets that arrived ahead of `next_id`
+    ahead: HashSet<u32>,
+    // ordered packets waiting on earlier ones
+    held: BTreeMap<u32, T>,
+    latest_sequence: Option<u32>,
+}
+
+impl<T> Incoming<T> {
+    pub fn new() -> Self {
+        Self {
+            channels: HashMap::new(),
+        }
+    }
+
+    fn channel(&mut self, channel: ChannelId) -> &mut IncomingChannel<T> {
+        self.channels
+            .entry(channel)
+            .or_insert_with(|| IncomingChannel {
+                next_id: 0,
+                ahead: HashSet::new(),
+                held: BTreeMap::new(),
+                latest_sequence: None,
+            })
+    }
+
+    // the packets that can be handed over now that `packet` is in, or `None` if it's too far
+    // ahead to keep and mustn't be acked. copies are acked again, since the first ack may have
+    // been lost, but hand nothing over
+    pub fn reliable(
+        &mut self,
+        channel: ChannelId,
+        id: AckId,
+        ordered: bool,
+        packet: T,
+    ) -> Option<Vec<T>> {
+        if !self.mark(channel, id)? {
+            return Some(Vec::new());
+        }
+        if !ordered {
+            return Some(vec![packet]);
+        }
+        let channel = self.channel(channel);
+        channel.held.insert(id.0, packet);
+        let later = channel.held.split_off(&channel.next_id);
+        let ready = std::mem::replace(&mut channel.held, later);
+        Some(ready.into_values().collect())
+    }
+
+    // notes that `id` arrived, and whether it's the first time. `None` if it's past the window
+    pub fn mark(&mut self, channel: ChannelId, id: AckId) -> Option<bool> {
+        let channel = self.channel(channel);
+        let AckId(id) = id;
+        if id >= channel.next_id.saturating_add(RECEIVE_WINDOW) {
+            return None;
+        }
+        if id < channel.next_id || !channel.ahead.insert(id) {
+            return Some(false);
+        }
+        while channel.ahead.remove(&channel.next_id) {
+            channel.next_id += 1;
+        }
+        Some(true)
+    }
+
+    // whether a sequenced packet is newer than every one before it
+    pub fn sequenced(&mut self, channel: ChannelId, sequence: u32) -> bool {
+        let channel = self.channel(channel);
+        if channel
+            .latest_sequence
+            .is_some_and(|latest| sequence <= latest)
+        {
+            return false;
+        }
+        channel.latest_sequence = Some(sequence);
+        true
+    }
+
+    // the packets in a redundant datagram that are newer than every one before them
+    pub fn redundant(&mut self, channel: ChannelId, sequence: u32, packets: Vec<T>) -> Vec<T> {
+        (0..)
+            .zip(packets)
+            .filter(|(index, _)| self.sequenced(channel, sequence.wrapping_add(*index)))
+            .map(|(_, packet)| packet)
+            .collect()
     }
 }
 
@@ -197,4 +427,157 @@ impl ClientId {
     pub(crate) fn new(id: u32) -> Self {
         Self(id)
     }
+
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL: ChannelId = ChannelId::RELIABLE;
+
+    #[test]
+    fn reliable_packets_are_handed_over_once() {
+        let mut incoming = Incoming::new();
+        assert_eq!(
+            incoming.reliable(CHANNEL, AckId(1), false, 'b'),
+            Some(vec!['b'])
+        );
+        assert_eq!(
+            incoming.reliable(CHANNEL, AckId(0), false, 'a'),
+            Some(vec!['a'])
+        );
+        assert_eq!(
+            incoming.reliable(CHANNEL, AckId(1), false, 'b'),
+            Some(vec![])
+        );
+        assert_eq!(
+            incoming.reliable(CHANNEL, AckId(0), false, 'a'),
+            Some(vec![])
+        );
+        let channel = incoming.channel(CHANNEL);
+        assert_eq!(channel.next_id, 2);
+        assert!(channel.ahead.is_empty());
+    }
+
+    #[test]
+    fn ordered_packets_wait_for_earlier_ones() {
+        let mut incoming = Incoming::new();
+        assert_eq!(
+            incoming.reliable(CHANNEL, AckId(2), true, 'c'),
+            Some(vec![])
+        );
+        assert_eq!(
+            incoming.reliable(CHANNEL, AckId(1), true, 'b'),
+            Some(vec![])
+        );
+        assert_eq!(
+            incoming.reliable(CHANNEL, AckId(1), true, 'b'),
+            Some(vec![])
+        );
+        // other channels don't hold anything up
+        let other = ChannelId::RELIABLE_ORDERED;
+        assert_eq!(
+            incoming.reliable(other, AckId(0), true, 'x'),
+            Some(vec!['x'])
+        );
+        assert_eq!(
+            incoming.reliable(CHANNEL, AckId(0), true, 'a'),
+            Some(vec!['a', 'b', 'c'])
+        );
+        assert!(incoming.channel(CHANNEL).held.is_empty());
+    }
+
+    #[test]
+    fn packets_past_the_window_are_dropped() {
+        let mut incoming = Incoming::new();
+        let far = AckId(RECEIVE_WINDOW);
+        assert_eq!(incoming.reliable(CHANNEL, far, false, 'z'), None);
+        assert_eq!(
+            incoming.reliable(CHANNEL, AckId(0), false, 'a'),
+            Some(vec!['a'])
+        );
+        assert_eq!(incoming.reliable(CHANNEL, far, false, 'z'), Some(vec!['z']));
+    }
+
+    #[test]
+    fn stale_sequenced_packets_are_dropped() {
+        let mut incoming = Incoming::<()>::new();
+        assert!(incoming.sequenced(CHANNEL, 3));
+        assert!(!incoming.sequenced(CHANNEL, 3));
+        assert!(!incoming.sequenced(CHANNEL, 1));
+        assert!(incoming.sequenced(CHANNEL, 7));
+    }
+
+    #[test]
+    fn redundant_packets_are_handed_over_once() {
+        let mut incoming = Incoming::new();
+        assert_eq!(incoming.redundant(CHANNEL, 0, vec!['a']), vec!['a']);
+        // 'b' and 'c' were lost
+        assert_eq!(
+            incoming.redundant(CHANNEL, 1, vec!['b', 'c', 'd']),
+            vec!['b', 'c', 'd']
+        );
+        assert_eq!(
+            incoming.redundant(CHANNEL, 2, vec!['c', 'd', 'e']),
+            vec!['e']
+        );
+    }
+
+    #[test]
+    fn markers_must_match() {
+        let server = ProtocolMarker::new::<u8, String>();
+        let client = ProtocolMarker::new::<u8, String>();
+        assert_eq!(ProtocolMarker::mismatch(&server, &client), None);
+
+        let client = ProtocolMarker::new::<u8, Vec<u8>>();
+        assert!(matches!(
+            ProtocolMarker::mismatch(&server, &client),
+            Some(DisconnectReason::SchemaMismatch { .. })
+        ));
+
+        let client = ProtocolMarker {
+            version: PROTOCOL_VERSION + 1,
+            ..server
+        };
+        assert_eq!(
+            ProtocolMarker::mismatch(&server, &client),
+            Some(DisconnectReason::ProtocolMismatch {
+                server: PROTOCOL_VERSION,
+                client: PROTOCOL_VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn resends_go_before_new_packets() {
+        let (rtt, mut loss) = (RttEstimator::default(), LossEstimator::default());
+        let mut buffer = ReliableBuffer::new();
+        buffer.add('a');
+        buffer.add('b');
+        buffer.process(&rtt, &mut loss, |_, _| BufferResult::Attempted);
+        for sent in buffer.sent.values_mut() {
+            sent.sent_at -= rtt.retransmit_timeout();
+        }
+        buffer.add('c');
+        let mut sent = Vec::new();
+        buffer.process(&rtt, &mut loss, |value, _| {
+            sent.push(*value);
+            BufferResult::NotSent
+        });
+        assert_eq!(sent, vec!['a', 'b', 'c']);
+        assert!(loss.loss() > 0.0);
+
+        // acks for packets waiting to go again mean they don't have to
+        buffer.ack(&AckId(0), &mut RttEstimator::default(), &mut loss);
+        let mut sent = Vec::new();
+        buffer.process(&rtt, &mut loss, |value, _| {
+            sent.push(*value);
+            BufferResult::Attempted
+        });
+        assert_eq!(sent, vec!['b', 'c']);
+    }
 }
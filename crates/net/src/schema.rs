@@ -0,0 +1,611 @@
+use std::{collections::BTreeMap, fmt};
+
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+
+// types nested deeper than this are taken to contain themselves, which can't be traced
+const MAX_DEPTH: usize = 64;
+// how many times `T` is traced while looking for enum variants not yet visited
+const MAX_PASSES: usize = 10_000;
+
+/// A hash of the shape of `T`: the names, fields and variants of the types it's made of, all
+/// the way down. Builds whose packets hash the same decode each other's bytes the same way.
+///
+/// The shape is traced through `T`'s `Deserialize` impl, so this panics for types that can't
+/// be traced that way: those that go through `deserialize_any`, like untagged enums do, and
+/// those that contain themselves.
+pub fn hash<T: DeserializeOwned>() -> u64 {
+    fnv1a(describe::<T>().as_bytes())
+}
+
+// every type `T` is made of, one a line, sorted so the order they were found in doesn't matter
+fn describe<T: DeserializeOwned>() -> String {
+    let mut tracer = Tracer::default();
+    let mut root = String::new();
+    for _ in 0..MAX_PASSES {
+        root.clear();
+        let tracing = Tracing {
+            tracer: &mut tracer,
+            out: &mut root,
+            depth: 0,
+        };
+        if let Err(error) = T::deserialize(tracing) {
+            panic!("can't trace {}: {}", std::any::type_name::<T>(), error);
+        }
+        if tracer.enums.values().all(Variants::all_visited) {
+            let mut description = root.clone();
+            for (name, shape) in &tracer.types {
+                description.push_str(&format!("\n{} = {}", name, shape));
+            }
+            return description;
+        }
+    }
+    panic!(
+        "can't trace {}: too many enum variants",
+        std::any::type_name::<T>()
+    );
+}
+
+// 64 bit FNV-1a, which unlike the std hashers is the same for every build
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[derive(Debug, Default)]
+struct Tracer {
+    // the shape of each named type, and of each enum variant as `Enum::Variant`
+    types: BTreeMap<String, String>,
+    enums: BTreeMap<&'static str, Variants>,
+}
+
+#[derive(Debug)]
+struct Variants {
+    visited: Vec<bool>,
+    // once all have been visited, they take turns, to get to the enums inside them
+    next: usize,
+}
+
+impl Variants {
+    fn all_visited(&self) -> bool {
+        self.visited.iter().all(|visited| *visited)
+    }
+
+    // the first variant not visited yet, or the next in turn
+    fn pick(&mut self) -> usize {
+        let index = match self.visited.iter().position(|visited| !visited) {
+            Some(index) => index,
+            None => {
+                self.next = (self.next + 1) % self.visited.len();
+                self.next
+            }
+        };
+        self.visited[index] = true;
+        index
+    }
+}
+
+#[derive(Debug)]
+struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Self(message.to_string())
+    }
+}
+
+// a deserializer that makes up a value of whatever type asks, writing the type's shape to `out`
+struct Tracing<'a> {
+    tracer: &'a mut Tracer,
+    out: &'a mut String,
+    depth: usize,
+}
+
+impl<'a> Tracing<'a> {
+    fn primitive(self, name: &str) {
+        self.out.push_str(name);
+    }
+
+    // the depth of what's inside this
+    fn deeper(&self) -> Result<usize, Error> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Error("a type contains itself".to_string()));
+        }
+        Ok(self.depth + 1)
+    }
+
+    // a tracer for something inside this, writing to `out`
+    fn nested<'b>(&'b mut self, out: &'b mut String) -> Result<Tracing<'b>, Error> {
+        Ok(Tracing {
+            depth: self.deeper()?,
+            tracer: self.tracer,
+            out,
+        })
+    }
+
+    // hands the visitor `count` made up elements, returning their shapes
+    fn elements<'de, V: Visitor<'de>>(
+        &mut self,
+        count: usize,
+        visitor: V,
+    ) -> Result<(V::Value, Vec<String>), Error> {
+        let mut elements = Elements {
+            depth: self.deeper()?,
+            tracer: self.tracer,
+            remaining: count,
+            shapes: Vec::new(),
+        };
+        let value = visitor.visit_seq(&mut elements)?;
+        Ok((value, elements.shapes))
+    }
+
+    fn record(&mut self, name: String, shape: String) {
+        self.tracer.types.insert(name, shape);
+    }
+}
+
+struct Elements<'a> {
+    tracer: &'a mut Tracer,
+    depth: usize,
+    remaining: usize,
+    shapes: Vec<String>,
+}
+
+impl<'de, 'a> SeqAccess<'de> for Elements<'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let mut shape = String::new();
+        let value = seed.deserialize(Tracing {
+            tracer: self.tracer,
+            out: &mut shape,
+            depth: self.depth,
+        })?;
+        self.shapes.push(shape);
+        Ok(Some(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+// a map with a single made up entry
+struct Entry<'a> {
+    tracer: &'a mut Tracer,
+    depth: usize,
+    key: Option<String>,
+    value: Option<String>,
+}
+
+impl<'de, 'a> MapAccess<'de> for Entry<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        if self.key.is_some() {
+            return Ok(None);
+        }
+        let mut shape = String::new();
+        let key = seed.deserialize(Tracing {
+            tracer: self.tracer,
+            out: &mut shape,
+            depth: self.depth,
+        })?;
+        self.key = Some(shape);
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let mut shape = String::new();
+        let value = seed.deserialize(Tracing {
+            tracer: self.tracer,
+            out: &mut shape,
+            depth: self.depth,
+        })?;
+        self.value = Some(shape);
+        Ok(value)
+    }
+}
+
+// the variant of an enum picked for this pass
+struct Variant<'a> {
+    tracing: Tracing<'a>,
+    name: String,
+    index: u32,
+}
+
+impl<'de, 'a> EnumAccess<'de> for Variant<'a> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let index: de::value::U32Deserializer<Error> = self.index.into_deserializer();
+        Ok((seed.deserialize(index)?, self))
+    }
+}
+
+impl<'de, 'a> VariantAccess<'de> for Variant<'a> {
+    type Error = Error;
+
+    fn unit_variant(mut self) -> Result<(), Error> {
+        self.tracing.record(self.name, String::new());
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(mut self, seed: T) -> Result<T::Value, Error> {
+        let mut shape = String::new();
+        let value = seed.deserialize(self.tracing.nested(&mut shape)?)?;
+        self.tracing.record(self.name, format!("({})", shape));
+        Ok(value)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(mut self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        let (value, shapes) = self.tracing.elements(len, visitor)?;
+        self.tracing
+            .record(self.name, format!("({})", shapes.join(", ")));
+        Ok(value)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        mut self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let (value, shapes) = self.tracing.elements(fields.len(), visitor)?;
+        self.tracing
+            .record(self.name, fields_shape(fields, &shapes));
+        Ok(value)
+    }
+}
+
+fn fields_shape(fields: &[&str], shapes: &[String]) -> String {
+    let fields = fields
+        .iter()
+        .zip(shapes)
+        .map(|(field, shape)| format!("{}: {}", field, shape))
+        .collect::<Vec<_>>();
+    format!("{{ {} }}", fields.join(", "))
+}
+
+macro_rules! primitives {
+    ($($method:ident => $visit:ident($value:expr)),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                self.primitive(stringify!($method).trim_start_matches("deserialize_"));
+                visitor.$visit($value)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Tracing<'a> {
+    type Error = Error;
+
+    primitives! {
+        deserialize_bool => visit_bool(false),
+        deserialize_i8 => visit_i8(0),
+        deserialize_i16 => visit_i16(0),
+        deserialize_i32 => visit_i32(0),
+        deserialize_i64 => visit_i64(0),
+        deserialize_u8 => visit_u8(0),
+        deserialize_u16 => visit_u16(0),
+        deserialize_u32 => visit_u32(0),
+        deserialize_u64 => visit_u64(0),
+        deserialize_f32 => visit_f32(0.0),
+        deserialize_f64 => visit_f64(0.0),
+        deserialize_char => visit_char('\0'),
+        deserialize_str => visit_str(""),
+        deserialize_string => visit_string(String::new()),
+        deserialize_bytes => visit_bytes(&[]),
+        deserialize_byte_buf => visit_byte_buf(Vec::new()),
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.primitive("()");
+        visitor.visit_unit()
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error(
+            "it doesn't say what it is, so its shape is unknown".to_string(),
+        ))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Error> {
+        let mut shape = String::new();
+        let value = visitor.visit_some(self.nested(&mut shape)?)?;
+        self.out.push_str(&format!("option<{}>", shape));
+        Ok(value)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        mut self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.out.push_str(name);
+        self.record(name.to_string(), String::new());
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        mut self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let mut shape = String::new();
+        let value = visitor.visit_newtype_struct(self.nested(&mut shape)?)?;
+        self.out.push_str(name);
+        self.record(name.to_string(), format!("({})", shape));
+        Ok(value)
+    }
+
+    // sequences are described by a single element
+    fn deserialize_seq<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Error> {
+        let (value, shapes) = self.elements(1, visitor)?;
+        self.out.push_str(&format!("[{}]", shapes.join("")));
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        mut self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let (value, shapes) = self.elements(len, visitor)?;
+        self.out.push_str(&format!("({})", shapes.join(", ")));
+        Ok(value)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        mut self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.out.push_str(name);
+        let (value, shapes) = self.elements(len, visitor)?;
+        self.record(name.to_string(), format!("({})", shapes.join(", ")));
+        Ok(value)
+    }
+
+    // and maps by a single entry
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let mut entry = Entry {
+            depth: self.deeper()?,
+            tracer: self.tracer,
+            key: None,
+            value: None,
+        };
+        let value = visitor.visit_map(&mut entry)?;
+        self.out.push_str(&format!(
+            "{{{}: {}}}",
+            entry.key.unwrap_or_default(),
+            entry.value.unwrap_or_default()
+        ));
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        mut self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.out.push_str(name);
+        let (value, shapes) = self.elements(fields.len(), visitor)?;
+        self.record(name.to_string(), fields_shape(fields, &shapes));
+        Ok(value)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        mut self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        if variants.is_empty() {
+            return Err(Error(format!("{} has no variants", name)));
+        }
+        self.out.push_str(name);
+        self.record(name.to_string(), format!("[{}]", variants.join(", ")));
+        let index = self
+            .tracer
+            .enums
+            .entry(name)
+            .or_insert_with(|| Variants {
+                visited: vec![false; variants.len()],
+                next: 0,
+            })
+            .pick();
+        // the variant's shape is recorded on its own
+        visitor.visit_enum(Variant {
+            tracing: Tracing {
+                depth: self.deeper()?,
+                tracer: self.tracer,
+                out: self.out,
+            },
+            name: format!("{}::{}", name, variants[index]),
+            index: index as u32,
+        })
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error(
+            "identifiers only come up in self describing formats".to_string(),
+        ))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+// the types here are only traced, never read
+#[cfg(test)]
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    mod before {
+        use super::*;
+
+        #[derive(Deserialize)]
+        pub enum Packet {
+            Hello { name: String, ids: Vec<u32> },
+            Move(Position, Option<Direction>),
+            Bye,
+        }
+
+        #[derive(Deserialize)]
+        pub struct Position {
+            pub x: f32,
+            pub y: f32,
+        }
+
+        #[derive(Deserialize)]
+        pub enum Direction {
+            Up,
+            Down,
+        }
+    }
+
+    // the same, built again
+    mod same {
+        use super::*;
+
+        #[derive(Deserialize)]
+        pub enum Packet {
+            Hello { name: String, ids: Vec<u32> },
+            Move(Position, Option<Direction>),
+            Bye,
+        }
+
+        #[derive(Deserialize)]
+        pub struct Position {
+            pub x: f32,
+            pub y: f32,
+        }
+
+        #[derive(Deserialize)]
+        pub enum Direction {
+            Up,
+            Down,
+        }
+    }
+
+    // a variant added to an enum only reached through another
+    mod nested_change {
+        use super::*;
+
+        #[derive(Deserialize)]
+        pub enum Packet {
+            Hello { name: String, ids: Vec<u32> },
+            Move(Position, Option<Direction>),
+            Bye,
+        }
+
+        #[derive(Deserialize)]
+        pub struct Position {
+            pub x: f32,
+            pub y: f32,
+        }
+
+        #[derive(Deserialize)]
+        pub enum Direction {
+            Up,
+            Down,
+            Left,
+        }
+    }
+
+    // a field that changed type
+    mod field_change {
+        use super::*;
+
+        #[derive(Deserialize)]
+        pub enum Packet {
+            Hello { name: String, ids: Vec<u32> },
+            Move(Position, Option<Direction>),
+            Bye,
+        }
+
+        #[derive(Deserialize)]
+        pub struct Position {
+            pub x: f64,
+            pub y: f64,
+        }
+
+        #[derive(Deserialize)]
+        pub enum Direction {
+            Up,
+            Down,
+        }
+    }
+
+    #[test]
+    fn the_same_types_hash_the_same() {
+        assert_eq!(hash::<before::Packet>(), hash::<same::Packet>());
+        assert_eq!(hash::<(before::Packet, u8)>(), hash::<(same::Packet, u8)>());
+    }
+
+    #[test]
+    fn changes_anywhere_change_the_hash() {
+        let before = hash::<before::Packet>();
+        assert_ne!(before, hash::<nested_change::Packet>());
+        assert_ne!(before, hash::<field_change::Packet>());
+        assert_ne!(
+            hash::<(before::Packet, u8)>(),
+            hash::<(u8, before::Packet)>()
+        );
+    }
+
+    #[test]
+    fn every_variant_is_described() {
+        let description = describe::<before::Packet>();
+        assert!(description.contains("Packet::Hello = { name: string, ids: [u32] }"));
+        assert!(description.contains("Packet::Move = (Position, option<Direction>)"));
+        assert!(description.contains("Position = { x: f32, y: f32 }"));
+        assert!(description.contains("Direction = [Up, Down]"));
+        assert!(description.contains("Direction::Down = "));
+        let map = describe::<HashMap<String, (u8, bool)>>();
+        assert_eq!(map, "{string: (u8, bool)}");
+    }
+
+    #[test]
+    #[should_panic(expected = "contains itself")]
+    fn types_that_contain_themselves_are_refused() {
+        #[derive(Deserialize)]
+        struct Tree {
+            _children: Vec<Tree>,
+        }
+        hash::<Tree>();
+    }
+}
@@ -1,20 +1,44 @@
-use std::{collections::HashMap, marker::PhantomData, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+    net::SocketAddr,
+    sync::Arc,
+};
 
 use futures::{FutureExt, SinkExt, StreamExt};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, Mutex, RwLock},
+};
 use tracing::{debug, trace, warn};
 use uuid::Uuid;
 use warp::{
     ws::{Message, WebSocket},
     Filter,
 };
-use webrtc_unreliable::{Server as RtcServer, SessionEndpoint};
+use webrtc_unreliable::{SendError, Server as RtcServer, SessionEndpoint};
 
-use crate::protocol::{
-    ClientId, ClientProtocolPacket, ReliableBuffer, ServerProtocolPacket, ServerProtocolPacketInner,
+use crate::{
+    batch::{self, Batcher},
+    channel::{ChannelId, Channels, Scheduler},
+    fragment::{Fragmenter, Reassembler, MAX_DATAGRAM_SIZE},
+    frame::{self, Kind},
+    loopback::Loopback,
+    protocol::{
+        AckId, ClientId, ClientProtocolPacket, DisconnectReason, Incoming, ProtocolMarker,
+        ServerProtocolPacket, ServerProtocolPacketInner, UdpSession, HEARTBEAT_INTERVAL,
+        IDLE_TIMEOUT,
+    },
+    stats::{ConnectionStats, ServerStats, Traffic},
 };
 
+pub use crate::protocol::Delivery;
+
+// how often the server sends what's waiting, when the application isn't sending anything that
+// would make it
+const FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(20);
+
 struct ReliableTransport {
     inner: Inner,
     outgoing_tx: mpsc::Sender<(ClientId, Vec<u8>)>,
@@ -24,18 +48,33 @@ struct ReliableTransport {
 type Inner = Arc<RwLock<ReliableTransportInner>>;
 
 #[derive(Debug)]
-enum ReliableEvent {
+pub(crate) enum ReliableEvent {
     NewClient { id: ClientId, challenge: String },
     ClientDisconnected { id: ClientId },
 }
 
+// what the processor has the unreliable transport do, in order
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum UnreliableCommand {
+    Send(SocketAddr, Vec<u8>),
+    // a client finished the handshake from this address, so datagrams to it go back the way it
+    // came in
+    Connected(SocketAddr),
+    Disconnected(SocketAddr),
+}
+
 impl ReliableTransport {
-    pub fn new(listen_addr: SocketAddr, events_tx: mpsc::Sender<ReliableEvent>) -> Self {
+    pub fn new(
+        listen_addr: SocketAddr,
+        udp_public_addr: Option<SocketAddr>,
+        events_tx: mpsc::Sender<ReliableEvent>,
+    ) -> Self {
         let (outgoing_tx, outgoing_rx) = mpsc::channel(32);
 
         Self {
             inner: Arc::new(RwLock::new(ReliableTransportInner::new(
                 listen_addr,
+                udp_public_addr,
                 events_tx,
             ))),
             outgoing_rx: Some(outgoing_rx),
@@ -48,8 +87,9 @@ impl ReliableTransport {
         inner.set_session_endpoint(endpoint);
     }
 
-    async fn incoming(&self) -> crossbeam_channel::Receiver<(ClientId, Vec<u8>)> {
-        self.inner.read().await.incoming_rx.clone()
+    // can only be taken once
+    async fn incoming(&self) -> mpsc::UnboundedReceiver<(ClientId, Vec<u8>)> {
+        self.inner.write().await.incoming_rx.take().unwrap()
     }
 
     async fn outgoing(&self) -> mpsc::Sender<(ClientId, Vec<u8>)> {
@@ -101,8 +141,20 @@ impl ReliableTransport {
         let rtc = warp::post()
             .and(warp::path("rtc"))
             .and(warp::body::stream())
-            .and(inner)
+            .and(inner.clone())
             .and_then(rtc_callback);
+
+        // native clients ask where to send datagrams instead of negotiating WebRTC
+        let udp =
+            warp::get()
+                .and(warp::path("udp"))
+                .and(inner)
+                .and_then(|inner: Inner| async move {
+                    match inner.read().await.udp_public_addr {
+                        Some(addr) => Ok(warp::reply::json(&UdpSession { addr })),
+                        None => Err(warp::reject::not_found()),
+                    }
+                });
         // .and_then(move |body, inner: Inner| async move {
         //     let inner = inner.write().await;
 
@@ -114,7 +166,7 @@ impl ReliableTransport {
         //     }
         // });
 
-        let routes = connect.or(rtc);
+        let routes = connect.or(rtc).or(udp);
 
         let mut outgoing = self.outgoing_rx.take().unwrap();
         let inner = self.inner.clone();
@@ -153,21 +205,24 @@ async fn client_connected(ws: WebSocket, inner: Inner) {
     let client_id = inner.write().await.register_client(tx.clone());
     debug!("client connected: {:?}", client_id);
     let challenge = format!("{}", Uuid::new_v4());
-    inner
-        .read()
-        .await
-        .events_tx
-        .send(ReliableEvent::NewClient {
-            id: client_id,
-            challenge: challenge.clone(),
-        })
-        .await
-        .unwrap();
+    let events_tx = inner.read().await.events_tx.clone();
+    let event = ReliableEvent::NewClient {
+        id: client_id,
+        challenge: challenge.clone(),
+    };
+    if events_tx.send(event).await.is_err() {
+        warn!("server stopped, dropping websocket");
+        inner.write().await.unregister(&client_id);
+        return;
+    }
 
     let sender = tokio::task::spawn(async move {
         while let Some(message) = rx.recv().await {
             debug!(?client_id, "sending");
-            user_ws_tx.send(Message::binary(message)).await.unwrap();
+            if let Err(e) = user_ws_tx.send(Message::binary(message)).await {
+                warn!("websocket error: {}", e);
+                break;
+            }
         }
         debug!("ws send loop done");
     });
@@ -180,12 +235,15 @@ async fn client_connected(ws: WebSocket, inner: Inner) {
                 break;
             }
         };
-        inner
+        if inner
             .read()
             .await
             .incoming_tx
             .send((client_id, packet))
-            .unwrap();
+            .is_err()
+        {
+            break;
+        }
     }
 
     debug!("client disconnected");
@@ -196,34 +254,37 @@ async fn client_connected(ws: WebSocket, inner: Inner) {
 
     inner.write().await.unregister(&client_id);
 
-    inner
-        .read()
-        .await
-        .events_tx
+    events_tx
         .send(ReliableEvent::ClientDisconnected { id: client_id })
         .await
-        .unwrap();
+        .ok();
 }
 
 struct ReliableTransportInner {
     listen_addr: SocketAddr,
+    udp_public_addr: Option<SocketAddr>,
     next_client_id: u32,
     session_endpoint: Option<SessionEndpoint>,
     connections: HashMap<ClientId, mpsc::UnboundedSender<Vec<u8>>>,
-    incoming_tx: crossbeam_channel::Sender<(ClientId, Vec<u8>)>,
-    incoming_rx: crossbeam_channel::Receiver<(ClientId, Vec<u8>)>,
+    incoming_tx: mpsc::UnboundedSender<(ClientId, Vec<u8>)>,
+    incoming_rx: Option<mpsc::UnboundedReceiver<(ClientId, Vec<u8>)>>,
     events_tx: mpsc::Sender<ReliableEvent>,
 }
 
 impl ReliableTransportInner {
-    fn new(listen_addr: SocketAddr, events_tx: mpsc::Sender<ReliableEvent>) -> Self {
-        let (incoming_tx, incoming_rx) = crossbeam_channel::unbounded();
+    fn new(
+        listen_addr: SocketAddr,
+        udp_public_addr: Option<SocketAddr>,
+        events_tx: mpsc::Sender<ReliableEvent>,
+    ) -> Self {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
         Self {
             next_client_id: 1,
             session_endpoint: None,
             connections: HashMap::new(),
             listen_addr,
-            incoming_rx,
+            udp_public_addr,
+            incoming_rx: Some(incoming_rx),
             incoming_tx,
             events_tx,
         }
@@ -236,7 +297,8 @@ impl ReliableTransportInner {
     pub fn send(&mut self, client_id: &ClientId, message: Vec<u8>) {
         if let Some(tx) = self.connections.get(client_id) {
             debug!("sending to {:?}: {:?}", client_id, self.connections.keys());
-            tx.send(message).unwrap();
+            // the websocket closing unregisters the client soon enough
+            tx.send(message).ok();
         }
     }
 
@@ -262,17 +324,27 @@ struct UnreliableTransport {
     rtc: RtcServer,
     session_endpoint: SessionEndpoint,
     incoming_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
-    outgoing_rx: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+    outgoing_rx: mpsc::Receiver<UnreliableCommand>,
+    datagrams: Datagrams,
+    // plain UDP for native clients, next to WebRTC
+    udp: Option<UdpSocket>,
+    // connected clients that came over `udp`
+    udp_peers: HashSet<SocketAddr>,
 }
 
 impl UnreliableTransport {
     async fn new(
         listen_addr: SocketAddr,
         public_addr: SocketAddr,
+        udp_listen_addr: Option<SocketAddr>,
         incoming_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
-        outgoing_rx: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+        outgoing_rx: mpsc::Receiver<UnreliableCommand>,
     ) -> Self {
         let rtc = RtcServer::new(listen_addr, public_addr).await.unwrap();
+        let udp = match udp_listen_addr {
+            Some(addr) => Some(UdpSocket::bind(addr).await.unwrap()),
+            None => None,
+        };
         let session_endpoint = rtc.session_endpoint();
         // let rtc = Arc::new(RwLock::new(rtc));
         Self {
@@ -280,6 +352,9 @@ impl UnreliableTransport {
             session_endpoint,
             incoming_tx,
             outgoing_rx,
+            datagrams: Datagrams::default(),
+            udp,
+            udp_peers: HashSet::new(),
         }
     }
 
@@ -287,11 +362,36 @@ impl UnreliableTransport {
         self.session_endpoint.clone()
     }
 
+    async fn send(&mut self, addr: SocketAddr, datagram: &[u8]) -> Result<(), SendError> {
+        match self.udp.as_ref() {
+            Some(udp) if self.udp_peers.contains(&addr) => {
+                udp.send_to(datagram, addr).await?;
+            }
+            _ => {
+                self.rtc
+                    .send(datagram, webrtc_unreliable::MessageType::Binary, &addr)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
     async fn listen(&mut self) {
         enum Next {
             Recv(SocketAddr, Vec<u8>),
-            Send(SocketAddr, Vec<u8>),
+            Command(UnreliableCommand),
         }
+        async fn recv_udp(
+            udp: Option<&UdpSocket>,
+            buffer: &mut [u8],
+        ) -> std::io::Result<(usize, SocketAddr)> {
+            match udp {
+                Some(udp) => udp.recv_from(buffer).await,
+                None => futures::future::pending().await,
+            }
+        }
+
+        let mut udp_buffer = vec![0; 65536];
         loop {
             let next = tokio::select! {
                 Ok(recv) = self.rtc.recv() => {
@@ -300,118 +400,315 @@ impl UnreliableTransport {
                     Next::Recv(addr, bytes)
                     // self.incoming_tx.send((addr, bytes)).await.unwrap();
                 },
-                Some((addr, data)) = self.outgoing_rx.recv() => {
-                    trace!("sending via rtc to {:?}", addr);
-                    Next::Send(addr, data)
-                }
+                Ok((size, addr)) = recv_udp(self.udp.as_ref(), &mut udp_buffer) => {
+                    Next::Recv(addr, udp_buffer[..size].to_vec())
+                },
+                Some(command) = self.outgoing_rx.recv() => Next::Command(command),
             };
 
             match next {
                 Next::Recv(addr, data) => {
-                    self.incoming_tx.send((addr, data)).await.unwrap();
+                    if self.incoming_tx.send((addr, data)).await.is_err() {
+                        debug!("server stopped, so unreliable transport stops too");
+                        break;
+                    }
                 }
-                Next::Send(addr, data) => {
-                    let size = data.len();
-                    // debug!("size: {:?}", size);
-                    if size > 1600 {
-                        panic!();
+                Next::Command(UnreliableCommand::Send(addr, data)) => {
+                    trace!(?addr, "sending datagram");
+                    for datagram in self.datagrams.split(data) {
+                        if let Err(e) = self.send(addr, &datagram).await {
+                            warn!("failed to send to {:?}", addr);
+                            break;
+                        }
                     }
-                    if let Err(e) = self
-                        .rtc
-                        .send(&data, webrtc_unreliable::MessageType::Binary, &addr)
-                        .await
-                    {
-                        warn!("failed to send to {:?}", addr);
+                }
+                Next::Command(UnreliableCommand::Connected(addr)) => {
+                    // webrtc clients are the rtc server's, so anyone else came over udp
+                    if self.udp.is_some() && !self.rtc.is_connected(&addr) {
+                        self.udp_peers.insert(addr);
                     }
                 }
+                Next::Command(UnreliableCommand::Disconnected(addr)) => {
+                    self.udp_peers.remove(&addr);
+                }
+            }
+        }
+    }
+}
+
+// splits what the server sends into datagrams, fragmenting what doesn't fit in one
+#[derive(Debug, Default)]
+pub(crate) struct Datagrams {
+    fragmenter: Fragmenter,
+}
+
+impl Datagrams {
+    // `data` as datagrams small enough to send, fragmented if it doesn't fit in one
+    pub fn split(&mut self, data: Vec<u8>) -> Vec<Vec<u8>> {
+        if data.len() <= MAX_DATAGRAM_SIZE {
+            return vec![data];
+        }
+        match self.fragmenter.split(&data) {
+            Some(fragments) => fragments
+                .into_iter()
+                .map(|fragment| frame::encode_fragment(&fragment))
+                .collect(),
+            None => {
+                warn!(size = data.len(), "dropping message too large to send");
+                Vec::new()
             }
         }
     }
 }
 
+/// A client finishing or losing its connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    // both transports are ready, so broadcasts reach the client from now on
+    Connected(ClientId),
+    Disconnected(ClientId, DisconnectReason),
+}
+
+/// Which connected clients a packet goes to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recipients {
+    One(ClientId),
+    Many(Vec<ClientId>),
+    AllExcept(ClientId),
+    All,
+}
+
+/// A packet for the server to send.
+#[derive(Debug, Clone)]
+pub struct Outgoing<T> {
+    pub recipients: Recipients,
+    pub channel: ChannelId,
+    pub packet: T,
+}
+
+impl<T> Outgoing<T> {
+    pub fn new(recipients: Recipients, channel: ChannelId, packet: T) -> Self {
+        Self {
+            recipients,
+            channel,
+            packet,
+        }
+    }
+
+    pub fn reliable(recipients: Recipients, packet: T) -> Self {
+        Self::new(recipients, ChannelId::RELIABLE, packet)
+    }
+
+    pub fn reliable_ordered(recipients: Recipients, packet: T) -> Self {
+        Self::new(recipients, ChannelId::RELIABLE_ORDERED, packet)
+    }
+
+    pub fn unreliable(recipients: Recipients, packet: T) -> Self {
+        Self::new(recipients, ChannelId::UNRELIABLE, packet)
+    }
+
+    pub fn unreliable_sequenced(recipients: Recipients, packet: T) -> Self {
+        Self::new(recipients, ChannelId::UNRELIABLE_SEQUENCED, packet)
+    }
+}
+
 pub struct ServerConfig {
     pub http_listen_addr: SocketAddr,
     pub webrtc_listen_addr: SocketAddr,
     pub webrtc_public_addr: SocketAddr,
+    // for native clients, which send plain UDP datagrams instead of using WebRTC
+    pub udp_listen_addr: Option<SocketAddr>,
+    pub udp_public_addr: Option<SocketAddr>,
+}
+
+enum Transports {
+    Network {
+        reliable: ReliableTransport,
+        unreliable: Box<UnreliableTransport>,
+    },
+    Loopback {
+        network: Loopback,
+        reliable_rx: mpsc::UnboundedReceiver<(ClientId, Vec<u8>)>,
+        datagram_rx: mpsc::UnboundedReceiver<(SocketAddr, Vec<u8>)>,
+        incoming_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+        outgoing_rx: mpsc::Receiver<UnreliableCommand>,
+    },
 }
 
 pub struct Server<OutgoingPacket, IncomingPacket> {
-    config: ServerConfig,
-    reliable_buffers: HashMap<ClientId, ReliableBuffer<OutgoingPacket>>,
     incoming_packet_type: PhantomData<IncomingPacket>,
-    reliable_transport: Option<ReliableTransport>,
-    unreliable_transport: Option<UnreliableTransport>,
+    // taken when listening starts
+    transports: Option<Transports>,
     events_rx: mpsc::Receiver<ReliableEvent>,
     unreliable_incoming_rx: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
-    unreliable_outgoing_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    unreliable_outgoing_tx: mpsc::Sender<UnreliableCommand>,
     server_broadcast_rx: mpsc::UnboundedReceiver<OutgoingPacket>,
-    server_rx: mpsc::UnboundedReceiver<(ClientId, OutgoingPacket)>,
+    server_rx: mpsc::UnboundedReceiver<Outgoing<OutgoingPacket>>,
     server_tx: mpsc::UnboundedSender<(ClientId, IncomingPacket)>,
+    connection_tx: mpsc::UnboundedSender<ConnectionEvent>,
+    channels: Channels,
+    stats: ServerStats,
 }
 
 impl<OutgoingPacket, IncomingPacket> Server<OutgoingPacket, IncomingPacket>
 where
-    OutgoingPacket: std::fmt::Debug + Send + Sync + Serialize,
+    OutgoingPacket: std::fmt::Debug + Send + Sync + Serialize + DeserializeOwned,
     IncomingPacket: std::fmt::Debug + Send + Sync + DeserializeOwned,
 {
     pub async fn new(
         config: ServerConfig,
         server_broadcast_rx: mpsc::UnboundedReceiver<OutgoingPacket>,
-        server_rx: mpsc::UnboundedReceiver<(ClientId, OutgoingPacket)>,
+        server_rx: mpsc::UnboundedReceiver<Outgoing<OutgoingPacket>>,
         server_tx: mpsc::UnboundedSender<(ClientId, IncomingPacket)>,
+        connection_tx: mpsc::UnboundedSender<ConnectionEvent>,
     ) -> Self {
         let (events_tx, events_rx) = mpsc::channel(32);
 
-        let reliable_transport = ReliableTransport::new(config.http_listen_addr, events_tx);
+        let reliable_transport =
+            ReliableTransport::new(config.http_listen_addr, config.udp_public_addr, events_tx);
         let (incoming_tx, unreliable_incoming_rx) = mpsc::channel(32);
         let (unreliable_outgoing_tx, unreliable_outgoing_rx) = mpsc::channel(32);
 
         let unreliable_transport = UnreliableTransport::new(
             config.webrtc_listen_addr,
             config.webrtc_public_addr,
+            config.udp_listen_addr,
             incoming_tx,
             unreliable_outgoing_rx,
         )
         .await;
         Self {
-            config,
-            reliable_buffers: HashMap::new(),
             incoming_packet_type: PhantomData,
-            reliable_transport: Some(reliable_transport),
-            unreliable_transport: Some(unreliable_transport),
+            transports: Some(Transports::Network {
+                reliable: reliable_transport,
+                unreliable: Box::new(unreliable_transport),
+            }),
             events_rx,
             unreliable_incoming_rx,
             unreliable_outgoing_tx,
             server_broadcast_rx,
             server_rx,
             server_tx,
+            connection_tx,
+            channels: Channels::default(),
+            stats: ServerStats::default(),
         }
     }
 
+    /// A server for clients on `network` instead of real sockets, so tests can run it in
+    /// process.
+    pub fn loopback(
+        network: &Loopback,
+        server_broadcast_rx: mpsc::UnboundedReceiver<OutgoingPacket>,
+        server_rx: mpsc::UnboundedReceiver<Outgoing<OutgoingPacket>>,
+        server_tx: mpsc::UnboundedSender<(ClientId, IncomingPacket)>,
+        connection_tx: mpsc::UnboundedSender<ConnectionEvent>,
+    ) -> Self {
+        let (events_tx, events_rx) = mpsc::channel(32);
+        let (reliable_tx, reliable_rx) = mpsc::unbounded_channel();
+        let (datagram_tx, datagram_rx) = mpsc::unbounded_channel();
+        network.bind(events_tx, reliable_tx, datagram_tx);
+        let (incoming_tx, unreliable_incoming_rx) = mpsc::channel(32);
+        let (unreliable_outgoing_tx, outgoing_rx) = mpsc::channel(32);
+        Self {
+            incoming_packet_type: PhantomData,
+            transports: Some(Transports::Loopback {
+                network: network.clone(),
+                reliable_rx,
+                datagram_rx,
+                incoming_tx,
+                outgoing_rx,
+            }),
+            events_rx,
+            unreliable_incoming_rx,
+            unreliable_outgoing_tx,
+            server_broadcast_rx,
+            server_rx,
+            server_tx,
+            connection_tx,
+            channels: Channels::default(),
+            stats: ServerStats::default(),
+        }
+    }
+
+    /// Sends on `channels` instead of just the builtin ones.
+    pub fn with_channels(mut self, channels: Channels) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    /// Stats for every connected client, kept up to date while the server listens.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
+
     pub async fn listen(&mut self) {
-        let mut unreliable_transport = self.unreliable_transport.take().unwrap();
-        let mut reliable_transport = self.reliable_transport.take().unwrap();
-        reliable_transport
-            .set_session_endpoint(unreliable_transport.session_endpoint())
-            .await;
-        let _reliable_rx = reliable_transport.incoming().await;
-        let reliable_tx = reliable_transport.outgoing().await;
-        let _reliable = tokio::spawn(async move {
-            reliable_transport.listen().await;
-        });
-        let _unreliable = tokio::spawn(async move {
-            unreliable_transport.listen().await;
-        });
+        let (mut reliable_rx, reliable_tx) = match self.transports.take().unwrap() {
+            Transports::Network {
+                reliable: mut reliable_transport,
+                unreliable: mut unreliable_transport,
+            } => {
+                reliable_transport
+                    .set_session_endpoint(unreliable_transport.session_endpoint())
+                    .await;
+                let reliable_rx = reliable_transport.incoming().await;
+                let reliable_tx = reliable_transport.outgoing().await;
+                let _reliable = tokio::spawn(async move {
+                    reliable_transport.listen().await;
+                });
+                let _unreliable = tokio::spawn(async move {
+                    unreliable_transport.listen().await;
+                });
+                (reliable_rx, reliable_tx)
+            }
+            Transports::Loopback {
+                network,
+                reliable_rx,
+                datagram_rx,
+                incoming_tx,
+                outgoing_rx,
+            } => {
+                let (reliable_tx, reliable_outgoing_rx) = mpsc::channel(32);
+                tokio::spawn(network.serve(
+                    datagram_rx,
+                    incoming_tx,
+                    reliable_outgoing_rx,
+                    outgoing_rx,
+                ));
+                (reliable_rx, reliable_tx)
+            }
+        };
         {
             let mut processor = Processor::<OutgoingPacket, IncomingPacket>::new(
                 reliable_tx,
                 self.unreliable_outgoing_tx.clone(),
+                self.server_tx.clone(),
+                self.connection_tx.clone(),
+                self.channels.clone(),
+                ProtocolMarker::new::<OutgoingPacket, IncomingPacket>(),
+                self.stats.clone(),
             );
 
+            let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+            let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
             loop {
                 tokio::select! {
                     Some(broadcast) = self.server_broadcast_rx.recv() => {
-                        processor.broadcast(broadcast).await;
+                        processor.broadcast(broadcast);
+                        self.queue_waiting(&mut processor);
+                        processor.flush().await;
+                    }
+                    Some(outgoing) = self.server_rx.recv() => {
+                        processor.send(outgoing);
+                        self.queue_waiting(&mut processor);
+                        processor.flush().await;
+                    }
+                    _ = flush.tick() => {
+                        processor.flush().await;
+                    }
+                    _ = heartbeat.tick() => {
+                        processor.heartbeat();
+                        processor.flush().await;
                     }
                     Some(event) = self.events_rx.recv() => {
                         debug!("got reliable event {:?}", event);
@@ -420,18 +717,33 @@ where
                                 processor.register_reliable_client(id, challenge).await;
                             }
                             ReliableEvent::ClientDisconnected { id } => {
-                                processor.unregister_client(&id);
+                                processor.unregister_client(&id, DisconnectReason::Closed);
                             }
                         }
                     }
 
+                    Some((client_id, packet)) = reliable_rx.recv() => {
+                        processor.process_reliable_packet(client_id, packet);
+                    }
+
                     Some((addr, packet)) = self.unreliable_incoming_rx.recv() => {
-                        processor.process_packet(addr, packet).await;
+                        processor.receive(addr, packet).await;
                     }
                 }
             }
         };
     }
+
+    // hands the processor everything else the application sent meanwhile, so it all goes out in
+    // the same flush
+    fn queue_waiting(&mut self, processor: &mut Processor<OutgoingPacket, IncomingPacket>) {
+        while let Some(Some(broadcast)) = self.server_broadcast_rx.recv().now_or_never() {
+            processor.broadcast(broadcast);
+        }
+        while let Some(Some(outgoing)) = self.server_rx.recv().now_or_never() {
+            processor.send(outgoing);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -440,8 +752,26 @@ struct Processor<OutgoingPacket, IncomingPacket> {
     outgoing_type: std::marker::PhantomData<OutgoingPacket>,
     challenge_to_client: HashMap<String, ClientId>,
     addr_to_client: HashMap<SocketAddr, ClientId>,
+    channels: Channels,
+    // sent to clients in the handshake, and checked against theirs
+    marker: ProtocolMarker,
+    // encoded packets waiting to go to each client, and reliable ones until they are acked
+    schedulers: HashMap<ClientId, Scheduler>,
+    // what each client sent on the way, to drop copies and stale packets
+    incoming: HashMap<ClientId, Incoming<Vec<u8>>>,
+    // fragments from each connected client, until the rest of their message arrives
+    reassemblers: HashMap<ClientId, Reassembler>,
+    // when a datagram last arrived from each client, or when it connected if none has yet
+    last_heard: HashMap<ClientId, instant::Instant>,
+    next_ping: u32,
+    traffic: HashMap<ClientId, Traffic>,
+    stats: ServerStats,
     reliable_tx: mpsc::Sender<(ClientId, Vec<u8>)>,
-    unreliable_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    unreliable_tx: mpsc::Sender<UnreliableCommand>,
+    // clients' addresses coming and going, told to the unreliable transport with the next flush
+    peer_changes: Vec<UnreliableCommand>,
+    server_tx: mpsc::UnboundedSender<(ClientId, IncomingPacket)>,
+    connection_tx: mpsc::UnboundedSender<ConnectionEvent>,
 }
 
 impl<OutgoingPacket, IncomingPacket> Processor<OutgoingPacket, IncomingPacket>
@@ -451,119 +781,601 @@ where
 {
     fn new(
         reliable_tx: mpsc::Sender<(ClientId, Vec<u8>)>,
-        unreliable_tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+        unreliable_tx: mpsc::Sender<UnreliableCommand>,
+        server_tx: mpsc::UnboundedSender<(ClientId, IncomingPacket)>,
+        connection_tx: mpsc::UnboundedSender<ConnectionEvent>,
+        channels: Channels,
+        marker: ProtocolMarker,
+        stats: ServerStats,
     ) -> Self {
         Self {
+            marker,
             incoming_type: std::marker::PhantomData,
             outgoing_type: std::marker::PhantomData,
             challenge_to_client: HashMap::new(),
             addr_to_client: HashMap::new(),
+            channels,
+            schedulers: HashMap::new(),
+            incoming: HashMap::new(),
+            reassemblers: HashMap::new(),
+            last_heard: HashMap::new(),
+            next_ping: 0,
+            traffic: HashMap::new(),
+            stats,
             reliable_tx,
             unreliable_tx,
+            peer_changes: Vec::new(),
+            server_tx,
+            connection_tx,
         }
     }
 
+    // queues a packet, to go out with the next flush
     #[tracing::instrument(level = "debug", skip(self))]
-    async fn broadcast(&self, packet: OutgoingPacket) {
+    fn send(&mut self, outgoing: Outgoing<OutgoingPacket>) {
         use bincode::Options;
         let bincoder = bincode::DefaultOptions::new()
             .with_fixint_encoding()
             .reject_trailing_bytes();
-        let encoded = bincoder.serialize(&packet).unwrap();
-        for addr in self.addr_to_client.keys() {
-            self.unreliable_tx
-                .send((addr.clone(), encoded.clone()))
-                .await
-                .unwrap();
+        let encoded = bincoder.serialize(&outgoing.packet).unwrap();
+        for client_id in self.recipients(&outgoing.recipients) {
+            self.queue(client_id, outgoing.channel, encoded.clone());
         }
     }
 
-    fn client_id(&self, addr: &SocketAddr) -> Option<ClientId> {
-        self.addr_to_client.get(addr).copied()
+    fn queue(&mut self, client_id: ClientId, channel: ChannelId, packet: Vec<u8>) {
+        if let Some(scheduler) = self.schedulers.get_mut(&client_id) {
+            if let Err(error) = scheduler.push(channel, packet) {
+                warn!(?client_id, %error, "dropping packet");
+            }
+        }
     }
 
-    #[tracing::instrument(level = "debug", skip(self, packet))]
-    #[async_recursion::async_recursion]
-    async fn process_packet(&mut self, addr: SocketAddr, packet: Vec<u8>) {
+    // connected clients among `recipients`
+    fn recipients(&self, recipients: &Recipients) -> Vec<ClientId> {
+        let mut connected = self.addr_to_client.values().copied().collect::<Vec<_>>();
+        connected.sort_unstable();
+        connected.dedup();
+        match recipients {
+            Recipients::One(client_id) => connected.retain(|id| id == client_id),
+            Recipients::Many(client_ids) => connected.retain(|id| client_ids.contains(id)),
+            Recipients::AllExcept(client_id) => connected.retain(|id| id != client_id),
+            Recipients::All => {}
+        }
+        connected
+    }
+
+    // sends what each client's scheduler lets through, reliable packets that weren't acked in
+    // time included, packed into as few datagrams as fit
+    async fn flush(&mut self) {
+        let mut datagrams = Vec::new();
+        for (client_id, scheduler) in self.schedulers.iter_mut() {
+            let addr = self
+                .addr_to_client
+                .iter()
+                .find(|(_, id)| *id == client_id)
+                .map(|(addr, _)| *addr);
+            let (addr, traffic) = match (addr, self.traffic.get_mut(client_id)) {
+                (Some(addr), Some(traffic)) => (addr, traffic),
+                _ => continue,
+            };
+            let mut batcher = Batcher::new();
+            scheduler.flush(traffic, |message| {
+                let data = frame::encode(message);
+                let size = data.len();
+                batcher.push(data);
+                Some(size)
+            });
+            for datagram in batcher.finish() {
+                traffic.sent.record(datagram.len());
+                datagrams.push(UnreliableCommand::Send(addr, datagram));
+            }
+        }
+        let commands = self
+            .peer_changes
+            .drain(..)
+            .chain(datagrams)
+            .collect::<Vec<_>>();
+        for command in commands {
+            if self.unreliable_tx.send(command).await.is_err() {
+                warn!("unreliable transport stopped");
+            }
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn broadcast(&mut self, packet: OutgoingPacket) {
         use bincode::Options;
         let bincoder = bincode::DefaultOptions::new()
             .with_fixint_encoding()
             .reject_trailing_bytes();
+        let encoded = bincoder.serialize(&packet).unwrap();
+        for client_id in self.recipients(&Recipients::All) {
+            self.queue(client_id, ChannelId::UNRELIABLE, encoded.clone());
+        }
+    }
 
-        if let Ok(deserialized) = bincoder.deserialize::<IncomingPacket>(&packet) {
-            debug!("got user packet");
-        } else if let Ok(deserialized) = bincoder.deserialize::<ClientProtocolPacket>(&packet) {
-            debug!(?deserialized);
-            match deserialized {
-                ClientProtocolPacket::Connect { challenge } => {
+    async fn send_reliable(&mut self, client_id: ClientId, data: Vec<u8>) {
+        self.record_sent(client_id, data.len());
+        if self.reliable_tx.send((client_id, data)).await.is_err() {
+            warn!(?client_id, "reliable transport stopped");
+        }
+    }
+
+    fn record_sent(&mut self, client_id: ClientId, size: usize) {
+        if let Some(traffic) = self.traffic.get_mut(&client_id) {
+            traffic.sent.record(size);
+        }
+    }
+
+    fn record_received(&mut self, client_id: ClientId, size: usize) {
+        if let Some(traffic) = self.traffic.get_mut(&client_id) {
+            traffic.received.record(size);
+        }
+    }
+
+    fn connection_stats(&self) -> HashMap<ClientId, ConnectionStats> {
+        self.traffic
+            .iter()
+            .map(|(client_id, traffic)| (*client_id, traffic.stats()))
+            .collect()
+    }
+
+    fn ack(&mut self, client_id: ClientId, channel: ChannelId, id: &AckId) {
+        let scheduler = self.schedulers.get_mut(&client_id);
+        if let (Some(scheduler), Some(traffic)) = (scheduler, self.traffic.get_mut(&client_id)) {
+            scheduler.ack(channel, id, traffic);
+        }
+    }
+
+    // pings connected clients, gives up on those nothing arrived from in `IDLE_TIMEOUT`, and on
+    // messages still missing fragments
+    fn heartbeat(&mut self) {
+        let now = instant::Instant::now();
+        self.reassemblers.retain(|_, reassembler| {
+            reassembler.expire();
+            !reassembler.is_empty()
+        });
+        let timed_out = self
+            .last_heard
+            .iter()
+            .filter(|(_, heard)| now.duration_since(**heard) >= IDLE_TIMEOUT)
+            .map(|(client_id, _)| *client_id)
+            .collect::<Vec<_>>();
+        for client_id in timed_out {
+            debug!(?client_id, "client timed out");
+            self.disconnect(client_id, DisconnectReason::TimedOut);
+        }
+
+        let ping = ServerProtocolPacketInner::Ping { id: self.next_ping }
+            .into_packet()
+            .encode();
+        self.next_ping = self.next_ping.wrapping_add(1);
+        for client_id in self.addr_to_client.values() {
+            if let Some(scheduler) = self.schedulers.get_mut(client_id) {
+                scheduler.push_control(ping.clone());
+            }
+        }
+
+        self.stats.publish(self.connection_stats());
+    }
+
+    // tells the client why, before forgetting it
+    fn disconnect(&mut self, client_id: ClientId, reason: DisconnectReason) {
+        let packet = ServerProtocolPacketInner::Disconnect { reason }
+            .into_packet()
+            .encode();
+        if self.reliable_tx.try_send((client_id, packet)).is_err() {
+            debug!(?client_id, "couldn't say why it's disconnected");
+        }
+        self.unregister_client(&client_id, reason);
+    }
+
+    fn client_id(&self, addr: &SocketAddr) -> Option<ClientId> {
+        self.addr_to_client.get(addr).copied()
+    }
+
+    // hands a user packet to the application
+    fn forward(&self, client_id: ClientId, packet: IncomingPacket) {
+        if self.server_tx.send((client_id, packet)).is_err() {
+            warn!(?client_id, "application stopped receiving packets");
+        }
+    }
+
+    fn notify(&self, event: ConnectionEvent) {
+        if self.connection_tx.send(event).is_err() {
+            warn!(?event, "application stopped receiving connection events");
+        }
+    }
+
+    // a packet that arrived on the client's websocket
+    #[tracing::instrument(level = "debug", skip(self, packet))]
+    fn process_reliable_packet(&mut self, client_id: ClientId, packet: Vec<u8>) {
+        self.record_received(client_id, packet.len());
+        match frame::decode(&packet) {
+            Some((header, body)) if header.kind == Kind::Protocol => {
+                match ClientProtocolPacket::decode(body) {
+                    Some(ClientProtocolPacket::Disconnect { reason }) => {
+                        self.unregister_client(&client_id, reason);
+                    }
+                    Some(_) => debug!("ignoring protocol packet sent over the websocket"),
+                    None => warn!(?client_id, "dropping malformed protocol packet"),
+                }
+            }
+            Some((header, body)) if header.kind == Kind::Unreliable => {
+                self.deliver(client_id, body)
+            }
+            Some((header, _)) => {
+                debug!(kind = ?header.kind, "ignoring frame sent over the websocket")
+            }
+            None => warn!(?client_id, "dropping malformed packet"),
+        }
+    }
+
+    // a datagram that arrived from `addr`, put back together if it was fragmented
+    async fn receive(&mut self, addr: SocketAddr, packet: Vec<u8>) {
+        if let Some(client_id) = self.client_id(&addr) {
+            self.last_heard.insert(client_id, instant::Instant::now());
+            self.record_received(client_id, packet.len());
+        }
+        let packet = match frame::decode(&packet) {
+            Some((header, body)) if header.kind == Kind::Fragment => {
+                match self.reassemble(addr, body) {
+                    Some(message) => message,
+                    None => return,
+                }
+            }
+            _ => packet,
+        };
+        self.process_packet(addr, &packet).await;
+    }
+
+    // the whole message a fragment completes, if it does. only fragments from connected clients
+    // are kept, so nobody else gets the server to buffer anything
+    fn reassemble(&mut self, addr: SocketAddr, body: &[u8]) -> Option<Vec<u8>> {
+        let client_id = match self.client_id(&addr) {
+            Some(client_id) => client_id,
+            None => {
+                debug!(?addr, "dropping fragment from unknown client");
+                return None;
+            }
+        };
+        match frame::decode_fragment(body) {
+            Some(fragment) => self
+                .reassemblers
+                .entry(client_id)
+                .or_default()
+                .insert(fragment),
+            None => {
+                warn!(?addr, "dropping malformed fragment");
+                None
+            }
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, packet))]
+    async fn process_packet(&mut self, addr: SocketAddr, packet: &[u8]) {
+        match frame::decode(packet) {
+            Some((header, body)) if header.kind == Kind::Batch => match batch::unframe(body) {
+                Some(frames) => {
+                    for frame in frames {
+                        self.process_frame(addr, &frame).await;
+                    }
+                }
+                None => warn!(?addr, "dropping malformed batch"),
+            },
+            Some(_) => self.process_frame(addr, packet).await,
+            None => warn!(?addr, "dropping malformed packet"),
+        }
+    }
+
+    // one frame of a datagram. batches can't be nested, and fragments are put back together on
+    // arrival, so neither is expected here
+    async fn process_frame(&mut self, addr: SocketAddr, frame: &[u8]) {
+        let (header, body) = match frame::decode(frame) {
+            Some(decoded) => decoded,
+            None => {
+                warn!(?addr, "dropping malformed frame");
+                return;
+            }
+        };
+        if header.kind == Kind::Protocol {
+            match ClientProtocolPacket::decode(body) {
+                Some(packet) => self.process_protocol_packet(addr, packet).await,
+                None => warn!(?addr, "dropping malformed protocol packet"),
+            }
+            return;
+        }
+        let client_id = match self.client_id(&addr) {
+            Some(client_id) => client_id,
+            None => {
+                // reliable packets are sent again, by when the client is welcomed
+                debug!(?addr, "dropping packet from unknown client");
+                return;
+            }
+        };
+        let incoming = match self.incoming.get_mut(&client_id) {
+            Some(incoming) => incoming,
+            None => return,
+        };
+        match header.kind {
+            Kind::Unreliable => self.deliver(client_id, body),
+            Kind::Reliable | Kind::ReliableOrdered => {
+                let id = AckId::new(header.sequence);
+                let ordered = header.kind == Kind::ReliableOrdered;
+                let ready = match incoming.reliable(header.channel, id, ordered, body.to_vec()) {
+                    Some(ready) => ready,
+                    None => {
+                        debug!(?id, "dropping packet too far ahead");
+                        return;
+                    }
+                };
+                debug!(?id, "queueing ack");
+                if let Some(scheduler) = self.schedulers.get_mut(&client_id) {
+                    scheduler.push_control(frame::ack(header.channel, id));
+                }
+                for packet in ready {
+                    self.deliver(client_id, &packet);
+                }
+            }
+            Kind::Sequenced => {
+                if incoming.sequenced(header.channel, header.sequence) {
+                    self.deliver(client_id, body);
+                } else {
+                    trace!(sequence = header.sequence, ?addr, "dropping stale packet");
+                }
+            }
+            Kind::Redundant => match batch::unframe(body) {
+                Some(packets) => {
+                    for packet in incoming.redundant(header.channel, header.sequence, packets) {
+                        self.deliver(client_id, &packet);
+                    }
+                }
+                None => warn!(?addr, "dropping malformed redundant packet"),
+            },
+            Kind::Ack => {
+                debug!("got ack");
+                self.ack(client_id, header.channel, &AckId::new(header.sequence));
+            }
+            Kind::Protocol | Kind::Batch | Kind::Fragment => {
+                warn!(kind = ?header.kind, ?addr, "dropping unexpected frame");
+            }
+        }
+    }
+
+    async fn process_protocol_packet(&mut self, addr: SocketAddr, packet: ClientProtocolPacket) {
+        debug!(?packet);
+        match packet {
+            ClientProtocolPacket::Connect { marker, challenge } => {
+                if let Some(reason) = ProtocolMarker::mismatch(&self.marker, &marker) {
+                    warn!(?reason, ?addr, "turning away a client built differently");
+                    if let Some(client_id) = self.challenge_to_client.get(&challenge) {
+                        self.disconnect(*client_id, reason);
+                    }
+                    return;
+                }
+                debug!(
+                    ?challenge,
+                    ?addr,
+                    "got unreliable transport client connect packet",
+                );
+                // sent again until the welcome arrives, so this may not be the first one
+                let reconnect = self.addr_to_client.contains_key(&addr);
+                if let Some(client_id) = self.register_unreliable_client(&challenge, addr) {
                     debug!(
-                        ?challenge,
-                        ?addr,
-                        "got unreliable transport client connect packet",
+                        ?client_id,
+                        "associated unreliable connection to reliable connection"
                     );
-                    if let Some(client_id) = self.register_unreliable_client(&challenge, addr) {
-                        debug!(
-                            ?client_id,
-                            "associated unreliable connection to reliable connection"
-                        );
-                        self.reliable_tx
-                            .send((
-                                client_id,
-                                ServerProtocolPacket::from(ServerProtocolPacketInner::Welcome {})
-                                    .encode(),
-                            ))
-                            .await
-                            .unwrap();
-                    } else {
-                        // TODO
-                        panic!("no known client for challenge");
+                    self.last_heard.insert(client_id, instant::Instant::now());
+                    let welcome =
+                        ServerProtocolPacket::from(ServerProtocolPacketInner::Welcome {}).encode();
+                    self.send_reliable(client_id, welcome).await;
+                    if !reconnect {
+                        self.notify(ConnectionEvent::Connected(client_id));
                     }
+                } else {
+                    // a stale challenge, from a client already given up on
+                    debug!(?addr, "no known client for challenge");
                 }
-                ClientProtocolPacket::Ack { id } => {
-                    debug!("got ack");
-                }
-                ClientProtocolPacket::AckRequest { packet, id } => {
-                    self.process_packet(addr, packet).await;
-                    debug!(?id, "sending ack");
-                    self.unreliable_tx
-                        .send((
-                            addr,
-                            ServerProtocolPacketInner::Ack { id }.into_packet().encode(),
-                        ))
-                        .await
-                        .unwrap();
+            }
+            ClientProtocolPacket::Pong { id } => {
+                trace!(?id, ?addr, "got pong");
+            }
+            ClientProtocolPacket::Disconnect { reason } => {
+                if let Some(client_id) = self.client_id(&addr) {
+                    self.unregister_client(&client_id, reason);
                 }
             }
         }
     }
 
+    // decodes an application packet and hands it over
+    fn deliver(&self, client_id: ClientId, packet: &[u8]) {
+        use bincode::Options;
+        let bincoder = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes();
+        match bincoder.deserialize::<IncomingPacket>(packet) {
+            Ok(packet) => self.forward(client_id, packet),
+            Err(error) => warn!(?client_id, %error, "dropping packet that doesn't decode"),
+        }
+    }
+
     fn register_unreliable_client(
         &mut self,
         challenge: &str,
         addr: SocketAddr,
     ) -> Option<ClientId> {
-        let client_id = self.challenge_to_client.get(challenge)?;
-        self.addr_to_client.insert(addr, *client_id);
-        Some(*client_id)
+        let client_id = *self.challenge_to_client.get(challenge)?;
+        if self.addr_to_client.insert(addr, client_id).is_none() {
+            self.peer_changes.push(UnreliableCommand::Connected(addr));
+        }
+        Some(client_id)
     }
 
     async fn register_reliable_client(&mut self, client_id: ClientId, challenge: String) {
         self.challenge_to_client
             .insert(challenge.clone(), client_id);
-        self.reliable_tx
-            .send((
-                client_id,
-                ServerProtocolPacket::from(ServerProtocolPacketInner::ConnectChallenge {
-                    challenge,
-                })
-                .encode(),
-            ))
-            .await
-            .unwrap();
+        // the handshake has to finish within the idle timeout too
+        self.last_heard.insert(client_id, instant::Instant::now());
+        self.traffic.insert(client_id, Traffic::default());
+        self.incoming.insert(client_id, Incoming::new());
+        self.schedulers
+            .insert(client_id, Scheduler::new(&self.channels));
+        let packet = ServerProtocolPacketInner::ConnectChallenge {
+            marker: self.marker,
+            challenge,
+        }
+        .into_packet()
+        .encode();
+        self.send_reliable(client_id, packet).await;
     }
 
-    fn unregister_client(&mut self, client_id: &ClientId) {
-        self.addr_to_client.retain(|_, v| v != client_id);
+    fn unregister_client(&mut self, client_id: &ClientId, reason: DisconnectReason) {
+        let connected = self.addr_to_client.values().any(|v| v == client_id);
+        let peer_changes = &mut self.peer_changes;
+        self.addr_to_client.retain(|addr, v| {
+            if v == client_id {
+                peer_changes.push(UnreliableCommand::Disconnected(*addr));
+            }
+            v != client_id
+        });
         self.challenge_to_client.retain(|_, v| v != client_id);
+        self.schedulers.remove(client_id);
+        self.incoming.remove(client_id);
+        self.reassemblers.remove(client_id);
+        self.last_heard.remove(client_id);
+        self.traffic.remove(client_id);
+        if connected {
+            self.notify(ConnectionEvent::Disconnected(*client_id, reason));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::Rng;
+
+    #[tokio::test]
+    async fn garbage_from_clients_never_panics() {
+        let (reliable_tx, _reliable_rx) = mpsc::channel(100);
+        let (unreliable_tx, _unreliable_rx) = mpsc::channel(100);
+        let (server_tx, _server_rx) = mpsc::unbounded_channel();
+        let (connection_tx, _connection_rx) = mpsc::unbounded_channel();
+        let mut processor = Processor::<String, Vec<u16>>::new(
+            reliable_tx,
+            unreliable_tx,
+            server_tx,
+            connection_tx,
+            Channels::default(),
+            ProtocolMarker::new::<String, Vec<u16>>(),
+            ServerStats::default(),
+        );
+        let client_id = ClientId::new(0);
+        let known = SocketAddr::from(([127, 0, 0, 1], 1));
+        let unknown = SocketAddr::from(([127, 0, 0, 1], 2));
+        processor
+            .register_reliable_client(client_id, "challenge".to_string())
+            .await;
+        processor.register_unreliable_client("challenge", known);
+
+        let mut rng = Rng::new(25);
+        for index in 0..10_000 {
+            let packet = frame::garbage(&mut rng);
+            match index % 3 {
+                0 => processor.receive(known, packet).await,
+                1 => processor.receive(unknown, packet).await,
+                _ => processor.process_reliable_packet(client_id, packet),
+            }
+        }
+        processor.flush().await;
+        assert_eq!(processor.client_id(&known), Some(client_id));
+    }
+
+    #[tokio::test]
+    async fn the_unreliable_transport_learns_of_connected_clients() {
+        let (reliable_tx, _reliable_rx) = mpsc::channel(100);
+        let (unreliable_tx, mut unreliable_rx) = mpsc::channel(100);
+        let (server_tx, _server_rx) = mpsc::unbounded_channel();
+        let (connection_tx, _connection_rx) = mpsc::unbounded_channel();
+        let mut processor = Processor::<String, Vec<u16>>::new(
+            reliable_tx,
+            unreliable_tx,
+            server_tx,
+            connection_tx,
+            Channels::default(),
+            ProtocolMarker::new::<String, Vec<u16>>(),
+            ServerStats::default(),
+        );
+        let client_id = ClientId::new(0);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut commands = move || {
+            std::iter::from_fn(|| unreliable_rx.recv().now_or_never().flatten())
+                .filter(|command| !matches!(command, UnreliableCommand::Send(..)))
+                .collect::<Vec<_>>()
+        };
+
+        // nothing before the handshake
+        processor
+            .register_reliable_client(client_id, "challenge".to_string())
+            .await;
+        processor.register_unreliable_client("wrong", addr);
+        processor.flush().await;
+        assert_eq!(commands(), vec![]);
+
+        // once, though connects are sent until the welcome arrives
+        processor.register_unreliable_client("challenge", addr);
+        processor.register_unreliable_client("challenge", addr);
+        processor.flush().await;
+        assert_eq!(commands(), vec![UnreliableCommand::Connected(addr)]);
+
+        processor.unregister_client(&client_id, DisconnectReason::Closed);
+        processor.flush().await;
+        assert_eq!(commands(), vec![UnreliableCommand::Disconnected(addr)]);
+    }
+
+    #[tokio::test]
+    async fn only_connected_clients_fragments_are_buffered() {
+        let (reliable_tx, _reliable_rx) = mpsc::channel(100);
+        let (unreliable_tx, _unreliable_rx) = mpsc::channel(100);
+        let (server_tx, mut server_rx) = mpsc::unbounded_channel();
+        let (connection_tx, _connection_rx) = mpsc::unbounded_channel();
+        let mut processor = Processor::<String, Vec<u16>>::new(
+            reliable_tx,
+            unreliable_tx,
+            server_tx,
+            connection_tx,
+            Channels::default(),
+            ProtocolMarker::new::<String, Vec<u16>>(),
+            ServerStats::default(),
+        );
+        let client_id = ClientId::new(0);
+        let known = SocketAddr::from(([127, 0, 0, 1], 1));
+        let unknown = SocketAddr::from(([127, 0, 0, 1], 2));
+        processor
+            .register_reliable_client(client_id, "challenge".to_string())
+            .await;
+        processor.register_unreliable_client("challenge", known);
+
+        let packet = vec![7u16; 2000];
+        let message = frame::Header::new(Kind::Unreliable, ChannelId::UNRELIABLE, 0)
+            .frame(&bincode::serialize(&packet).unwrap());
+        let datagrams = Datagrams::default().split(message);
+        assert!(datagrams.len() > 1);
+        for datagram in &datagrams[1..] {
+            processor.receive(unknown, datagram.clone()).await;
+        }
+        assert!(processor.reassemblers.is_empty());
+        for datagram in datagrams {
+            processor.receive(known, datagram).await;
+        }
+        assert_eq!(
+            server_rx.recv().now_or_never(),
+            Some(Some((client_id, packet)))
+        );
+
+        processor.unregister_client(&client_id, DisconnectReason::Closed);
+        assert!(processor.reassemblers.is_empty());
     }
 }
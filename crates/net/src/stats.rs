@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::protocol::ClientId;
+
+// retransmit timeouts before there's a round trip to go by, and the bounds on them after
+const INITIAL_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(300);
+const MIN_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(50);
+const MAX_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(2);
+// how much of each new sample goes into the loss average
+const LOSS_WEIGHT: f64 = 1.0 / 32.0;
+// byte rates are averaged over at least this long
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// How a connection is doing, for debug overlays and logs.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ConnectionStats {
+    /// Smoothed round trip time, once an ack has been timed.
+    pub rtt: Option<Duration>,
+    /// How far round trips usually stray from `rtt`.
+    pub rtt_variance: Duration,
+    /// How long reliable packets wait for an ack before being resent.
+    pub retransmit_timeout: Duration,
+    /// The share of reliable packets lost lately, from 0 to 1.
+    pub loss: f64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub bytes_sent_per_second: f64,
+    pub bytes_received_per_second: f64,
+}
+
+/// The latest stats of every connected client, refreshed by the server every second or so.
+/// Clones share them.
+#[derive(Debug, Clone, Default)]
+pub struct ServerStats {
+    clients: Arc<RwLock<HashMap<ClientId, ConnectionStats>>>,
+}
+
+impl ServerStats {
+    pub fn get(&self, client_id: ClientId) -> Option<ConnectionStats> {
+        self.clients.read().unwrap().get(&client_id).copied()
+    }
+
+    pub fn all(&self) -> Vec<(ClientId, ConnectionStats)> {
+        let mut all = self
+            .clients
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(client_id, stats)| (*client_id, *stats))
+            .collect::<Vec<_>>();
+        all.sort_unstable_by_key(|(client_id, _)| *client_id);
+        all
+    }
+
+    pub(crate) fn publish(&self, clients: HashMap<ClientId, ConnectionStats>) {
+        *self.clients.write().unwrap() = clients;
+    }
+}
+
+// smoothed round trip time and its variance, the way TCP keeps them (RFC 6298)
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RttEstimator {
+    smoothed: Option<Duration>,
+    variance: Duration,
+}
+
+impl RttEstimator {
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(rtt);
+                self.variance = rtt / 2;
+            }
+            Some(smoothed) => {
+                let error = rtt.abs_diff(smoothed);
+                self.variance = self.variance * 3 / 4 + error / 4;
+                self.smoothed = Some(smoothed * 7 / 8 + rtt / 8);
+            }
+        }
+    }
+
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+
+    pub fn variance(&self) -> Duration {
+        self.variance
+    }
+
+    pub fn retransmit_timeout(&self) -> Duration {
+        match self.smoothed {
+            Some(smoothed) => (smoothed + self.variance * 4)
+                .max(MIN_RETRANSMIT_TIMEOUT)
+                .min(MAX_RETRANSMIT_TIMEOUT),
+            None => INITIAL_RETRANSMIT_TIMEOUT,
+        }
+    }
+}
+
+// a moving average of how many transmissions were lost
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LossEstimator {
+    loss: f64,
+}
+
+impl LossEstimator {
+    pub fn record(&mut self, lost: bool) {
+        let sample = if lost { 1.0 } else { 0.0 };
+        self.loss += (sample - self.loss) * LOSS_WEIGHT;
+    }
+
+    pub fn loss(&self) -> f64 {
+        self.loss
+    }
+}
+
+// bytes and packets going one way, and how fast lately
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Meter {
+    bytes: u64,
+    packets: u64,
+    window_start: Option<instant::Instant>,
+    window_bytes: u64,
+    // over the last full window
+    rate: f64,
+}
+
+impl Meter {
+    pub fn record(&mut self, bytes: usize) {
+        self.record_at(bytes, instant::Instant::now());
+    }
+
+    fn record_at(&mut self, bytes: usize, now: instant::Instant) {
+        self.bytes += bytes as u64;
+        self.packets += 1;
+        let start = *self.window_start.get_or_insert(now);
+        let elapsed = now.duration_since(start);
+        if elapsed >= RATE_WINDOW {
+            self.rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+        }
+        self.window_bytes += bytes as u64;
+    }
+
+    // bytes per second. a window that has run long enough counts before it is rolled over, so
+    // the rate falls off once traffic stops
+    fn rate_at(&self, now: instant::Instant) -> f64 {
+        match self.window_start {
+            Some(start) if now.duration_since(start) >= RATE_WINDOW => {
+                self.window_bytes as f64 / now.duration_since(start).as_secs_f64()
+            }
+            _ => self.rate,
+        }
+    }
+}
+
+// everything measured about one connection
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Traffic {
+    pub sent: Meter,
+    pub received: Meter,
+    // timed from acks, to know how long to wait for the next ones
+    pub rtt: RttEstimator,
+    pub loss: LossEstimator,
+}
+
+impl Traffic {
+    pub fn stats(&self) -> ConnectionStats {
+        let now = instant::Instant::now();
+        ConnectionStats {
+            rtt: self.rtt.smoothed(),
+            rtt_variance: self.rtt.variance(),
+            retransmit_timeout: self.rtt.retransmit_timeout(),
+            loss: self.loss.loss(),
+            bytes_sent: self.sent.bytes,
+            bytes_received: self.received.bytes,
+            packets_sent: self.sent.packets,
+            packets_received: self.received.packets,
+            bytes_sent_per_second: self.sent.rate_at(now),
+            bytes_received_per_second: self.received.rate_at(now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtt_converges_and_bounds_timeouts() {
+        let mut rtt = RttEstimator::default();
+        assert_eq!(rtt.retransmit_timeout(), INITIAL_RETRANSMIT_TIMEOUT);
+        rtt.sample(Duration::from_millis(100));
+        assert_eq!(rtt.smoothed(), Some(Duration::from_millis(100)));
+        assert_eq!(rtt.retransmit_timeout(), Duration::from_millis(300));
+        for _ in 0..100 {
+            rtt.sample(Duration::from_millis(40));
+        }
+        let smoothed = rtt.smoothed().unwrap();
+        assert!(smoothed > Duration::from_millis(39) && smoothed < Duration::from_millis(41));
+        assert!(rtt.variance() < Duration::from_millis(1));
+        assert_eq!(rtt.retransmit_timeout(), MIN_RETRANSMIT_TIMEOUT);
+
+        rtt.sample(Duration::from_secs(30));
+        assert_eq!(rtt.retransmit_timeout(), MAX_RETRANSMIT_TIMEOUT);
+    }
+
+    #[test]
+    fn loss_follows_recent_samples() {
+        let mut loss = LossEstimator::default();
+        for index in 0..1000 {
+            loss.record(index % 4 == 0);
+        }
+        assert!((loss.loss() - 0.25).abs() < 0.05);
+        for _ in 0..1000 {
+            loss.record(false);
+        }
+        assert!(loss.loss() < 0.01);
+    }
+
+    #[test]
+    fn rates_cover_the_last_window() {
+        let start = instant::Instant::now();
+        let mut meter = Meter::default();
+        for tick in 0..20 {
+            meter.record_at(100, start + Duration::from_millis(100 * tick));
+        }
+        assert_eq!(meter.bytes, 2000);
+        assert_eq!(meter.packets, 20);
+        let rate = meter.rate_at(start + Duration::from_millis(1950));
+        assert!((rate - 1000.0).abs() < 1.0, "{}", rate);
+        // nothing since, so it falls off
+        assert!(meter.rate_at(start + Duration::from_secs(10)) < 200.0);
+    }
+}
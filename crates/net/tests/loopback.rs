@@ -0,0 +1,577 @@
+use std::time::Duration;
+
+use futures::FutureExt;
+use gnet::{
+    channel::{ChannelConfig, Channels},
+    client::{Channel, Client},
+    loopback::{LinkConfig, Loopback},
+    protocol::ClientId,
+    protocol::Delivery,
+    protocol::DisconnectReason,
+    server::{ConnectionEvent, Outgoing, Recipients, Server},
+    stats::ServerStats,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+enum ToServer {
+    Hello(u32),
+    Blob(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+enum ToClient {
+    Welcome(u32),
+    Blob(Vec<u8>),
+}
+
+struct Harness {
+    client: Client<ToServer, ToClient>,
+    client_id: ClientId,
+    outgoing_tx: mpsc::UnboundedSender<Outgoing<ToClient>>,
+    incoming_rx: mpsc::UnboundedReceiver<(ClientId, ToServer)>,
+    connection_rx: mpsc::UnboundedReceiver<ConnectionEvent>,
+    stats: ServerStats,
+    // the server stops when these go
+    _broadcast_tx: mpsc::UnboundedSender<ToClient>,
+}
+
+// a server on a loopback network with `config`, and a client done with the handshake
+async fn connect(config: LinkConfig) -> Harness {
+    connect_with(config, Channels::default()).await
+}
+
+// the same, with the server sending on `channels`
+async fn connect_with(config: LinkConfig, channels: Channels) -> Harness {
+    let network = Loopback::new(config);
+    let (broadcast_tx, broadcast_rx) = mpsc::unbounded_channel();
+    let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+    let (connection_tx, mut connection_rx) = mpsc::unbounded_channel();
+    let mut server = Server::<ToClient, ToServer>::loopback(
+        &network,
+        broadcast_rx,
+        outgoing_rx,
+        incoming_tx,
+        connection_tx,
+    )
+    .with_channels(channels);
+    let stats = server.stats();
+    tokio::spawn(async move { server.listen().await });
+
+    let client = Client::<ToServer, ToClient>::new();
+    client.connect_loopback(&network).await.unwrap();
+    let connected = until(&client, || connection_rx.recv().now_or_never().flatten()).await;
+    let client_id = match connected {
+        ConnectionEvent::Connected(client_id) => client_id,
+        event => panic!("unexpected {:?}", event),
+    };
+    Harness {
+        client,
+        client_id,
+        outgoing_tx,
+        incoming_rx,
+        connection_rx,
+        stats,
+        _broadcast_tx: broadcast_tx,
+    }
+}
+
+// processes the client until `done` returns something
+async fn until<T>(client: &Client<ToServer, ToClient>, mut done: impl FnMut() -> Option<T>) -> T {
+    let wait = async {
+        loop {
+            client.process();
+            if let Some(value) = done() {
+                return value;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(20), wait)
+        .await
+        .expect("timed out")
+}
+
+fn blob(length: usize) -> Vec<u8> {
+    (0..length).map(|index| (index * 13) as u8).collect()
+}
+
+fn lossy(loss: f64, seed: u64) -> LinkConfig {
+    LinkConfig {
+        latency: Duration::from_millis(5),
+        jitter: Duration::from_millis(10),
+        loss,
+        duplication: 0.1,
+        reordering: 0.1,
+        seed,
+    }
+}
+
+#[tokio::test]
+async fn perfect_links() {
+    let Harness {
+        client,
+        client_id,
+        outgoing_tx,
+        mut incoming_rx,
+        mut connection_rx,
+        _broadcast_tx,
+        ..
+    } = connect(LinkConfig::default()).await;
+    client.send_reliable(ToServer::Hello(1)).unwrap();
+    let (from, packet) = until(&client, || incoming_rx.recv().now_or_never().flatten()).await;
+    assert_eq!(from, client_id);
+    assert_eq!(packet, ToServer::Hello(1));
+
+    outgoing_tx
+        .send(Outgoing::unreliable(
+            Recipients::One(client_id),
+            ToClient::Welcome(2),
+        ))
+        .unwrap();
+    let received = until(&client, || client.poll().pop()).await;
+    assert_eq!(received.packet, ToClient::Welcome(2));
+    assert_eq!(received.channel, Channel::Unreliable);
+
+    drop(client);
+    let disconnected = tokio::time::timeout(Duration::from_secs(5), connection_rx.recv())
+        .await
+        .unwrap();
+    assert_eq!(
+        disconnected,
+        Some(ConnectionEvent::Disconnected(
+            client_id,
+            DisconnectReason::Closed
+        ))
+    );
+}
+
+#[tokio::test]
+async fn reliable_packets_survive_loss() {
+    let Harness {
+        client,
+        client_id,
+        outgoing_tx,
+        mut incoming_rx,
+        _broadcast_tx,
+        ..
+    } = connect(lossy(0.3, 1)).await;
+    for index in 0..20 {
+        client.send_reliable(ToServer::Hello(index)).unwrap();
+        outgoing_tx
+            .send(Outgoing::reliable(
+                Recipients::One(client_id),
+                ToClient::Welcome(index),
+            ))
+            .unwrap();
+    }
+
+    // in any order, but each once
+    let mut to_server = Vec::new();
+    let mut to_client = Vec::new();
+    until(&client, || {
+        while let Some(Some((_, ToServer::Hello(index)))) = incoming_rx.recv().now_or_never() {
+            to_server.push(index);
+        }
+        for received in client.poll() {
+            if let ToClient::Welcome(index) = received.packet {
+                to_client.push(index);
+            }
+        }
+        Some(()).filter(|_| to_server.len() >= 20 && to_client.len() >= 20)
+    })
+    .await;
+    to_server.sort_unstable();
+    to_client.sort_unstable();
+    assert_eq!(to_server, (0..20).collect::<Vec<_>>());
+    assert_eq!(to_client, (0..20).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn ordered_packets_arrive_in_order() {
+    let Harness {
+        client,
+        client_id,
+        outgoing_tx,
+        mut incoming_rx,
+        _broadcast_tx,
+        ..
+    } = connect(lossy(0.3, 3)).await;
+    for index in 0..50 {
+        client
+            .send(Delivery::ReliableOrdered, ToServer::Hello(index))
+            .unwrap();
+        outgoing_tx
+            .send(Outgoing::reliable_ordered(
+                Recipients::One(client_id),
+                ToClient::Welcome(index),
+            ))
+            .unwrap();
+    }
+
+    let mut to_server = Vec::new();
+    let mut to_client = Vec::new();
+    until(&client, || {
+        while let Some(Some((_, ToServer::Hello(index)))) = incoming_rx.recv().now_or_never() {
+            to_server.push(index);
+        }
+        for received in client.poll() {
+            if let ToClient::Welcome(index) = received.packet {
+                to_client.push(index);
+            }
+        }
+        Some(()).filter(|_| to_server.len() >= 50 && to_client.len() >= 50)
+    })
+    .await;
+    assert_eq!(to_server, (0..50).collect::<Vec<_>>());
+    assert_eq!(to_client, (0..50).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn sequenced_packets_never_go_back() {
+    let Harness {
+        client,
+        client_id,
+        outgoing_tx,
+        mut incoming_rx,
+        _broadcast_tx,
+        ..
+    } = connect(LinkConfig {
+        latency: Duration::from_millis(5),
+        jitter: Duration::from_millis(20),
+        loss: 0.1,
+        duplication: 0.2,
+        reordering: 0.5,
+        seed: 4,
+    })
+    .await;
+    for index in 0..100 {
+        client
+            .send(Delivery::UnreliableSequenced, ToServer::Hello(index))
+            .unwrap();
+        outgoing_tx
+            .send(Outgoing::unreliable_sequenced(
+                Recipients::One(client_id),
+                ToClient::Welcome(index),
+            ))
+            .unwrap();
+    }
+
+    // some are lost, and there's no telling which, so give them all time to land
+    let mut to_server = Vec::new();
+    let mut to_client = Vec::new();
+    for _ in 0..50 {
+        client.process();
+        while let Some(Some((_, ToServer::Hello(index)))) = incoming_rx.recv().now_or_never() {
+            to_server.push(index);
+        }
+        for received in client.poll() {
+            if let ToClient::Welcome(index) = received.packet {
+                to_client.push(index);
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    for received in [&to_server, &to_client] {
+        assert!(!received.is_empty());
+        assert!(
+            received.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            received
+        );
+    }
+}
+
+#[tokio::test]
+async fn redundant_packets_ride_out_loss() {
+    let Harness {
+        client,
+        mut incoming_rx,
+        _broadcast_tx,
+        ..
+    } = connect(LinkConfig {
+        latency: Duration::from_millis(5),
+        loss: 0.2,
+        seed: 5,
+        ..LinkConfig::default()
+    })
+    .await;
+    // one a tick, like inputs
+    let mut to_server = Vec::new();
+    for index in 0..110 {
+        if index < 100 {
+            client
+                .send(Delivery::UnreliableRedundant, ToServer::Hello(index))
+                .unwrap();
+        }
+        client.process();
+        while let Some(Some((_, ToServer::Hello(index)))) = incoming_rx.recv().now_or_never() {
+            to_server.push(index);
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // only runs of lost datagrams as long as the redundancy lose packets
+    assert!(to_server.len() >= 90, "{:?}", to_server);
+    assert!(
+        to_server.windows(2).all(|pair| pair[0] < pair[1]),
+        "{:?}",
+        to_server
+    );
+}
+
+#[tokio::test]
+async fn higher_priority_channels_go_first() {
+    let mut channels = Channels::default();
+    channels.bandwidth = 20_000;
+    let bulk = channels.add("bulk", ChannelConfig::new(Delivery::Reliable));
+    let urgent = channels.add(
+        "urgent",
+        ChannelConfig {
+            priority: 1,
+            ..ChannelConfig::new(Delivery::Reliable)
+        },
+    );
+    let Harness {
+        client,
+        client_id,
+        outgoing_tx,
+        _broadcast_tx,
+        ..
+    } = connect_with(LinkConfig::default(), channels).await;
+    for _ in 0..20 {
+        outgoing_tx
+            .send(Outgoing::new(
+                Recipients::One(client_id),
+                bulk,
+                ToClient::Blob(blob(500)),
+            ))
+            .unwrap();
+    }
+    outgoing_tx
+        .send(Outgoing::new(
+            Recipients::One(client_id),
+            urgent,
+            ToClient::Welcome(1),
+        ))
+        .unwrap();
+
+    // the bulk packets take about half a second to fit in the bandwidth
+    let mut received = Vec::new();
+    until(&client, || {
+        received.extend(client.poll().into_iter().map(|received| received.packet));
+        Some(()).filter(|_| received.len() >= 21)
+    })
+    .await;
+    assert_eq!(received[0], ToClient::Welcome(1));
+}
+
+#[tokio::test]
+async fn small_packets_share_datagrams() {
+    let Harness {
+        client,
+        mut incoming_rx,
+        _broadcast_tx,
+        ..
+    } = connect(LinkConfig::default()).await;
+    let before = client.stats().packets_sent;
+    for index in 0..50 {
+        client.send_reliable(ToServer::Hello(index)).unwrap();
+    }
+    client.process();
+    // a pong or an ack may have gone along
+    let sent = client.stats().packets_sent - before;
+    assert!(sent <= 3, "{}", sent);
+
+    let mut to_server = Vec::new();
+    until(&client, || {
+        while let Some(Some((_, ToServer::Hello(index)))) = incoming_rx.recv().now_or_never() {
+            to_server.push(index);
+        }
+        Some(()).filter(|_| to_server.len() >= 50)
+    })
+    .await;
+    to_server.sort_unstable();
+    assert_eq!(to_server, (0..50).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn fragmented_packets_survive_loss() {
+    let Harness {
+        client,
+        client_id,
+        outgoing_tx,
+        mut incoming_rx,
+        _broadcast_tx,
+        ..
+    } = connect(lossy(0.05, 2)).await;
+    client.send_reliable(ToServer::Blob(blob(5000))).unwrap();
+    let (_, packet) = until(&client, || incoming_rx.recv().now_or_never().flatten()).await;
+    assert_eq!(packet, ToServer::Blob(blob(5000)));
+
+    outgoing_tx
+        .send(Outgoing::reliable(
+            Recipients::One(client_id),
+            ToClient::Blob(blob(7000)),
+        ))
+        .unwrap();
+    let received = until(&client, || client.poll().pop()).await;
+    assert_eq!(received.packet, ToClient::Blob(blob(7000)));
+}
+
+#[tokio::test]
+async fn clients_say_goodbye() {
+    let Harness {
+        client,
+        client_id,
+        mut connection_rx,
+        _broadcast_tx,
+        ..
+    } = connect(LinkConfig::default()).await;
+    client.disconnect();
+    assert_eq!(client.disconnected(), Some(DisconnectReason::Requested));
+    let disconnected = tokio::time::timeout(Duration::from_secs(5), connection_rx.recv())
+        .await
+        .unwrap();
+    assert_eq!(
+        disconnected,
+        Some(ConnectionEvent::Disconnected(
+            client_id,
+            DisconnectReason::Requested
+        ))
+    );
+}
+
+#[tokio::test]
+async fn connecting_without_a_server_fails() {
+    let network = Loopback::new(LinkConfig::default());
+    let client = Client::<ToServer, ToClient>::new();
+    assert!(client.connect_loopback(&network).await.is_err());
+}
+
+#[tokio::test]
+async fn clients_built_differently_give_up() {
+    // what the server sends, as a client built from other sources thinks it looks
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    enum OtherToClient {
+        Welcome(u64),
+        Blob(Vec<u8>),
+    }
+
+    let network = Loopback::new(LinkConfig::default());
+    let (_broadcast_tx, broadcast_rx) = mpsc::unbounded_channel();
+    let (_outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+    let (incoming_tx, _incoming_rx) = mpsc::unbounded_channel();
+    let (connection_tx, mut connection_rx) = mpsc::unbounded_channel();
+    let mut server = Server::<ToClient, ToServer>::loopback(
+        &network,
+        broadcast_rx,
+        outgoing_rx,
+        incoming_tx,
+        connection_tx,
+    );
+    tokio::spawn(async move { server.listen().await });
+
+    let client = Client::<ToServer, OtherToClient>::new();
+    client.connect_loopback(&network).await.unwrap();
+    let reason = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            client.process();
+            if let Some(reason) = client.disconnected() {
+                return reason;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(
+        matches!(reason, DisconnectReason::SchemaMismatch { .. }),
+        "{:?}",
+        reason
+    );
+    // it never counted as connected
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(connection_rx.recv().now_or_never().is_none());
+}
+
+#[tokio::test]
+async fn silent_clients_time_out() {
+    let Harness {
+        client,
+        client_id,
+        mut connection_rx,
+        _broadcast_tx,
+        ..
+    } = connect(LinkConfig::default()).await;
+    // without processing, pings go unanswered
+    let disconnected = tokio::time::timeout(Duration::from_secs(15), connection_rx.recv())
+        .await
+        .unwrap();
+    assert_eq!(
+        disconnected,
+        Some(ConnectionEvent::Disconnected(
+            client_id,
+            DisconnectReason::TimedOut
+        ))
+    );
+    // the server says why
+    let reason = until(&client, || client.disconnected()).await;
+    assert_eq!(reason, DisconnectReason::TimedOut);
+}
+
+#[tokio::test]
+async fn both_sides_measure_the_connection() {
+    let Harness {
+        client,
+        client_id,
+        outgoing_tx,
+        mut incoming_rx,
+        stats,
+        _broadcast_tx,
+        ..
+    } = connect(LinkConfig {
+        latency: Duration::from_millis(20),
+        ..LinkConfig::default()
+    })
+    .await;
+    for index in 0..10 {
+        client.send_reliable(ToServer::Hello(index)).unwrap();
+        outgoing_tx
+            .send(Outgoing::reliable(
+                Recipients::One(client_id),
+                ToClient::Welcome(index),
+            ))
+            .unwrap();
+    }
+    let mut received = 0;
+    until(&client, || {
+        while let Some(Some(_)) = incoming_rx.recv().now_or_never() {
+            received += 1;
+        }
+        Some(()).filter(|_| received >= 10 && client.stats().rtt.is_some())
+    })
+    .await;
+
+    // a datagram each way, and up to a call to `process` before the client acks
+    let in_range = |rtt: Option<Duration>| {
+        let rtt = rtt.unwrap();
+        rtt >= Duration::from_millis(40) && rtt < Duration::from_millis(200)
+    };
+    let client_stats = client.stats();
+    assert!(in_range(client_stats.rtt), "{:?}", client_stats);
+    assert!(client_stats.bytes_sent > 0 && client_stats.bytes_received > 0);
+    // batched, so there may be fewer datagrams than packets
+    assert!(client_stats.packets_sent > 0);
+
+    // published with the next heartbeat
+    let server_stats = until(&client, || {
+        stats.get(client_id).filter(|stats| stats.rtt.is_some())
+    })
+    .await;
+    assert!(in_range(server_stats.rtt), "{:?}", server_stats);
+    assert!(server_stats.packets_received > 0);
+    let clients = stats.all().into_iter().map(|(client_id, _)| client_id);
+    assert_eq!(clients.collect::<Vec<_>>(), vec![client_id]);
+}
@@ -0,0 +1,93 @@
+use std::{net::SocketAddr, time::Duration};
+
+use futures::FutureExt;
+use gnet::{
+    client::{Channel, Client},
+    server::{ConnectionEvent, Outgoing, Recipients, Server, ServerConfig},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+enum ToServer {
+    Hello(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+enum ToClient {
+    Welcome(u32),
+}
+
+fn addr(port: u16) -> SocketAddr {
+    ([127, 0, 0, 1], port).into()
+}
+
+// processes the client until `done` returns something
+async fn until<T>(client: &Client<ToServer, ToClient>, mut done: impl FnMut() -> Option<T>) -> T {
+    let wait = async {
+        loop {
+            client.process();
+            if let Some(value) = done() {
+                return value;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), wait)
+        .await
+        .expect("timed out")
+}
+
+#[tokio::test]
+async fn native_client_talks_to_the_server() {
+    let (_broadcast_tx, broadcast_rx) = mpsc::unbounded_channel();
+    let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+    let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel();
+    let (connection_tx, mut connection_rx) = mpsc::unbounded_channel();
+    let mut server = Server::<ToClient, ToServer>::new(
+        ServerConfig {
+            http_listen_addr: addr(47301),
+            webrtc_listen_addr: addr(47302),
+            webrtc_public_addr: addr(47302),
+            udp_listen_addr: Some(addr(47303)),
+            udp_public_addr: Some(addr(47303)),
+        },
+        broadcast_rx,
+        outgoing_rx,
+        incoming_tx,
+        connection_tx,
+    )
+    .await;
+    tokio::spawn(async move { server.listen().await });
+    // give warp a moment to bind
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = Client::<ToServer, ToClient>::new();
+    client.connect(addr(47301)).await.unwrap();
+    let connected = until(&client, || connection_rx.recv().now_or_never().flatten()).await;
+    let client_id = match connected {
+        ConnectionEvent::Connected(client_id) => client_id,
+        event => panic!("unexpected {:?}", event),
+    };
+
+    client.send_reliable(ToServer::Hello(7)).unwrap();
+    let (from, packet) = until(&client, || incoming_rx.recv().now_or_never().flatten()).await;
+    assert_eq!(from, client_id);
+    assert_eq!(packet, ToServer::Hello(7));
+
+    outgoing_tx
+        .send(Outgoing::reliable(
+            Recipients::One(client_id),
+            ToClient::Welcome(8),
+        ))
+        .unwrap();
+    let received = until(&client, || client.poll().pop()).await;
+    assert_eq!(received.packet, ToClient::Welcome(8));
+    assert_eq!(received.channel, Channel::Reliable);
+}
+
+#[tokio::test]
+async fn connecting_to_nothing_fails() {
+    let client = Client::<ToServer, ToClient>::new();
+    assert!(client.connect(addr(47302)).await.is_err());
+}
@@ -0,0 +1,57 @@
+use bevy_tasks::TaskPool;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use game_common::{material::Materials, rng::Seed, world::Tick};
+use game_server::world::{advance, Cells};
+
+const SIZE: u32 = 2048;
+
+// the bottom half is a mix of materials so every chunk is busy
+fn world(materials: &Materials) -> Cells {
+    let names = ["empty", "sand", "water", "oil", "steam"];
+    let mut cells = Cells::new(SIZE, SIZE);
+    for y in 0..SIZE / 2 {
+        for x in 0..SIZE {
+            let hash = x.wrapping_mul(7919) ^ y.wrapping_mul(104_729);
+            let material = materials.id(names[hash as usize % names.len()]).unwrap();
+            cells.set_at(x, y, materials.cell(material));
+        }
+    }
+    // settle into a state where every chunk is awake
+    advance(&mut cells, materials, Seed::default(), Tick(0), None);
+    cells
+}
+
+fn advance_world(c: &mut Criterion) {
+    let materials = Materials::builtin();
+    let cells = world(&materials);
+    let pool = TaskPool::new();
+
+    let mut group = c.benchmark_group("advance 2048x2048");
+    group.sample_size(20);
+    group.bench_function("serial", |b| {
+        b.iter_batched(
+            || cells.clone(),
+            |mut cells| advance(&mut cells, &materials, Seed::default(), Tick(1), None),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("parallel", |b| {
+        b.iter_batched(
+            || cells.clone(),
+            |mut cells| {
+                advance(
+                    &mut cells,
+                    &materials,
+                    Seed::default(),
+                    Tick(1),
+                    Some(&pool),
+                )
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, advance_world);
+criterion_main!(benches);
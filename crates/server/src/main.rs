@@ -1,14 +1,29 @@
 mod net;
-mod world;
+
+use std::net::SocketAddr;
 
 use bevy_ecs::prelude::*;
 use clap::Arg;
-use game_common::{app::App, world::Tick, ClientPacket, ServerPacket};
-use gnet::protocol::ClientId;
+use futures::FutureExt;
+use game_common::{
+    app::App,
+    events::{EventReader, EventWriter},
+    material::Materials,
+    rng::Seed,
+    world::{FlyingCell, Tick},
+    ClientPacket, ServerPacket,
+};
+use game_server::world::{
+    paint::{Paint, PaintBudgets, Painter},
+    replication, Cells, Particle, Position, WorldPlugin, WorldSystem,
+};
+use gnet::{
+    protocol::ClientId,
+    server::{ConnectionEvent, Outgoing, Recipients},
+    stats::ServerStats,
+};
 use tokio::{sync::mpsc, task::JoinHandle};
-use tracing::{debug, event, info, span, trace, Level};
-
-use crate::world::WorldPlugin;
+use tracing::{debug, event, info, span, trace, warn, Level};
 
 // #[tokio::main(flavor = "current_thread")]
 #[tokio::main]
@@ -45,6 +60,24 @@ async fn main() -> anyhow::Result<()> {
                 .required(true)
                 .help("listen on the specified address/port for incoming HTTP (session reqeusts and test page"),
         )
+        .arg(
+            Arg::with_name("udp")
+                .long("udp")
+                .takes_value(true)
+                .help("listen on the specified address/port for plain UDP datagrams from native clients"),
+        )
+        .arg(
+            Arg::with_name("materials")
+                .long("materials")
+                .takes_value(true)
+                .help("load material definitions from the given RON file instead of the builtin ones"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .help("seed the cell simulation with the given number"),
+        )
         .get_matches();
 
     let webrtc_listen_addr = matches
@@ -53,7 +86,7 @@ async fn main() -> anyhow::Result<()> {
         .parse()
         .expect("could not parse WebRTC data address/port");
 
-    let webrtc_public_addr = matches
+    let webrtc_public_addr: SocketAddr = matches
         .value_of("public")
         .unwrap()
         .parse()
@@ -65,12 +98,37 @@ async fn main() -> anyhow::Result<()> {
         .parse()
         .expect("could not parse HTTP address/port");
 
+    // advertised on the same host as WebRTC
+    let udp_listen_addr: Option<SocketAddr> = matches
+        .value_of("udp")
+        .map(|addr| addr.parse().expect("could not parse UDP address/port"));
+    let udp_public_addr =
+        udp_listen_addr.map(|addr| SocketAddr::new(webrtc_public_addr.ip(), addr.port()));
+
+    let materials = match matches.value_of("materials") {
+        Some(path) => Materials::load(path)?,
+        None => Materials::builtin(),
+    };
+
+    let seed = match matches.value_of("seed") {
+        Some(seed) => Seed(seed.parse().expect("could not parse seed")),
+        None => Seed::default(),
+    };
+
     let (server_broadcast_tx, server_broadcast_rx) = mpsc::unbounded_channel();
     let (server_tx, server_tx_rx) = mpsc::unbounded_channel();
     let (server_rx_tx, server_rx) = mpsc::unbounded_channel();
+    let (connection_tx, connection_rx) = mpsc::unbounded_channel();
 
     let gameloop = tokio::spawn(async move {
-        let mut app = setup_ecs(server_broadcast_tx, server_tx, server_rx);
+        let mut app = setup_ecs(
+            materials,
+            seed,
+            server_broadcast_tx,
+            server_tx,
+            server_rx,
+            connection_rx,
+        );
         debug!("starting game loop");
         tick(move || {
             app.update();
@@ -84,12 +142,16 @@ async fn main() -> anyhow::Result<()> {
                 http_listen_addr: session_listen_addr,
                 webrtc_listen_addr,
                 webrtc_public_addr,
+                udp_listen_addr,
+                udp_public_addr,
             },
             server_broadcast_rx,
             server_tx_rx,
             server_rx_tx,
+            connection_tx,
         )
         .await;
+        tokio::spawn(log_stats(server.stats()));
         server.listen().await;
         // debug!("starting server");
         // server
@@ -110,6 +172,24 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+// every client's connection stats, now and then
+async fn log_stats(stats: ServerStats) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        for (client, stats) in stats.all() {
+            info!(
+                ?client,
+                rtt = ?stats.rtt,
+                loss = ?stats.loss,
+                sent_per_second = ?stats.bytes_sent_per_second,
+                received_per_second = ?stats.bytes_received_per_second,
+                "connection stats"
+            );
+        }
+    }
+}
+
 async fn tick<U>(mut update: U)
 where
     U: FnMut(),
@@ -138,9 +218,12 @@ where
 }
 
 fn setup_ecs(
+    materials: Materials,
+    seed: Seed,
     server_broadcast_tx: mpsc::UnboundedSender<ServerPacket>,
-    server_tx: mpsc::UnboundedSender<(ClientId, ServerPacket)>,
+    server_tx: mpsc::UnboundedSender<Outgoing<ServerPacket>>,
     server_rx: mpsc::UnboundedReceiver<(ClientId, ClientPacket)>,
+    connection_rx: mpsc::UnboundedReceiver<ConnectionEvent>,
 ) -> App {
     debug!("setting up ecs");
     App::builder()
@@ -148,11 +231,116 @@ fn setup_ecs(
         .insert_resource(server_broadcast_tx)
         .insert_resource(server_tx)
         .insert_resource(server_rx)
-        .add_plugin(WorldPlugin)
-        .add_system(update_tick.system())
+        .insert_resource(connection_rx)
+        .add_event::<ConnectionEvent>()
+        .add_plugin(WorldPlugin { materials, seed })
+        // the world advances and replicates as of this tick, so it goes first
+        .add_system(
+            update_tick
+                .system()
+                .label(ServerSystem::Tick)
+                .before(WorldSystem::Paint),
+        )
+        .add_system(receive_packets.system().after(ServerSystem::Tick))
+        .add_system(send_particles.system().after(WorldSystem::Particles))
+        .add_system(receive_connections.system())
+        .add_system(forget_painters.system())
         .build()
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+enum ServerSystem {
+    Tick,
+}
+
+fn receive_connections(
+    mut connection_rx: ResMut<mpsc::UnboundedReceiver<ConnectionEvent>>,
+    mut connections: EventWriter<ConnectionEvent>,
+) {
+    while let Some(Some(event)) = connection_rx.recv().now_or_never() {
+        info!(?event, "connection");
+        connections.send(event);
+    }
+}
+
+fn forget_painters(
+    mut connections: EventReader<ConnectionEvent>,
+    mut budgets: ResMut<PaintBudgets>,
+) {
+    for event in connections.iter() {
+        if let ConnectionEvent::Disconnected(client, _) = event {
+            budgets.forget(Painter(client.get()));
+        }
+    }
+}
+
+fn receive_packets(
+    mut server_rx: ResMut<mpsc::UnboundedReceiver<(ClientId, ClientPacket)>>,
+    server_tx: Res<mpsc::UnboundedSender<Outgoing<ServerPacket>>>,
+    cells: Res<Cells>,
+    tick: Res<Tick>,
+    mut paint: EventWriter<Paint>,
+) {
+    while let Some(Some((client, packet))) = server_rx.recv().now_or_never() {
+        match packet {
+            // everything so far, which the broadcast updates build on
+            ClientPacket::Connect() => {
+                for packet in replication::snapshot(&cells, *tick) {
+                    let outgoing = Outgoing::reliable(Recipients::One(client), packet);
+                    if server_tx.send(outgoing).is_err() {
+                        warn!(?client, "failed to send snapshot");
+                    }
+                }
+            }
+            ClientPacket::Paint(stroke) => paint.send(Paint {
+                painter: Painter(client.get()),
+                stroke,
+            }),
+            packet => debug!(?client, "got packet {:?}", packet),
+        }
+    }
+}
+
+// at about 9 bytes each, a packet of particles stays under 20 KiB. an explosion flinging more
+// than that shows the first launched
+const MAX_PARTICLES: usize = 2048;
+
+// particles only live on the server, clients just draw where they are. sequenced, so older
+// positions arriving late are dropped
+fn send_particles(
+    particles: Query<(&Particle, &Position)>,
+    server_tx: Res<mpsc::UnboundedSender<Outgoing<ServerPacket>>>,
+    tick: Res<Tick>,
+    // whether the last packet had any, so clients hear once that they all landed
+    mut flying: Local<bool>,
+) {
+    let mut sorted = particles.iter().collect::<Vec<_>>();
+    if sorted.is_empty() && !*flying {
+        return;
+    }
+    *flying = !sorted.is_empty();
+    sorted.sort_by_key(|(particle, _)| particle.id);
+    let particles = sorted
+        .into_iter()
+        .take(MAX_PARTICLES)
+        .map(|(particle, position)| FlyingCell {
+            x: position.0.x,
+            y: position.0.y,
+            material: particle.cell.material,
+        })
+        .collect();
+    let packet = ServerPacket::Particles {
+        tick: *tick,
+        particles,
+    };
+    if server_tx
+        .send(Outgoing::unreliable_sequenced(Recipients::All, packet))
+        .is_err()
+    {
+        warn!("failed to send particles");
+    }
+}
+
 fn update_tick(mut tick: ResMut<Tick>) {
     trace!("server tick");
     tick.increment_self();
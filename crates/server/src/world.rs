@@ -1,19 +1,48 @@
+mod chunk;
+mod explosion;
+mod fire;
+mod heat;
+mod movement;
+pub mod paint;
+mod particle;
+mod reaction;
+pub mod replication;
+
 use bevy_ecs::prelude::*;
+use bevy_tasks::{ComputeTaskPool, TaskPool};
 use game_common::{
     app::{AppBuilder, Plugin},
-    world::Cell,
+    events::EventReader,
+    material::Materials,
+    rng::Seed,
+    world::{Cell, ChunkPosition, Tick, WORLD_HEIGHT, WORLD_WIDTH},
     ServerPacket,
 };
 use tokio::sync::mpsc;
 use tracing::{debug, warn};
-#[derive(Debug)]
-struct Cells {
-    width: u32,
-    height: u32,
+use ultraviolet::Vec2;
+
+pub use self::particle::{Particle, ParticleBundle, Position, Velocity};
+use self::{
+    chunk::{ChunkCells, ChunkState, Layout, SharedCells, CHUNK_AREA, PASSES},
+    explosion::Detonation,
+    paint::{Paint, PaintBudgets},
+    replication::{Replica, KEYFRAME_INTERVAL},
+};
+
+#[derive(Debug, Clone)]
+pub struct Cells {
+    layout: Layout,
     // double buffering
     cells_a: CellsInner,
     cells_b: CellsInner,
     active: Active,
+    // cells that already moved during the current tick, indexed like `CellsInner::cells`
+    moved: Vec<bool>,
+    chunks: Vec<ChunkState>,
+    // cells flung out of the grid that haven't been handed to the particle layer yet
+    launched: Vec<ParticleBundle>,
+    next_particle: u64,
 }
 
 // which buffer is active
@@ -33,31 +62,35 @@ impl Active {
 }
 
 impl Cells {
-    fn new(width: u32, height: u32) -> Self {
+    pub fn new(width: u32, height: u32) -> Self {
+        let layout = Layout::new(width, height);
         Self {
-            width,
-            height,
-            cells_a: CellsInner::new(width, height),
-            cells_b: CellsInner::new(width, height),
+            layout,
+            cells_a: CellsInner::new(layout),
+            cells_b: CellsInner::new(layout),
             active: Active::A,
+            moved: vec![false; layout.cell_count()],
+            chunks: vec![ChunkState::default(); layout.chunk_count()],
+            launched: Vec::new(),
+            next_particle: 0,
         }
     }
 
-    pub fn current(&self) -> &[Cell] {
-        self.inner_back().cells()
+    // every chunk in the world
+    pub fn chunks(&self) -> impl Iterator<Item = ChunkPosition> {
+        let layout = self.layout;
+        (0..layout.chunk_count()).map(move |index| layout.chunk_position(index))
     }
 
-    fn inner_active(&self) -> &CellsInner {
-        match self.active {
-            Active::A => &self.cells_a,
-            Active::B => &self.cells_b,
-        }
+    // the cells of a chunk as of the last completed tick
+    pub fn current_chunk(&self, chunk: ChunkPosition) -> &[Cell] {
+        self.inner_back().chunk(self.layout.chunk_index_of(chunk))
     }
 
-    fn inner_active_mut(&mut self) -> &mut CellsInner {
+    fn inner_back_mut(&mut self) -> &mut CellsInner {
         match self.active {
-            Active::A => &mut self.cells_a,
-            Active::B => &mut self.cells_b,
+            Active::A => &mut self.cells_b,
+            Active::B => &mut self.cells_a,
         }
     }
 
@@ -68,20 +101,167 @@ impl Cells {
         }
     }
 
-    fn neighborhoods(&self) -> impl Iterator<Item = ((u32, u32), AB<Neighborhood>)> {
-        (0..self.height)
-            .flat_map(move |y| (0..self.width).map(move |x| (x, y)))
-            .filter_map(move |(x, y)| self.neighborhood(x, y).map(|n| ((x, y), n)))
+    // (active, back)
+    fn inner_split_mut(&mut self) -> (&mut CellsInner, &CellsInner) {
+        match self.active {
+            Active::A => (&mut self.cells_a, &self.cells_b),
+            Active::B => (&mut self.cells_b, &self.cells_a),
+        }
     }
 
-    fn set_at(&mut self, x: u32, y: u32, cell: Cell) -> Option<()> {
-        self.inner_active_mut().set_at(x, y, cell)
+    // start a tick. chunks that changed last tick are the only ones where the buffers differ,
+    // so only those are copied into the active buffer
+    fn begin(&mut self) {
+        let changed = self
+            .chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| state.changed)
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        let (active, back) = self.inner_split_mut();
+        for &index in &changed {
+            active.chunk_mut(index).copy_from_slice(back.chunk(index));
+        }
+        for index in changed {
+            let range = index * CHUNK_AREA..(index + 1) * CHUNK_AREA;
+            self.moved[range]
+                .iter_mut()
+                .for_each(|moved| *moved = false);
+        }
+        for state in self.chunks.iter_mut() {
+            state.awake = state.wake;
+            state.wake = false;
+            state.changed = false;
+        }
+    }
+
+    // the cell at (x, y) as of the last completed tick
+    pub fn current_at(&self, x: u32, y: u32) -> Option<Cell> {
+        self.inner_back().cell_at(x, y)
+    }
+
+    // changes the current state, in between ticks
+    pub fn set_at(&mut self, x: u32, y: u32, cell: Cell) -> Option<()> {
+        self.inner_back_mut().set_at(x, y, cell)?;
+        self.touch(x, y);
+        Some(())
+    }
+
+    // the cell at (x, y) in the tick being simulated, once all passes are done
+    fn active_at(&self, x: u32, y: u32) -> Option<Cell> {
+        match self.active {
+            Active::A => self.cells_a.cell_at(x, y),
+            Active::B => self.cells_b.cell_at(x, y),
+        }
     }
 
-    fn neighborhood(&self, center_x: u32, center_y: u32) -> Option<AB<Neighborhood>> {
-        let a = self.cells_a.neighborhood(center_x, center_y)?;
-        let b = self.cells_b.neighborhood(center_x, center_y)?;
-        Some(AB { a, b })
+    fn set_active(&mut self, x: u32, y: u32, cell: Cell) -> Option<()> {
+        self.inner_split_mut().0.set_at(x, y, cell)?;
+        self.touch(x, y);
+        Some(())
+    }
+
+    /// Sends `cell` flying from `position`, in cells, at `velocity`, in cells per tick. It's
+    /// simulated as a particle until it lands back in the grid.
+    pub fn launch(&mut self, cell: Cell, position: Vec2, velocity: Vec2) {
+        self.launched.push(ParticleBundle {
+            particle: Particle {
+                id: self.next_particle,
+                cell,
+            },
+            position: Position(position),
+            velocity: Velocity(velocity),
+        });
+        self.next_particle += 1;
+    }
+
+    /// Particles launched since the last call.
+    pub fn take_launched(&mut self) -> Vec<ParticleBundle> {
+        std::mem::take(&mut self.launched)
+    }
+
+    // run `f` on every awake chunk, in parallel on `pool` if given. the result is the same
+    // either way. returns the detonations in the order they happened
+    fn update_chunks<F>(&mut self, pool: Option<&TaskPool>, f: F) -> Vec<Detonation>
+    where
+        F: Fn(&mut ChunkCells) + Sync,
+    {
+        let layout = self.layout;
+        let mut detonations = Vec::new();
+        for &pass in PASSES.iter() {
+            let chunks = (0..layout.chunk_count())
+                .filter(|&index| self.chunks[index].awake)
+                .map(|index| layout.chunk_position(index))
+                .filter(|&chunk| chunk::in_pass(chunk, pass))
+                .collect::<Vec<_>>();
+            if chunks.is_empty() {
+                continue;
+            }
+            let updates = {
+                let (active, moved) = match self.active {
+                    Active::A => (&mut self.cells_a, &mut self.moved),
+                    Active::B => (&mut self.cells_b, &mut self.moved),
+                };
+                let shared = SharedCells::new(layout, &mut active.cells, moved);
+                let f = &f;
+                let update = move |chunk| {
+                    // safety: every chunk in a pass is updated at most once
+                    let mut cells = unsafe { shared.chunk(chunk) };
+                    f(&mut cells);
+                    cells.finish()
+                };
+                match pool {
+                    Some(pool) => pool.scope(|scope| {
+                        for chunk in chunks {
+                            scope.spawn(async move { update(chunk) });
+                        }
+                    }),
+                    None => chunks.into_iter().map(update).collect(),
+                }
+            };
+            for update in updates {
+                for (x, y) in update.touched {
+                    self.touch(x, y);
+                }
+                if update.stay_awake {
+                    self.chunks[layout.chunk_index_of(update.chunk)].wake = true;
+                }
+                detonations.extend(update.detonations);
+            }
+        }
+        detonations
+    }
+
+    // record a change to the cell at (x, y). anything around it might be able to move now, so
+    // every chunk touching its neighborhood is simulated next tick
+    fn touch(&mut self, x: u32, y: u32) {
+        if let Some(index) = self.layout.chunk_index(x, y) {
+            let state = &mut self.chunks[index];
+            state.changed = true;
+            state.dirty = true;
+        }
+        for (relative_x, relative_y) in NEIGHBORHOOD.iter() {
+            let x = ((x as i64) + relative_x) as u32;
+            let y = ((y as i64) + relative_y) as u32;
+            if let Some(index) = self.layout.chunk_index(x, y) {
+                self.chunks[index].wake = true;
+            }
+        }
+    }
+
+    // chunks modified since the last call
+    fn take_dirty(&mut self) -> Vec<ChunkPosition> {
+        let layout = self.layout;
+        self.chunks
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, state)| state.dirty)
+            .map(|(index, state)| {
+                state.dirty = false;
+                layout.chunk_position(index)
+            })
+            .collect()
     }
 
     pub fn swap(&mut self) {
@@ -89,65 +269,40 @@ impl Cells {
     }
 }
 
-// both cells_a and cells_b values of T
-#[derive(Debug, Copy, Clone)]
-struct AB<T> {
-    a: T,
-    b: T,
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct CellsInner {
-    width: u32,
-    height: u32,
+    layout: Layout,
     cells: Vec<Cell>,
 }
 
 impl CellsInner {
-    fn new(width: u32, height: u32) -> Self {
-        let cells = vec![Cell::Empty; width as usize * height as usize];
-        Self {
-            width,
-            height,
-            cells,
-        }
+    fn new(layout: Layout) -> Self {
+        let cells = vec![Cell::empty(); layout.cell_count()];
+        Self { layout, cells }
     }
 
-    fn cells(&self) -> &[Cell] {
-        &self.cells
+    fn chunk(&self, index: usize) -> &[Cell] {
+        &self.cells[index * CHUNK_AREA..(index + 1) * CHUNK_AREA]
     }
 
-    fn set_at(&mut self, x: u32, y: u32, cell: Cell) -> Option<()> {
-        let index = self.cell_index(x, y)?;
-        self.cells[index] = cell;
-        Some(())
-    }
-
-    fn neighborhood(&self, center_x: u32, center_y: u32) -> Option<Neighborhood> {
-        let mut neighborhood = [Cell::Empty; 9];
-        for (i, (relative_x, relative_y)) in NEIGHBORHOOD.iter().enumerate() {
-            let x = ((center_x as i64) + relative_x) as u32;
-            let y = ((center_y as i64) + relative_y) as u32;
-            neighborhood[i] = self.cell_at(x, y)?;
-        }
-        Some(neighborhood)
+    fn chunk_mut(&mut self, index: usize) -> &mut [Cell] {
+        &mut self.cells[index * CHUNK_AREA..(index + 1) * CHUNK_AREA]
     }
 
     fn cell_at(&self, x: u32, y: u32) -> Option<Cell> {
-        let index = self.cell_index(x, y)?;
-        self.cells.get(index).copied()
+        let index = self.layout.cell_index(x, y)?;
+        Some(self.cells[index])
     }
 
-    fn cell_index(&self, x: u32, y: u32) -> Option<usize> {
-        let x_index = self.height.checked_mul(x)?;
-        let y_index = y;
-        let index = x_index.checked_add(y_index)?;
-        Some(index as usize)
+    fn set_at(&mut self, x: u32, y: u32, cell: Cell) -> Option<()> {
+        let index = self.layout.cell_index(x, y)?;
+        self.cells[index] = cell;
+        Some(())
     }
 }
 
 // [nw, n, ne, w, c, e, sw, s, se]
-type Neighborhood<'a> = [Cell; 9];
+type Neighborhood = [Option<Cell>; 9];
 
 const NEIGHBORHOOD: [(i64, i64); 9] = [
     (-1, 1),
@@ -161,42 +316,568 @@ const NEIGHBORHOOD: [(i64, i64); 9] = [
     (1, -1),
 ];
 
-pub struct WorldPlugin;
+// indices into a `Neighborhood`
+const NW: usize = 0;
+const N: usize = 1;
+const NE: usize = 2;
+const W: usize = 3;
+const C: usize = 4;
+const E: usize = 5;
+const SW: usize = 6;
+const S: usize = 7;
+const SE: usize = 8;
+
+// every cell interacts with its north and east neighbors. the other directions are covered by
+// the neighbors themselves, so each pair of adjacent cells interacts once per tick
+const INTERACTIONS: [usize; 2] = [N, E];
+
+pub struct WorldPlugin {
+    pub materials: Materials,
+    pub seed: Seed,
+}
 
 impl Plugin for WorldPlugin {
     fn build(&mut self, app: AppBuilder) -> AppBuilder {
-        app.insert_resource(Cells::new(1024, 1024))
-            .add_system(advance_cells.system())
-            .add_system(send_state.system())
+        app.insert_resource(self.materials.clone())
+            .insert_resource(self.seed)
+            .insert_resource(Cells::new(WORLD_WIDTH, WORLD_HEIGHT))
+            .insert_resource(PaintBudgets::default())
+            .insert_resource(Replica::default())
+            .add_event::<Paint>()
+            .add_system(apply_paint.system().label(WorldSystem::Paint))
+            .add_system(
+                advance_cells
+                    .system()
+                    .label(WorldSystem::Advance)
+                    .after(WorldSystem::Paint),
+            )
+            .add_system(
+                advance_particles
+                    .system()
+                    .label(WorldSystem::Particles)
+                    .after(WorldSystem::Advance),
+            )
+            .add_system(send_state.system().after(WorldSystem::Particles))
     }
 }
 
-#[derive(Debug, Clone)]
-enum CellChange {
-    Set { x: u32, y: u32, cell: Cell },
+/// The world's systems, in the order they run each tick. Systems the world depends on, like the
+/// one advancing the tick, go before `Paint`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum WorldSystem {
+    Paint,
+    Advance,
+    Particles,
 }
 
-fn send_state(cells: Res<Cells>, broadcast: Res<mpsc::UnboundedSender<ServerPacket>>) {
-    if let Err(_) = broadcast.send(ServerPacket::UpdateCells { cells: vec![] }) {
-        warn!("failed to send");
+fn send_state(
+    mut cells: ResMut<Cells>,
+    mut replica: ResMut<Replica>,
+    tick: Res<Tick>,
+    broadcast: Res<mpsc::UnboundedSender<ServerPacket>>,
+) {
+    let dirty = cells.take_dirty();
+    let packets = if tick.0.is_multiple_of(KEYFRAME_INTERVAL) {
+        replica.keyframe(&cells, *tick)
+    } else {
+        replica.update(&cells, dirty, *tick).into_iter().collect()
+    };
+    for packet in packets {
+        if broadcast.send(packet).is_err() {
+            warn!("failed to send");
+        }
     }
 }
 
-fn advance_cells(mut cells: ResMut<Cells>) {
-    let changes = cells
-        .neighborhoods()
-        .map(|(position, _neighborhood)| CellChange::Set {
-            x: position.0,
-            y: position.1,
-            cell: Cell::Stone,
-        })
-        .collect::<Vec<_>>();
-    for change in changes {
-        match change {
-            CellChange::Set { x, y, cell } => {
-                cells.set_at(x, y, cell);
-            }
+fn apply_paint(
+    mut cells: ResMut<Cells>,
+    materials: Res<Materials>,
+    mut budgets: ResMut<PaintBudgets>,
+    seed: Res<Seed>,
+    tick: Res<Tick>,
+    mut strokes: EventReader<Paint>,
+) {
+    for stroke in strokes.iter() {
+        if let Err(error) = paint::paint(&mut cells, &materials, &mut budgets, *seed, *tick, stroke)
+        {
+            debug!(painter = ?stroke.painter, "rejected stroke: {}", error);
+        }
+    }
+}
+
+fn advance_cells(
+    mut cells: ResMut<Cells>,
+    materials: Res<Materials>,
+    seed: Res<Seed>,
+    tick: Res<Tick>,
+    pool: Res<ComputeTaskPool>,
+) {
+    advance(&mut cells, &materials, *seed, *tick, Some(&pool));
+}
+
+// particles live as entities until they land back in the grid
+fn advance_particles(
+    mut commands: Commands,
+    mut cells: ResMut<Cells>,
+    materials: Res<Materials>,
+    mut particles: Query<(Entity, &Particle, &mut Position, &mut Velocity)>,
+) {
+    let mut flying = particles.iter_mut().collect::<Vec<_>>();
+    flying.sort_by_key(|(_, particle, _, _)| particle.id);
+    for (entity, particle, mut position, mut velocity) in flying {
+        if particle::step(
+            &mut cells,
+            &materials,
+            particle,
+            &mut position,
+            &mut velocity,
+        ) {
+            commands.entity(entity).despawn();
         }
     }
+    for particle in cells.take_launched() {
+        commands.spawn_bundle(particle);
+    }
+}
+
+/// Simulates a single tick, on `pool` if given. The same seed, tick and cells always produce
+/// the same result.
+pub fn advance(
+    cells: &mut Cells,
+    materials: &Materials,
+    seed: Seed,
+    tick: Tick,
+    pool: Option<&TaskPool>,
+) {
+    cells.begin();
+    let detonations =
+        cells.update_chunks(pool, |chunk| advance_chunk(chunk, materials, seed, tick));
+    explosion::explode(cells, materials, seed, tick, detonations);
     cells.swap();
 }
+
+fn advance_chunk(cells: &mut ChunkCells, materials: &Materials, seed: Seed, tick: Tick) {
+    let ((min_x, min_y), (max_x, max_y)) = cells.bounds();
+    let mut rng = seed.rng(tick, cells.position());
+    // bottom to top so falling cells don't move more than once. the horizontal direction
+    // alternates every tick so spreading isn't biased to one side
+    let reverse = tick.0 & 1 == 1;
+    for y in min_y..max_y {
+        for i in 0..(max_x - min_x) {
+            let x = if reverse { max_x - 1 - i } else { min_x + i };
+            reaction::update(cells, materials, &mut rng, x, y);
+            heat::update(cells, materials, x, y);
+            // before fire, which would burn explosives out instead
+            explosion::update(cells, materials, x, y);
+            fire::update(cells, materials, &mut rng, x, y);
+            if cells.has_moved(x, y) {
+                continue;
+            }
+            let neighborhood = match cells.neighborhood(x, y) {
+                Some(neighborhood) => neighborhood,
+                None => continue,
+            };
+            if let Some(neighbor) = movement::next_move(materials, &neighborhood, rng.bool()) {
+                cells.swap_with_neighbor(x, y, neighbor);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use game_common::world::{AMBIENT_TEMPERATURE, CHUNK_SIZE};
+
+    use super::*;
+
+    fn materials() -> Materials {
+        Materials::builtin()
+    }
+
+    fn fill(cells: &mut Cells, materials: &Materials, name: &str, xs: &[u32], ys: &[u32]) {
+        let cell = materials.cell(materials.id(name).unwrap());
+        for &x in xs {
+            for &y in ys {
+                cells.set_at(x, y, cell).unwrap();
+            }
+        }
+    }
+
+    fn run(cells: &mut Cells, materials: &Materials, ticks: u32) {
+        for tick in 0..ticks {
+            advance(cells, materials, Seed::default(), Tick(tick), None);
+        }
+    }
+
+    fn count(cells: &Cells, materials: &Materials, name: &str) -> usize {
+        let id = materials.id(name).unwrap();
+        all_cells(cells).filter(|c| c.material == id).count()
+    }
+
+    fn all_cells(cells: &Cells) -> impl Iterator<Item = Cell> + '_ {
+        let layout = cells.layout;
+        (0..layout.height)
+            .flat_map(move |y| (0..layout.width).map(move |x| (x, y)))
+            .map(move |(x, y)| cells.current_at(x, y).unwrap())
+    }
+
+    fn row(cells: &Cells, y: u32) -> Vec<Cell> {
+        (0..cells.layout.width)
+            .map(|x| cells.current_at(x, y).unwrap())
+            .collect()
+    }
+
+    fn column_height(cells: &Cells, x: u32) -> u32 {
+        (0..cells.layout.height)
+            .take_while(|&y| !cells.current_at(x, y).unwrap().is_empty())
+            .count() as u32
+    }
+
+    #[test]
+    fn sand_column_collapses_into_pile() {
+        let materials = materials();
+        let mut cells = Cells::new(32, 32);
+        fill(
+            &mut cells,
+            &materials,
+            "sand",
+            &[16],
+            &(0..12).collect::<Vec<_>>(),
+        );
+
+        run(&mut cells, &materials, 40);
+
+        assert_eq!(count(&cells, &materials, "sand"), 12);
+        let center = column_height(&cells, 16);
+        assert!(center < 12, "column is still {} high", center);
+        assert!(column_height(&cells, 15) > 0 && column_height(&cells, 17) > 0);
+
+        // the pile is stable: nothing moves anymore
+        let before = all_cells(&cells).collect::<Vec<_>>();
+        run(&mut cells, &materials, 4);
+        assert_eq!(before, all_cells(&cells).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn water_spreads_flat() {
+        let materials = materials();
+        let mut cells = Cells::new(16, 16);
+        fill(
+            &mut cells,
+            &materials,
+            "water",
+            &[8],
+            &(0..8).collect::<Vec<_>>(),
+        );
+
+        run(&mut cells, &materials, 100);
+
+        assert_eq!(count(&cells, &materials, "water"), 8);
+        assert!((1..16).all(|y| row(&cells, y).iter().all(Cell::is_empty)));
+    }
+
+    #[test]
+    fn gas_rises() {
+        let materials = materials();
+        let mut cells = Cells::new(8, 16);
+        fill(&mut cells, &materials, "steam", &[4], &[0]);
+
+        run(&mut cells, &materials, 20);
+
+        let steam = materials.id("steam").unwrap();
+        assert!(row(&cells, 15).iter().any(|c| c.material == steam));
+    }
+
+    #[test]
+    fn denser_materials_sink() {
+        let materials = materials();
+        let mut cells = Cells::new(1, 8);
+        fill(&mut cells, &materials, "sand", &[0], &[4, 5]);
+        fill(&mut cells, &materials, "water", &[0], &[0, 1, 2, 3]);
+
+        run(&mut cells, &materials, 10);
+
+        let sand = materials.id("sand").unwrap();
+        let water = materials.id("water").unwrap();
+        let column = (0..8).map(|y| row(&cells, y)[0]).collect::<Vec<_>>();
+        assert!(column[..2].iter().all(|c| c.material == sand));
+        assert!(column[2..6].iter().all(|c| c.material == water));
+    }
+
+    #[test]
+    fn cells_fall_across_chunks() {
+        let materials = materials();
+        let mut cells = Cells::new(CHUNK_SIZE * 2, CHUNK_SIZE * 2);
+        fill(
+            &mut cells,
+            &materials,
+            "sand",
+            &[CHUNK_SIZE + 1],
+            &[CHUNK_SIZE + 10],
+        );
+
+        run(&mut cells, &materials, CHUNK_SIZE + 20);
+
+        let sand = materials.id("sand").unwrap();
+        assert_eq!(row(&cells, 0)[CHUNK_SIZE as usize + 1].material, sand);
+    }
+
+    #[test]
+    fn settled_chunks_sleep() {
+        let materials = materials();
+        let mut cells = Cells::new(CHUNK_SIZE * 2, CHUNK_SIZE);
+        fill(&mut cells, &materials, "sand", &[4], &[0, 1, 2, 3]);
+        assert_eq!(cells.take_dirty(), vec![ChunkPosition { x: 0, y: 0 }]);
+
+        run(&mut cells, &materials, 20);
+        cells.take_dirty();
+        run(&mut cells, &materials, 2);
+
+        assert!(cells.chunks.iter().all(|state| !state.awake));
+        assert!(cells.take_dirty().is_empty());
+    }
+
+    // a busy world spanning a few chunks
+    fn mixed(materials: &Materials) -> Cells {
+        let names = ["empty", "sand", "water", "oil", "steam", "stone"];
+        let mut cells = Cells::new(CHUNK_SIZE * 3, CHUNK_SIZE * 3);
+        for y in 0..CHUNK_SIZE * 3 {
+            for x in 0..CHUNK_SIZE * 3 {
+                let hash = x.wrapping_mul(7919) ^ y.wrapping_mul(104_729);
+                let material = materials.id(names[hash as usize % names.len()]).unwrap();
+                cells.set_at(x, y, materials.cell(material)).unwrap();
+            }
+        }
+        cells
+    }
+
+    #[test]
+    fn parallel_matches_serial() {
+        let materials = materials();
+        let mut serial = mixed(&materials);
+        // blasts reach across chunk borders
+        let gunpowder = materials.cell(materials.id("gunpowder").unwrap());
+        for &(x, y) in &[
+            (CHUNK_SIZE, 20),
+            (CHUNK_SIZE * 2 - 1, CHUNK_SIZE + 3),
+            (90, 150),
+        ] {
+            serial
+                .set_at(x, y, materials.ignite(gunpowder).unwrap())
+                .unwrap();
+        }
+        let mut parallel = serial.clone();
+        let pool = TaskPool::new();
+
+        for tick in 0..30 {
+            advance(&mut serial, &materials, Seed(1), Tick(tick), None);
+            advance(&mut parallel, &materials, Seed(1), Tick(tick), Some(&pool));
+        }
+
+        assert_eq!(
+            all_cells(&serial).collect::<Vec<_>>(),
+            all_cells(&parallel).collect::<Vec<_>>()
+        );
+        assert_eq!(serial.take_launched(), parallel.take_launched());
+    }
+
+    #[test]
+    fn same_seed_same_result() {
+        let materials = materials();
+        let run_seeded = |seed| {
+            let mut cells = mixed(&materials);
+            for tick in 0..20 {
+                advance(&mut cells, &materials, seed, Tick(tick), None);
+            }
+            all_cells(&cells).collect::<Vec<_>>()
+        };
+
+        assert_eq!(run_seeded(Seed(1)), run_seeded(Seed(1)));
+        assert_ne!(run_seeded(Seed(1)), run_seeded(Seed(2)));
+    }
+
+    #[test]
+    fn heat_diffuses_without_loss() {
+        let materials = materials();
+        let mut cells = Cells::new(16, 3);
+        let xs = (0..16).collect::<Vec<_>>();
+        fill(&mut cells, &materials, "stone", &xs, &[0, 1, 2]);
+        let stone = materials.id("stone").unwrap();
+        cells
+            .set_at(8, 1, Cell::with_temperature(stone, 900))
+            .unwrap();
+        let total = |cells: &Cells| {
+            all_cells(cells)
+                .map(|cell| cell.temperature as i32)
+                .sum::<i32>()
+        };
+        let before = total(&cells);
+
+        run(&mut cells, &materials, 10);
+
+        assert_eq!(total(&cells), before);
+        let center = cells.current_at(8, 1).unwrap().temperature;
+        let side = cells.current_at(6, 1).unwrap().temperature;
+        assert!(center < 900 && side > AMBIENT_TEMPERATURE);
+    }
+
+    #[test]
+    fn lava_boils_water_and_hardens() {
+        let materials = materials();
+        let mut cells = Cells::new(1, 16);
+        fill(&mut cells, &materials, "lava", &[0], &[0, 1]);
+        fill(&mut cells, &materials, "water", &[0], &[2, 3, 4, 5]);
+
+        run(&mut cells, &materials, 40);
+
+        assert!(count(&cells, &materials, "steam") > 0);
+        assert!(count(&cells, &materials, "stone") > 0);
+    }
+
+    #[test]
+    fn water_and_lava_react() {
+        let materials = materials();
+        let mut cells = Cells::new(2, 1);
+        fill(&mut cells, &materials, "water", &[0], &[0]);
+        fill(&mut cells, &materials, "lava", &[1], &[0]);
+
+        run(&mut cells, &materials, 1);
+
+        assert_eq!(count(&cells, &materials, "stone"), 1);
+        assert_eq!(count(&cells, &materials, "water"), 0);
+        assert_eq!(count(&cells, &materials, "lava"), 0);
+    }
+
+    #[test]
+    fn fire_burns_wood_to_ash() {
+        let materials = materials();
+        let mut cells = Cells::new(8, 8);
+        fill(
+            &mut cells,
+            &materials,
+            "wood",
+            &(1..8).collect::<Vec<_>>(),
+            &[0],
+        );
+        fill(&mut cells, &materials, "fire", &[0], &[0]);
+
+        run(&mut cells, &materials, 20);
+        let burning = all_cells(&cells).filter(Cell::is_burning).count();
+        assert!(burning > 1, "only {} cells are burning", burning);
+        assert!(count(&cells, &materials, "smoke") > 0);
+
+        run(&mut cells, &materials, 200);
+        assert_eq!(count(&cells, &materials, "wood"), 0);
+        assert_eq!(count(&cells, &materials, "ash"), 7);
+        assert!(all_cells(&cells).all(|cell| !cell.is_burning()));
+    }
+
+    #[test]
+    fn fire_burns_out() {
+        let materials = materials();
+        let mut cells = Cells::new(4, 4);
+        fill(&mut cells, &materials, "fire", &[1], &[0]);
+        fill(&mut cells, &materials, "stone", &[0, 2], &[0]);
+
+        run(&mut cells, &materials, 40);
+
+        assert_eq!(count(&cells, &materials, "fire"), 0);
+        assert_eq!(count(&cells, &materials, "stone"), 2);
+    }
+
+    #[test]
+    fn acid_dissolves() {
+        let materials = materials();
+        let mut cells = Cells::new(1, 8);
+        fill(&mut cells, &materials, "stone", &[0], &[0, 1, 2, 3]);
+        fill(&mut cells, &materials, "acid", &[0], &[4]);
+
+        run(&mut cells, &materials, 200);
+
+        assert_eq!(count(&cells, &materials, "stone"), 0);
+        assert_eq!(count(&cells, &materials, "acid"), 1);
+    }
+
+    #[test]
+    fn ignited_gunpowder_explodes() {
+        let materials = materials();
+        let mut cells = Cells::new(64, 64);
+        let xs = (0..64).collect::<Vec<_>>();
+        fill(&mut cells, &materials, "sand", &xs, &[0, 1, 2, 3]);
+        fill(&mut cells, &materials, "gunpowder", &[32], &[4]);
+        fill(&mut cells, &materials, "fire", &[32], &[5]);
+
+        run(&mut cells, &materials, 5);
+
+        assert_eq!(count(&cells, &materials, "gunpowder"), 0);
+        let sand = materials.id("sand").unwrap();
+        let launched = cells.take_launched();
+        assert!(launched.iter().all(|bundle| bundle.velocity.0.mag() > 0.0));
+        let flung = launched
+            .iter()
+            .filter(|bundle| bundle.particle.cell.material == sand)
+            .count();
+        assert!(flung > 0);
+        assert_eq!(count(&cells, &materials, "sand") + flung, 64 * 4);
+        assert!(cells.current_at(32, 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn debris_lands_back_in_the_grid() {
+        let materials = materials();
+        let mut cells = Cells::new(64, 64);
+        let xs = (0..64).collect::<Vec<_>>();
+        fill(&mut cells, &materials, "sand", &xs, &[0, 1, 2, 3]);
+        fill(&mut cells, &materials, "gunpowder", &[32], &[4]);
+        fill(&mut cells, &materials, "fire", &[32], &[5]);
+        let mut world = World::new();
+        world.insert_resource(cells);
+        world.insert_resource(materials.clone());
+        world.insert_resource(Seed::default());
+        world.insert_resource(Tick(0));
+        world.insert_resource(ComputeTaskPool(TaskPool::new()));
+        let mut stage = SystemStage::single_threaded()
+            .with_system(advance_cells.system().label(WorldSystem::Advance))
+            .with_system(advance_particles.system().after(WorldSystem::Advance));
+
+        for _ in 0..100 {
+            stage.run(&mut world);
+        }
+
+        assert_eq!(world.query::<&Particle>().iter(&world).count(), 0);
+        let cells = world.get_resource::<Cells>().unwrap();
+        assert_eq!(count(cells, &materials, "sand"), 64 * 4);
+    }
+
+    #[test]
+    fn blasts_set_off_other_explosives() {
+        let materials = materials();
+        let mut cells = Cells::new(64, 8);
+        let xs = (0..64).collect::<Vec<_>>();
+        fill(&mut cells, &materials, "stone", &xs, &[0]);
+        // more than a blast apart, so only the chain reaches the last one
+        fill(&mut cells, &materials, "gunpowder", &[10, 15, 20], &[1]);
+        let gunpowder = materials.cell(materials.id("gunpowder").unwrap());
+        cells
+            .set_at(10, 1, materials.ignite(gunpowder).unwrap())
+            .unwrap();
+
+        run(&mut cells, &materials, 1);
+
+        assert_eq!(count(&cells, &materials, "gunpowder"), 0);
+    }
+
+    #[test]
+    fn hot_nitro_explodes() {
+        let materials = materials();
+        let mut cells = Cells::new(16, 16);
+        let nitro = materials.id("nitro").unwrap();
+        cells
+            .set_at(8, 0, Cell::with_temperature(nitro, 150))
+            .unwrap();
+
+        run(&mut cells, &materials, 1);
+
+        assert_eq!(count(&cells, &materials, "nitro"), 0);
+    }
+}
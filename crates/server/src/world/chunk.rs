@@ -0,0 +1,266 @@
+use std::marker::PhantomData;
+
+use game_common::world::{Cell, ChunkPosition, CHUNK_SIZE};
+
+use super::{explosion::Detonation, Neighborhood, NEIGHBORHOOD};
+
+pub(super) const CHUNK_AREA: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+// cells are stored chunk by chunk so each chunk is a contiguous slice, row-major within the
+// chunk. worlds that aren't a multiple of CHUNK_SIZE get partial chunks along the top and right
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Layout {
+    pub width: u32,
+    pub height: u32,
+    pub chunks_x: u32,
+    pub chunks_y: u32,
+}
+
+impl Layout {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            chunks_x: width.div_ceil(CHUNK_SIZE),
+            chunks_y: height.div_ceil(CHUNK_SIZE),
+        }
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks_x as usize * self.chunks_y as usize
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.chunk_count() * CHUNK_AREA
+    }
+
+    pub fn cell_index(&self, x: u32, y: u32) -> Option<usize> {
+        let chunk = self.chunk_index(x, y)?;
+        let local = (y % CHUNK_SIZE) as usize * CHUNK_SIZE as usize + (x % CHUNK_SIZE) as usize;
+        Some(chunk * CHUNK_AREA + local)
+    }
+
+    // index of the chunk containing the cell at (x, y)
+    pub fn chunk_index(&self, x: u32, y: u32) -> Option<usize> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        Some(self.chunk_index_of(ChunkPosition {
+            x: x / CHUNK_SIZE,
+            y: y / CHUNK_SIZE,
+        }))
+    }
+
+    pub fn chunk_index_of(&self, chunk: ChunkPosition) -> usize {
+        chunk.y as usize * self.chunks_x as usize + chunk.x as usize
+    }
+
+    pub fn chunk_position(&self, chunk_index: usize) -> ChunkPosition {
+        ChunkPosition {
+            x: (chunk_index % self.chunks_x as usize) as u32,
+            y: (chunk_index / self.chunks_x as usize) as u32,
+        }
+    }
+
+    // cell bounds of a chunk as ((min_x, min_y), (max_x, max_y)), max exclusive
+    pub fn chunk_bounds(&self, chunk: ChunkPosition) -> ((u32, u32), (u32, u32)) {
+        let min = (chunk.x * CHUNK_SIZE, chunk.y * CHUNK_SIZE);
+        let max = (
+            (min.0 + CHUNK_SIZE).min(self.width),
+            (min.1 + CHUNK_SIZE).min(self.height),
+        );
+        (min, max)
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub(super) struct ChunkState {
+    // simulated during the current tick
+    pub awake: bool,
+    // simulated during the next tick
+    pub wake: bool,
+    // modified during the current tick
+    pub changed: bool,
+    // modified since the network last took it
+    pub dirty: bool,
+}
+
+// chunks are simulated in four passes over a 2x2 checkerboard. chunks in the same pass are at
+// least one chunk apart, and a chunk only ever reads or writes cells within one cell of its
+// bounds, so the chunks of a pass never access the same cells and can run in parallel
+pub(super) const PASSES: [(u32, u32); 4] = [(0, 0), (1, 0), (0, 1), (1, 1)];
+
+pub(super) fn in_pass(chunk: ChunkPosition, pass: (u32, u32)) -> bool {
+    chunk.x % 2 == pass.0 && chunk.y % 2 == pass.1
+}
+
+// the active buffer, shared between the chunks of a pass
+#[derive(Copy, Clone)]
+pub(super) struct SharedCells<'a> {
+    layout: Layout,
+    cells: *mut Cell,
+    moved: *mut bool,
+    _marker: PhantomData<&'a mut [Cell]>,
+}
+
+// safety: only handed out to the chunks of a single pass, see `PASSES`
+unsafe impl Send for SharedCells<'_> {}
+unsafe impl Sync for SharedCells<'_> {}
+
+impl<'a> SharedCells<'a> {
+    pub fn new(layout: Layout, cells: &'a mut [Cell], moved: &'a mut [bool]) -> Self {
+        assert_eq!(cells.len(), layout.cell_count());
+        assert_eq!(moved.len(), layout.cell_count());
+        Self {
+            layout,
+            cells: cells.as_mut_ptr(),
+            moved: moved.as_mut_ptr(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No other `ChunkCells` for a chunk within one chunk of `chunk` may exist at the same time.
+    pub unsafe fn chunk(self, chunk: ChunkPosition) -> ChunkCells<'a> {
+        let ((min_x, min_y), (max_x, max_y)) = self.layout.chunk_bounds(chunk);
+        ChunkCells {
+            shared: self,
+            chunk,
+            min: (min_x, min_y),
+            max: (max_x, max_y),
+            touched: Vec::new(),
+            stay_awake: false,
+            detonations: Vec::new(),
+        }
+    }
+}
+
+// a chunk of the active buffer, plus the one cell border around it
+pub(super) struct ChunkCells<'a> {
+    shared: SharedCells<'a>,
+    chunk: ChunkPosition,
+    min: (u32, u32),
+    max: (u32, u32),
+    // cells that changed
+    touched: Vec<(u32, u32)>,
+    // something might happen next tick even though nothing changed
+    stay_awake: bool,
+    // blasts reach further than the border, so they're applied after the passes
+    detonations: Vec<Detonation>,
+}
+
+// what happened to a chunk during a pass
+pub(super) struct ChunkUpdate {
+    pub chunk: ChunkPosition,
+    pub touched: Vec<(u32, u32)>,
+    pub stay_awake: bool,
+    pub detonations: Vec<Detonation>,
+}
+
+impl ChunkCells<'_> {
+    pub fn position(&self) -> ChunkPosition {
+        self.chunk
+    }
+
+    // ((min_x, min_y), (max_x, max_y)), max exclusive
+    pub fn bounds(&self) -> ((u32, u32), (u32, u32)) {
+        (self.min, self.max)
+    }
+
+    pub fn finish(self) -> ChunkUpdate {
+        ChunkUpdate {
+            chunk: self.chunk,
+            touched: self.touched,
+            stay_awake: self.stay_awake,
+            detonations: self.detonations,
+        }
+    }
+
+    // simulate the chunk next tick, even if nothing changes
+    pub fn stay_awake(&mut self) {
+        self.stay_awake = true;
+    }
+
+    // clear the cell at (x, y) and blow it up once the passes are done
+    pub fn detonate(&mut self, x: u32, y: u32) -> Option<()> {
+        let cell = self.cell_at(x, y)?;
+        self.set_at(x, y, Cell::empty())?;
+        self.detonations.push(Detonation {
+            x,
+            y,
+            material: cell.material,
+        });
+        Some(())
+    }
+
+    fn index(&self, x: u32, y: u32) -> Option<usize> {
+        // offset by one so the border left of / below the world wraps to 0
+        let (border_x, border_y) = (x.wrapping_add(1), y.wrapping_add(1));
+        debug_assert!(
+            (self.min.0..=self.max.0 + 1).contains(&border_x)
+                && (self.min.1..=self.max.1 + 1).contains(&border_y),
+            "({}, {}) is outside of the chunk",
+            x,
+            y
+        );
+        self.shared.layout.cell_index(x, y)
+    }
+
+    pub fn cell_at(&self, x: u32, y: u32) -> Option<Cell> {
+        let index = self.index(x, y)?;
+        // safety: see `SharedCells::chunk`
+        Some(unsafe { *self.shared.cells.add(index) })
+    }
+
+    pub fn set_at(&mut self, x: u32, y: u32, cell: Cell) -> Option<()> {
+        let index = self.index(x, y)?;
+        // safety: see `SharedCells::chunk`
+        unsafe { *self.shared.cells.add(index) = cell };
+        self.touched.push((x, y));
+        Some(())
+    }
+
+    // out of bounds neighbors are `None`
+    pub fn neighborhood(&self, center_x: u32, center_y: u32) -> Option<Neighborhood> {
+        self.index(center_x, center_y)?;
+        let mut neighborhood = [None; 9];
+        for (i, cell) in neighborhood.iter_mut().enumerate() {
+            let (x, y) = neighbor_position(center_x, center_y, i);
+            *cell = self.cell_at(x, y);
+        }
+        Some(neighborhood)
+    }
+
+    pub fn has_moved(&self, x: u32, y: u32) -> bool {
+        self.index(x, y)
+            // safety: see `SharedCells::chunk`
+            .is_some_and(|index| unsafe { *self.shared.moved.add(index) })
+    }
+
+    // swap the cell at (x, y) with its neighbor at `NEIGHBORHOOD[neighbor]`
+    pub fn swap_with_neighbor(&mut self, x: u32, y: u32, neighbor: usize) -> Option<()> {
+        let (to_x, to_y) = neighbor_position(x, y, neighbor);
+        let from = self.index(x, y)?;
+        let to = self.index(to_x, to_y)?;
+        // safety: see `SharedCells::chunk`
+        unsafe {
+            std::ptr::swap(self.shared.cells.add(from), self.shared.cells.add(to));
+            *self.shared.moved.add(from) = true;
+            *self.shared.moved.add(to) = true;
+        }
+        self.touched.push((x, y));
+        self.touched.push((to_x, to_y));
+        Some(())
+    }
+}
+
+// position of the cell at `NEIGHBORHOOD[neighbor]` relative to (x, y). out of bounds positions
+// wrap around to positions that are out of bounds on the other side
+pub(super) fn neighbor_position(x: u32, y: u32, neighbor: usize) -> (u32, u32) {
+    let (relative_x, relative_y) = NEIGHBORHOOD[neighbor];
+    (
+        ((x as i64) + relative_x) as u32,
+        ((y as i64) + relative_y) as u32,
+    )
+}
@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+
+use game_common::{
+    material::{MaterialId, Materials},
+    rng::Seed,
+    world::{Cell, Tick},
+};
+use ultraviolet::Vec2;
+
+use super::{chunk::ChunkCells, particle, Cells};
+
+// an explosive cell that went off during a pass
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Detonation {
+    pub x: u32,
+    pub y: u32,
+    pub material: MaterialId,
+}
+
+/// Detonates the cell at (x, y) if it's explosive and either on fire or hotter than its
+/// material can stand.
+pub(super) fn update(cells: &mut ChunkCells, materials: &Materials, x: u32, y: u32) {
+    let cell = match cells.cell_at(x, y) {
+        Some(cell) => cell,
+        None => return,
+    };
+    let explosive = match materials
+        .get(cell.material)
+        .and_then(|material| material.explosive.as_ref())
+    {
+        Some(explosive) => explosive,
+        None => return,
+    };
+    if cell.is_burning() || cell.temperature > explosive.temperature {
+        cells.detonate(x, y);
+    }
+}
+
+/// Applies the blasts of `detonations` in order, in the active buffer. Every cell within a
+/// blast is torn out of the grid and launched away from its center, faster the closer it was.
+/// Explosives caught in a blast go off right after it.
+pub(super) fn explode(
+    cells: &mut Cells,
+    materials: &Materials,
+    seed: Seed,
+    tick: Tick,
+    detonations: Vec<Detonation>,
+) {
+    let mut pending = VecDeque::from(detonations);
+    while let Some(detonation) = pending.pop_front() {
+        let explosive = match materials
+            .get(detonation.material)
+            .and_then(|material| material.explosive.as_ref())
+        {
+            Some(explosive) => explosive,
+            None => continue,
+        };
+        let mut rng = seed.cell_rng(tick, detonation.x, detonation.y);
+        let radius = explosive.radius as i64;
+        for relative_y in -radius..=radius {
+            for relative_x in -radius..=radius {
+                let distance_squared = relative_x * relative_x + relative_y * relative_y;
+                if distance_squared == 0 || distance_squared > radius * radius {
+                    continue;
+                }
+                let x = detonation.x as i64 + relative_x;
+                let y = detonation.y as i64 + relative_y;
+                if x < 0 || y < 0 {
+                    continue;
+                }
+                let (x, y) = (x as u32, y as u32);
+                let cell = match cells.active_at(x, y) {
+                    Some(cell) if !cell.is_empty() => cell,
+                    _ => continue,
+                };
+                cells.set_active(x, y, Cell::empty());
+                let chained = materials
+                    .get(cell.material)
+                    .is_some_and(|material| material.explosive.is_some());
+                if chained {
+                    pending.push_back(Detonation {
+                        x,
+                        y,
+                        material: cell.material,
+                    });
+                    continue;
+                }
+                let distance = (distance_squared as f32).sqrt();
+                let falloff = 1.0 - distance / (radius as f32 + 1.0);
+                // jitter so debris doesn't fly out in perfect rings
+                let speed = explosive.power * falloff * (0.5 + rng.unit());
+                let direction = Vec2::new(relative_x as f32, relative_y as f32) / distance;
+                cells.launch(cell, particle::center(x, y), direction * speed);
+            }
+        }
+    }
+}
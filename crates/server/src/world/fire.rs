@@ -0,0 +1,57 @@
+use game_common::{material::Materials, rng::Rng};
+
+use super::{
+    chunk::{neighbor_position, ChunkCells},
+    C, N, NEIGHBORHOOD,
+};
+
+/// Burns the cell at (x, y) down if it's on fire, setting flammable neighbors alight and
+/// emitting smoke above it. Cells that burn out turn into their material's ash.
+pub(super) fn update(cells: &mut ChunkCells, materials: &Materials, rng: &mut Rng, x: u32, y: u32) {
+    let mut cell = match cells.cell_at(x, y) {
+        Some(cell) if cell.is_burning() => cell,
+        _ => return,
+    };
+    let ash = match materials.burnout(cell.material) {
+        Some(ash) => ash,
+        None => {
+            // turned into something that can't burn, e.g. through a reaction
+            cell.lifetime = 0;
+            cells.set_at(x, y, cell);
+            return;
+        }
+    };
+
+    for neighbor in (0..NEIGHBORHOOD.len()).filter(|&neighbor| neighbor != C) {
+        let (neighbor_x, neighbor_y) = neighbor_position(x, y, neighbor);
+        let other = match cells.cell_at(neighbor_x, neighbor_y) {
+            Some(other) if !other.is_burning() => other,
+            _ => continue,
+        };
+        let flammability = materials
+            .get(other.material)
+            .map_or(0.0, |material| material.flammability);
+        if flammability > 0.0 && rng.chance(flammability) {
+            if let Some(ignited) = materials.ignite(other) {
+                cells.set_at(neighbor_x, neighbor_y, ignited);
+            }
+        }
+    }
+
+    if let Some((emitted, chance)) = materials.emission(cell.material) {
+        let (above_x, above_y) = neighbor_position(x, y, N);
+        let above_empty = cells
+            .cell_at(above_x, above_y)
+            .is_some_and(|above| above.is_empty());
+        if above_empty && rng.chance(chance) {
+            cells.set_at(above_x, above_y, materials.cell(emitted));
+        }
+    }
+
+    cell.lifetime -= 1;
+    if cell.is_burning() {
+        cells.set_at(x, y, cell);
+    } else {
+        cells.set_at(x, y, materials.cell(ash));
+    }
+}
@@ -0,0 +1,48 @@
+use game_common::{material::Materials, world::Cell};
+
+use super::{
+    chunk::{neighbor_position, ChunkCells},
+    INTERACTIONS,
+};
+
+/// Exchanges heat between the cell at (x, y) and its neighbors, then changes its material if
+/// its temperature crossed one of the material's thresholds.
+pub(super) fn update(cells: &mut ChunkCells, materials: &Materials, x: u32, y: u32) {
+    let mut center = match cells.cell_at(x, y) {
+        Some(cell) => cell,
+        None => return,
+    };
+    let original = center;
+    for &neighbor in INTERACTIONS.iter() {
+        let (neighbor_x, neighbor_y) = neighbor_position(x, y, neighbor);
+        let mut other = match cells.cell_at(neighbor_x, neighbor_y) {
+            Some(cell) => cell,
+            None => continue,
+        };
+        let transfer = transfer(materials, center, other);
+        if transfer != 0 {
+            center.temperature -= transfer;
+            other.temperature += transfer;
+            cells.set_at(neighbor_x, neighbor_y, other);
+        }
+    }
+    if let Some(into) = materials.phase_change(center) {
+        center.material = into;
+    }
+    if center != original {
+        cells.set_at(x, y, center);
+    }
+}
+
+// heat flowing from `from` into `to`, negative if it flows the other way
+fn transfer(materials: &Materials, from: Cell, to: Cell) -> i16 {
+    let conductivity = |cell: Cell| {
+        materials
+            .get(cell.material)
+            .map_or(0.0, |material| material.conductivity)
+    };
+    let conductivity = conductivity(from).min(conductivity(to));
+    let difference = from.temperature as i32 - to.temperature as i32;
+    // a quarter, since a cell exchanges heat with four neighbors
+    (difference as f32 * conductivity * 0.25) as i16
+}
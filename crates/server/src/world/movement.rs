@@ -0,0 +1,69 @@
+use game_common::{
+    material::{Material, Materials, State},
+    world::Cell,
+};
+
+use super::{Neighborhood, C, E, N, NE, NW, S, SE, SW, W};
+
+const POWDER: &[usize] = &[S, SW, SE];
+const LIQUID: &[usize] = &[S, SW, SE, W, E];
+const GAS: &[usize] = &[N, NW, NE, W, E];
+
+/// Returns the neighbor the center cell should swap places with this tick, if any.
+///
+/// Candidates are tried in order (straight, diagonal, sideways); `flip` mirrors the order
+/// between west and east so piles and puddles don't lean to one side.
+pub(super) fn next_move(
+    materials: &Materials,
+    neighborhood: &Neighborhood,
+    flip: bool,
+) -> Option<usize> {
+    let center = neighborhood[C]?;
+    if center.is_empty() {
+        return None;
+    }
+    let material = materials.get(center.material)?;
+    let candidates = match material.state {
+        State::Solid => return None,
+        State::Powder => POWDER,
+        State::Liquid => LIQUID,
+        State::Gas => GAS,
+    };
+    candidates
+        .iter()
+        .map(|&neighbor| if flip { mirror(neighbor) } else { neighbor })
+        .find(|&neighbor| {
+            neighborhood[neighbor].is_some_and(|target| can_displace(materials, material, target))
+        })
+}
+
+// whether `mover` can swap places with `target`
+fn can_displace(materials: &Materials, mover: &Material, target: Cell) -> bool {
+    if target.is_empty() {
+        return true;
+    }
+    let target = match materials.get(target.material) {
+        Some(target) => target,
+        None => return false,
+    };
+    match (mover.state, target.state) {
+        (_, State::Solid) | (_, State::Powder) => false,
+        // lighter gases bubble up through heavier ones
+        (State::Gas, State::Gas) => target.density > mover.density,
+        (State::Gas, State::Liquid) => false,
+        // powders and liquids sink through anything lighter
+        (_, _) => target.density < mover.density,
+    }
+}
+
+fn mirror(neighbor: usize) -> usize {
+    match neighbor {
+        NW => NE,
+        NE => NW,
+        W => E,
+        E => W,
+        SW => SE,
+        SE => SW,
+        _ => neighbor,
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+
+use game_common::{
+    brush::{Stroke, MAX_BRUSH_RADIUS, MAX_STROKE_LENGTH},
+    material::Materials,
+    rng::Seed,
+    world::Tick,
+};
+
+use super::Cells;
+
+/// Someone painting, for telling their strokes apart when rate limiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Painter(pub u32);
+
+/// A stroke to apply to the world before the next tick.
+#[derive(Debug, Clone)]
+pub struct Paint {
+    pub painter: Painter,
+    pub stroke: Stroke,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum Error {
+    #[error("material {0} does not exist")]
+    UnknownMaterial(u8),
+    #[error(
+        "stroke is empty, longer than {} cells or wider than {}",
+        MAX_STROKE_LENGTH,
+        MAX_BRUSH_RADIUS
+    )]
+    TooLarge,
+    #[error("painting {0} cells at once is over the limit")]
+    RateLimited(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// cells a painter earns per tick, and can save up
+const PAINT_RATE: u32 = 512;
+const PAINT_BURST: u32 = 16384;
+
+// how many cells each painter may still paint
+#[derive(Debug, Default)]
+pub struct PaintBudgets {
+    budgets: HashMap<Painter, Budget>,
+}
+
+#[derive(Debug)]
+struct Budget {
+    cells: u32,
+    updated: Tick,
+}
+
+impl PaintBudgets {
+    // the painter's budget, with what they earned since it was last used
+    fn budget(&mut self, painter: Painter, tick: Tick) -> &mut Budget {
+        let budget = self.budgets.entry(painter).or_insert(Budget {
+            cells: PAINT_BURST,
+            updated: tick,
+        });
+        let elapsed = tick.0.wrapping_sub(budget.updated.0);
+        budget.cells = budget
+            .cells
+            .saturating_add(elapsed.saturating_mul(PAINT_RATE))
+            .min(PAINT_BURST);
+        budget.updated = tick;
+        budget
+    }
+
+    // whether the painter has `cells` left, without taking them
+    fn check(&mut self, painter: Painter, tick: Tick, cells: usize) -> Result<()> {
+        if cells > self.budget(painter, tick).cells as usize {
+            return Err(Error::RateLimited(cells));
+        }
+        Ok(())
+    }
+
+    // take `cells` from the painter's budget, if it has that many
+    fn spend(&mut self, painter: Painter, tick: Tick, cells: usize) -> Result<()> {
+        self.check(painter, tick, cells)?;
+        self.budget(painter, tick).cells -= cells as u32;
+        Ok(())
+    }
+
+    /// Drops the painter's budget, once they are gone for good.
+    pub fn forget(&mut self, painter: Painter) {
+        self.budgets.remove(&painter);
+    }
+}
+
+/// Paints `paint` into the current state of `cells`. Materials only fill empty cells, while
+/// painting empty erases anything. Strokes over the painter's budget are rejected as a whole.
+/// Returns how many cells changed.
+pub fn paint(
+    cells: &mut Cells,
+    materials: &Materials,
+    budgets: &mut PaintBudgets,
+    seed: Seed,
+    tick: Tick,
+    paint: &Paint,
+) -> Result<usize> {
+    let stroke = &paint.stroke;
+    if materials.get(stroke.material).is_none() {
+        return Err(Error::UnknownMaterial(stroke.material.0));
+    }
+    let first = match stroke.points.first() {
+        Some(&first) => first,
+        None => return Err(Error::TooLarge),
+    };
+    if stroke.radius > MAX_BRUSH_RADIUS || stroke.length() > MAX_STROKE_LENGTH {
+        return Err(Error::TooLarge);
+    }
+    // working out the cells takes a while for wide brushes, so painters who can't afford the
+    // most it could cover are turned away first
+    budgets.check(paint.painter, tick, stroke.max_cells())?;
+
+    // the painter's tick, so replaying the same strokes sprays the same cells
+    let mut rng = seed.cell_rng(stroke.tick, first.0, first.1);
+    let covered = stroke.cells(cells.layout.width, cells.layout.height, &mut rng);
+    budgets.spend(paint.painter, tick, covered.len())?;
+
+    let cell = materials.cell(stroke.material);
+    let mut painted = 0;
+    for (x, y) in covered {
+        let current = cells.current_at(x, y).unwrap();
+        if current != cell && (cell.is_empty() || current.is_empty()) {
+            cells.set_at(x, y, cell);
+            painted += 1;
+        }
+    }
+    Ok(painted)
+}
+
+#[cfg(test)]
+mod tests {
+    use game_common::{brush::Shape, material::MaterialId};
+
+    use super::*;
+
+    fn stroke(materials: &Materials, name: &str, shape: Shape, points: &[(u32, u32)]) -> Paint {
+        Paint {
+            painter: Painter(0),
+            stroke: Stroke {
+                material: materials.id(name).unwrap(),
+                shape,
+                radius: 2,
+                points: points.to_vec(),
+                tick: Tick(0),
+            },
+        }
+    }
+
+    fn apply(cells: &mut Cells, materials: &Materials, paint: &Paint) -> Result<usize> {
+        let mut budgets = PaintBudgets::default();
+        self::paint(
+            cells,
+            materials,
+            &mut budgets,
+            Seed::default(),
+            Tick(0),
+            paint,
+        )
+    }
+
+    #[test]
+    fn paints_only_empty_cells() {
+        let materials = Materials::builtin();
+        let mut cells = Cells::new(32, 32);
+        let stone = materials.cell(materials.id("stone").unwrap());
+        cells.set_at(10, 10, stone).unwrap();
+
+        let sand = stroke(&materials, "sand", Shape::Square, &[(10, 10)]);
+        assert_eq!(apply(&mut cells, &materials, &sand), Ok(24));
+        assert_eq!(cells.current_at(10, 10), Some(stone));
+        assert_eq!(
+            cells.current_at(12, 12).map(|cell| cell.material),
+            materials.id("sand")
+        );
+    }
+
+    #[test]
+    fn empty_erases() {
+        let materials = Materials::builtin();
+        let mut cells = Cells::new(32, 32);
+        let stone = materials.cell(materials.id("stone").unwrap());
+        cells.set_at(10, 10, stone).unwrap();
+
+        let eraser = stroke(&materials, "empty", Shape::Circle, &[(10, 10)]);
+        assert_eq!(apply(&mut cells, &materials, &eraser), Ok(1));
+        assert!(cells.current_at(10, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn clipped_to_the_world() {
+        let materials = Materials::builtin();
+        let mut cells = Cells::new(32, 32);
+
+        let corner = stroke(&materials, "sand", Shape::Square, &[(31, 31), (40, 40)]);
+        assert_eq!(apply(&mut cells, &materials, &corner), Ok(9));
+    }
+
+    #[test]
+    fn invalid_strokes_are_rejected() {
+        let materials = Materials::builtin();
+        let mut cells = Cells::new(32, 32);
+
+        let mut unknown = stroke(&materials, "sand", Shape::Circle, &[(1, 1)]);
+        unknown.stroke.material = MaterialId(200);
+        assert_eq!(
+            apply(&mut cells, &materials, &unknown),
+            Err(Error::UnknownMaterial(200))
+        );
+        let empty = stroke(&materials, "sand", Shape::Circle, &[]);
+        assert_eq!(apply(&mut cells, &materials, &empty), Err(Error::TooLarge));
+        let long = stroke(&materials, "sand", Shape::Line, &[(0, 0), (u32::MAX, 0)]);
+        assert_eq!(apply(&mut cells, &materials, &long), Err(Error::TooLarge));
+        let mut wide = stroke(&materials, "sand", Shape::Circle, &[(1, 1)]);
+        wide.stroke.radius = MAX_BRUSH_RADIUS + 1;
+        assert_eq!(apply(&mut cells, &materials, &wide), Err(Error::TooLarge));
+    }
+
+    #[test]
+    fn painting_is_rate_limited() {
+        let materials = Materials::builtin();
+        let mut cells = Cells::new(512, 512);
+        let mut budgets = PaintBudgets::default();
+        let mut big = stroke(
+            &materials,
+            "empty",
+            Shape::Square,
+            &[(100, 100), (100, 300)],
+        );
+        big.stroke.radius = MAX_BRUSH_RADIUS;
+        let most = big.stroke.max_cells();
+        let mut paint = |tick| {
+            self::paint(
+                &mut cells,
+                &materials,
+                &mut budgets,
+                Seed::default(),
+                Tick(tick),
+                &big,
+            )
+        };
+
+        assert!(paint(0).is_ok());
+        assert!(paint(0).is_ok());
+        // turned away for what it could cover, before working out what it does
+        assert_eq!(paint(0), Err(Error::RateLimited(most)));
+        // enough ticks later the budget has refilled
+        assert!(paint(100).is_ok());
+    }
+}
@@ -0,0 +1,251 @@
+use bevy_ecs::prelude::*;
+use game_common::{
+    material::{Materials, State},
+    world::Cell,
+};
+use ultraviolet::Vec2;
+
+use super::Cells;
+
+/// A cell flying freely outside of the grid, like debris from an explosion or a splash. It
+/// lands back in the grid once it hits something.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Particle {
+    // particles are always moved in launch order, so where they land is deterministic
+    pub id: u64,
+    pub cell: Cell,
+}
+
+// in cells, (0, 0) being the bottom left corner of the world
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position(pub Vec2);
+
+// in cells per tick
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Velocity(pub Vec2);
+
+#[derive(Bundle, Debug, Clone, Copy, PartialEq)]
+pub struct ParticleBundle {
+    pub particle: Particle,
+    pub position: Position,
+    pub velocity: Velocity,
+}
+
+// in cells per tick, per tick
+const GRAVITY: f32 = 0.25;
+// particles at least this fast throw liquids they hit up into the air
+const SPLASH_SPEED: f32 = 2.0;
+// fraction of the speed a splashing particle keeps, and passes on to the liquid
+const SPLASH_DAMPING: f32 = 0.5;
+
+/// The middle of the cell at (x, y).
+pub(super) fn center(x: u32, y: u32) -> Vec2 {
+    Vec2::new(x as f32 + 0.5, y as f32 + 0.5)
+}
+
+/// Moves a particle by one tick. Returns true once it landed back in `cells`.
+pub(super) fn step(
+    cells: &mut Cells,
+    materials: &Materials,
+    particle: &Particle,
+    position: &mut Position,
+    velocity: &mut Velocity,
+) -> bool {
+    velocity.0.y -= GRAVITY;
+    // move at most one cell at a time so fast particles can't pass through thin walls
+    let steps = velocity.0.x.abs().max(velocity.0.y.abs()).ceil().max(1.0);
+    let delta = velocity.0 / steps;
+    for _ in 0..steps as u32 {
+        let next = position.0 + delta;
+        match obstacle(cells, materials, next) {
+            Obstacle::None => position.0 = next,
+            Obstacle::Liquid(x, y, liquid) if velocity.0.mag() >= SPLASH_SPEED => {
+                // the liquid flies off the way the particle came and the particle plows on,
+                // slowed down
+                cells.set_at(x, y, Cell::empty());
+                let splash = Vec2::new(velocity.0.x, -velocity.0.y) * SPLASH_DAMPING;
+                cells.launch(liquid, center(x, y), splash);
+                velocity.0 *= SPLASH_DAMPING;
+                position.0 = next;
+                return false;
+            }
+            Obstacle::Liquid(..) | Obstacle::Solid => {
+                land(cells, materials, particle.cell, position.0);
+                return true;
+            }
+        }
+    }
+    false
+}
+
+enum Obstacle {
+    None,
+    // (x, y, cell)
+    Liquid(u32, u32, Cell),
+    Solid,
+}
+
+// particles fly through empty cells and gases. the sides and bottom of the world are walls,
+// above it is open air
+fn obstacle(cells: &Cells, materials: &Materials, position: Vec2) -> Obstacle {
+    if position.x < 0.0 || position.y < 0.0 || position.x >= cells.layout.width as f32 {
+        return Obstacle::Solid;
+    }
+    if position.y >= cells.layout.height as f32 {
+        return Obstacle::None;
+    }
+    let (x, y) = (position.x as u32, position.y as u32);
+    let cell = match cells.current_at(x, y) {
+        Some(cell) => cell,
+        None => return Obstacle::Solid,
+    };
+    if cell.is_empty() {
+        return Obstacle::None;
+    }
+    match state(materials, cell) {
+        Some(State::Gas) => Obstacle::None,
+        Some(State::Liquid) => Obstacle::Liquid(x, y, cell),
+        _ => Obstacle::Solid,
+    }
+}
+
+fn state(materials: &Materials, cell: Cell) -> Option<State> {
+    materials.get(cell.material).map(|material| material.state)
+}
+
+// put `cell` where the particle stopped, pushing gases it stopped in up the column and landing
+// on top of whatever got there first. it's lost if there's no room left above
+fn land(cells: &mut Cells, materials: &Materials, mut cell: Cell, position: Vec2) {
+    let x = position.x as u32;
+    for y in position.y as u32..cells.layout.height {
+        let here = match cells.current_at(x, y) {
+            Some(here) => here,
+            None => return,
+        };
+        if here.is_empty() {
+            cells.set_at(x, y, cell);
+            return;
+        }
+        if state(materials, here) == Some(State::Gas) {
+            cells.set_at(x, y, cell);
+            cell = here;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn materials() -> Materials {
+        Materials::builtin()
+    }
+
+    fn cell(materials: &Materials, name: &str) -> Cell {
+        materials.cell(materials.id(name).unwrap())
+    }
+
+    fn particle(cell: Cell, position: Vec2, velocity: Vec2) -> ParticleBundle {
+        ParticleBundle {
+            particle: Particle { id: 0, cell },
+            position: Position(position),
+            velocity: Velocity(velocity),
+        }
+    }
+
+    fn fly(cells: &mut Cells, materials: &Materials, bundle: &mut ParticleBundle) {
+        for _ in 0..100 {
+            let ParticleBundle {
+                particle,
+                position,
+                velocity,
+            } = bundle;
+            if step(cells, materials, particle, position, velocity) {
+                return;
+            }
+        }
+        panic!("particle never landed: {:?}", bundle);
+    }
+
+    #[test]
+    fn thrown_particles_fall_back_down() {
+        let materials = materials();
+        let mut cells = Cells::new(16, 64);
+        let sand = cell(&materials, "sand");
+        let mut thrown = particle(sand, Vec2::new(8.5, 0.5), Vec2::new(0.0, 3.0));
+
+        fly(&mut cells, &materials, &mut thrown);
+
+        assert_eq!(cells.current_at(8, 0), Some(sand));
+    }
+
+    #[test]
+    fn particles_land_on_top_of_cells() {
+        let materials = materials();
+        let mut cells = Cells::new(16, 32);
+        for y in 0..4 {
+            cells.set_at(8, y, cell(&materials, "stone")).unwrap();
+        }
+        let sand = cell(&materials, "sand");
+        let mut falling = particle(sand, Vec2::new(8.5, 20.5), Vec2::zero());
+
+        fly(&mut cells, &materials, &mut falling);
+
+        assert_eq!(cells.current_at(8, 4), Some(sand));
+    }
+
+    #[test]
+    fn fast_particles_stop_at_walls() {
+        let materials = materials();
+        let mut cells = Cells::new(16, 32);
+        for y in 0..32 {
+            cells.set_at(10, y, cell(&materials, "stone")).unwrap();
+        }
+        let sand = cell(&materials, "sand");
+        let mut flung = particle(sand, Vec2::new(2.5, 20.5), Vec2::new(12.0, 0.0));
+
+        fly(&mut cells, &materials, &mut flung);
+
+        assert_eq!(cells.current_at(9, 20), Some(sand));
+    }
+
+    #[test]
+    fn particles_fly_through_gases() {
+        let materials = materials();
+        let mut cells = Cells::new(16, 32);
+        let smoke = cell(&materials, "smoke");
+        for y in 0..8 {
+            cells.set_at(8, y, smoke).unwrap();
+        }
+        let sand = cell(&materials, "sand");
+        let mut falling = particle(sand, Vec2::new(8.5, 20.5), Vec2::zero());
+
+        fly(&mut cells, &materials, &mut falling);
+
+        assert_eq!(cells.current_at(8, 0), Some(sand));
+        // pushed up, not destroyed
+        assert_eq!(cells.current_at(8, 8), Some(smoke));
+    }
+
+    #[test]
+    fn fast_particles_splash_liquids() {
+        let materials = materials();
+        let mut cells = Cells::new(16, 64);
+        let water = cell(&materials, "water");
+        for x in 0..16 {
+            for y in 0..4 {
+                cells.set_at(x, y, water).unwrap();
+            }
+        }
+        let sand = cell(&materials, "sand");
+        let mut falling = particle(sand, Vec2::new(8.5, 40.5), Vec2::new(0.0, -6.0));
+
+        fly(&mut cells, &materials, &mut falling);
+
+        let splashed = cells.take_launched();
+        assert!(!splashed.is_empty());
+        assert!(splashed
+            .iter()
+            .all(|drop| drop.particle.cell == water && drop.velocity.0.y > 0.0));
+    }
+}
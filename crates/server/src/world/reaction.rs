@@ -0,0 +1,33 @@
+use game_common::{material::Materials, rng::Rng};
+
+use super::{
+    chunk::{neighbor_position, ChunkCells},
+    INTERACTIONS,
+};
+
+/// Reacts the cell at (x, y) with each of its neighbors it has a reaction with.
+pub(super) fn update(cells: &mut ChunkCells, materials: &Materials, rng: &mut Rng, x: u32, y: u32) {
+    for &neighbor in INTERACTIONS.iter() {
+        let (neighbor_x, neighbor_y) = neighbor_position(x, y, neighbor);
+        let (center, other) = match (cells.cell_at(x, y), cells.cell_at(neighbor_x, neighbor_y)) {
+            (Some(center), Some(other)) => (center, other),
+            _ => continue,
+        };
+        let reaction = match materials.reaction(center.material, other.material) {
+            Some(reaction) => reaction,
+            None => continue,
+        };
+        if !rng.chance(reaction.chance) {
+            // try again next tick
+            cells.stay_awake();
+            continue;
+        }
+        let (center_product, other_product) = reaction.products;
+        if center_product != center.material {
+            cells.set_at(x, y, materials.cell(center_product));
+        }
+        if other_product != other.material {
+            cells.set_at(neighbor_x, neighbor_y, materials.cell(other_product));
+        }
+    }
+}
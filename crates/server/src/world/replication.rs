@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+
+use game_common::{
+    delta::ChunkDelta,
+    world::{Cell, ChunkPosition, Tick},
+    ServerPacket,
+};
+
+use super::Cells;
+
+// full snapshots are broadcast this often, in ticks, so clients that missed updates catch up
+pub(super) const KEYFRAME_INTERVAL: u32 = 300;
+// snapshots and keyframes are split into packets of about this many bytes of chunks, far below
+// what the network can fragment. a chunk larger than that on its own goes alone
+const MAX_PACKET_SIZE: usize = 32 * 1024;
+
+/// What clients were last sent of every chunk, to diff the next update against. Chunks that
+/// were never sent are empty.
+#[derive(Debug, Default)]
+pub struct Replica {
+    chunks: HashMap<ChunkPosition, Vec<Cell>>,
+}
+
+impl Replica {
+    /// Changes to `chunks` since they were last sent, or `None` if none of them changed.
+    pub fn update(
+        &mut self,
+        cells: &Cells,
+        chunks: impl IntoIterator<Item = ChunkPosition>,
+        tick: Tick,
+    ) -> Option<ServerPacket> {
+        let deltas = chunks
+            .into_iter()
+            .filter_map(|chunk| {
+                let current = cells.current_chunk(chunk);
+                let sent = self
+                    .chunks
+                    .entry(chunk)
+                    .or_insert_with(|| vec![Cell::empty(); current.len()]);
+                let delta = ChunkDelta::between(chunk, sent, current)?;
+                sent.copy_from_slice(current);
+                Some(delta)
+            })
+            .collect::<Vec<_>>();
+        if deltas.is_empty() {
+            return None;
+        }
+        Some(ServerPacket::UpdateCells {
+            tick,
+            chunks: deltas,
+        })
+    }
+
+    /// Every chunk, empty ones too, for clients to start over from whatever they missed.
+    pub fn keyframe(&mut self, cells: &Cells, tick: Tick) -> Vec<ServerPacket> {
+        self.chunks = cells
+            .chunks()
+            .map(|chunk| (chunk, cells.current_chunk(chunk)))
+            .filter(|(_, current)| !current.iter().all(Cell::is_empty))
+            .map(|(chunk, current)| (chunk, current.to_vec()))
+            .collect();
+        let deltas = cells
+            .chunks()
+            .map(|chunk| ChunkDelta::full(chunk, cells.current_chunk(chunk)));
+        split(deltas)
+            .into_iter()
+            .map(|chunks| ServerPacket::SetCells { tick, chunks })
+            .collect()
+    }
+}
+
+/// Every chunk that isn't empty, for clients that just joined and know nothing yet. There is
+/// always at least one packet, even for an empty world, so clients can tell when they have it
+/// all.
+pub fn snapshot(cells: &Cells, tick: Tick) -> Vec<ServerPacket> {
+    let deltas = cells
+        .chunks()
+        .map(|chunk| (chunk, cells.current_chunk(chunk)))
+        .filter(|(_, current)| !current.iter().all(Cell::is_empty))
+        .map(|(chunk, current)| ChunkDelta::full(chunk, current));
+    let mut parts = split(deltas);
+    if parts.is_empty() {
+        parts.push(Vec::new());
+    }
+    let count = parts.len() as u32;
+    parts
+        .into_iter()
+        .map(|chunks| ServerPacket::Snapshot {
+            tick,
+            parts: count,
+            chunks,
+        })
+        .collect()
+}
+
+// `deltas` in groups of at most `MAX_PACKET_SIZE` bytes each, one per packet
+fn split(deltas: impl Iterator<Item = ChunkDelta>) -> Vec<Vec<ChunkDelta>> {
+    let mut packets = Vec::new();
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    for delta in deltas {
+        let delta_size = delta.encoded_size();
+        if !chunks.is_empty() && size + delta_size > MAX_PACKET_SIZE {
+            packets.push(std::mem::take(&mut chunks));
+            size = 0;
+        }
+        size += delta_size;
+        chunks.push(delta);
+    }
+    if !chunks.is_empty() {
+        packets.push(chunks);
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use game_common::{material::Materials, rng::Seed, world::CHUNK_SIZE};
+
+    use super::{
+        super::{advance, chunk::CHUNK_AREA},
+        *,
+    };
+
+    // what a client makes of the packets
+    #[derive(Default)]
+    struct Client {
+        chunks: HashMap<ChunkPosition, Vec<Cell>>,
+    }
+
+    impl Client {
+        fn receive(&mut self, packet: ServerPacket) {
+            let chunks = match packet {
+                ServerPacket::Snapshot { chunks, .. }
+                | ServerPacket::SetCells { chunks, .. }
+                | ServerPacket::UpdateCells { chunks, .. } => chunks,
+                packet => panic!("unexpected {:?}", packet),
+            };
+            for delta in chunks {
+                let cells = self
+                    .chunks
+                    .entry(delta.chunk)
+                    .or_insert_with(|| vec![Cell::empty(); CHUNK_AREA]);
+                delta.apply(cells).unwrap();
+            }
+        }
+
+        fn matches(&self, cells: &Cells) -> bool {
+            cells.chunks().all(|chunk| {
+                let current = cells.current_chunk(chunk);
+                match self.chunks.get(&chunk) {
+                    Some(known) => known == current,
+                    None => current.iter().all(Cell::is_empty),
+                }
+            })
+        }
+    }
+
+    fn falling_sand(materials: &Materials) -> Cells {
+        let mut cells = Cells::new(CHUNK_SIZE * 2, CHUNK_SIZE * 2);
+        let sand = materials.cell(materials.id("sand").unwrap());
+        for y in 20..100 {
+            cells.set_at(56 + y % 8, y, sand).unwrap();
+        }
+        cells
+    }
+
+    #[test]
+    fn updates_keep_clients_in_sync() {
+        let materials = Materials::builtin();
+        let mut cells = falling_sand(&materials);
+        let mut replica = Replica::default();
+        let mut client = Client::default();
+
+        for tick in 0..60 {
+            let dirty = cells.take_dirty();
+            if let Some(packet) = replica.update(&cells, dirty, Tick(tick)) {
+                client.receive(packet);
+            }
+            assert!(client.matches(&cells));
+            advance(&mut cells, &materials, Seed::default(), Tick(tick), None);
+        }
+    }
+
+    #[test]
+    fn joining_clients_catch_up() {
+        let materials = Materials::builtin();
+        let mut cells = falling_sand(&materials);
+        let mut replica = Replica::default();
+        for tick in 0..10 {
+            let dirty = cells.take_dirty();
+            replica.update(&cells, dirty, Tick(tick));
+            advance(&mut cells, &materials, Seed::default(), Tick(tick), None);
+        }
+
+        let mut client = Client::default();
+        for packet in snapshot(&cells, Tick(10)) {
+            client.receive(packet);
+        }
+        assert!(client.matches(&cells));
+        // changes since the replica was last updated are in the snapshot already, so applying
+        // them again is harmless
+        let dirty = cells.take_dirty();
+        client.receive(replica.update(&cells, dirty, Tick(10)).unwrap());
+        assert!(client.matches(&cells));
+    }
+
+    #[test]
+    fn keyframes_fix_missed_updates() {
+        let materials = Materials::builtin();
+        let mut cells = falling_sand(&materials);
+        let mut replica = Replica::default();
+        let mut client = Client::default();
+        for tick in 0..10 {
+            let dirty = cells.take_dirty();
+            // every update is lost
+            replica.update(&cells, dirty, Tick(tick));
+            advance(&mut cells, &materials, Seed::default(), Tick(tick), None);
+        }
+        assert!(!client.matches(&cells));
+
+        cells.take_dirty();
+        for packet in replica.keyframe(&cells, Tick(10)) {
+            client.receive(packet);
+        }
+        assert!(client.matches(&cells));
+        advance(&mut cells, &materials, Seed::default(), Tick(10), None);
+        let dirty = cells.take_dirty();
+        client.receive(replica.update(&cells, dirty, Tick(11)).unwrap());
+        assert!(client.matches(&cells));
+    }
+
+    #[test]
+    fn snapshots_skip_empty_chunks() {
+        let materials = Materials::builtin();
+        let cells = falling_sand(&materials);
+        match snapshot(&cells, Tick(0)).as_slice() {
+            [ServerPacket::Snapshot {
+                parts: 1, chunks, ..
+            }] => {
+                // the sand column only crosses the bottom left and top left chunks
+                assert_eq!(chunks.len(), 2);
+            }
+            packets => panic!("unexpected {:?}", packets),
+        }
+        // still a packet for an empty world, so the client knows it's done
+        let empty = Cells::new(CHUNK_SIZE * 2, CHUNK_SIZE * 2);
+        match snapshot(&empty, Tick(0)).as_slice() {
+            [ServerPacket::Snapshot {
+                parts: 1, chunks, ..
+            }] => assert!(chunks.is_empty()),
+            packets => panic!("unexpected {:?}", packets),
+        }
+    }
+
+    #[test]
+    fn keyframes_are_split_into_small_packets() {
+        let materials = Materials::builtin();
+        let sand = materials.cell(materials.id("sand").unwrap());
+        // every cell a different temperature from the next, so no runs to speak of
+        let mut cells = Cells::new(CHUNK_SIZE * 4, CHUNK_SIZE * 4);
+        for y in 0..CHUNK_SIZE * 4 {
+            for x in 0..CHUNK_SIZE * 4 {
+                let cell = Cell {
+                    temperature: ((x * 7 + y * 13) % 100) as i16,
+                    ..sand
+                };
+                cells.set_at(x, y, cell).unwrap();
+            }
+        }
+        let packets = Replica::default().keyframe(&cells, Tick(3));
+        assert_eq!(packets.len(), 16);
+        let mut client = Client::default();
+        for packet in packets {
+            match &packet {
+                ServerPacket::SetCells { tick, chunks } => {
+                    assert_eq!(*tick, Tick(3));
+                    let size = chunks.iter().map(ChunkDelta::encoded_size).sum::<usize>();
+                    assert!(chunks.len() == 1 || size <= MAX_PACKET_SIZE);
+                }
+                packet => panic!("unexpected {:?}", packet),
+            }
+            client.receive(packet);
+        }
+        assert!(client.matches(&cells));
+
+        // chunks that emptied since are cleared by the next keyframe
+        let mut emptied = Cells::new(CHUNK_SIZE * 4, CHUNK_SIZE * 4);
+        emptied.take_dirty();
+        for packet in Replica::default().keyframe(&emptied, Tick(4)) {
+            client.receive(packet);
+        }
+        assert!(client.matches(&emptied));
+    }
+}